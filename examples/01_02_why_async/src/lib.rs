@@ -1,47 +1,102 @@
 #![cfg(test)]
 
 use {
-    futures::{
-        executor::block_on,
-        join,
+    futures::{executor::block_on, join},
+    std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::{Arc, Mutex},
+        thread,
     },
-    std::thread,
 };
 
-fn download(_url: &str) {
-    // ...
+fn download(url: &str) -> String {
+    block_on(download_async(url))
 }
 
-#[test]
 // ANCHOR: get_two_sites
-fn get_two_sites() {
-    // Spawn two threads to do work.
-    let thread_one = thread::spawn(|| download("https://www.foo.com"));
-    let thread_two = thread::spawn(|| download("https://www.bar.com"));
+fn get_two_sites(url_one: &str, url_two: &str) {
+    thread::scope(|scope| {
+        // Spawn two threads to do work.
+        let thread_one = scope.spawn(|| download(url_one));
+        let thread_two = scope.spawn(|| download(url_two));
 
-    // Wait for both threads to complete.
-    thread_one.join().expect("thread one panicked");
-    thread_two.join().expect("thread two panicked");
+        // Wait for both threads to complete.
+        thread_one.join().expect("thread one panicked");
+        thread_two.join().expect("thread two panicked");
+    });
 }
 // ANCHOR_END: get_two_sites
 
-async fn download_async(_url: &str) {
-    // ...
+async fn download_async(url: &str) -> String {
+    let response = http_client::get(url).await.expect("download failed");
+    response.text().into_owned()
 }
 
 // ANCHOR: get_two_sites_async
-async fn get_two_sites_async() {
+async fn get_two_sites_async(url_one: &str, url_two: &str) {
     // Create two different "futures" which, when run to completion,
     // will asynchronously download the webpages.
-    let future_one = download_async("https://www.foo.com");
-    let future_two = download_async("https://www.bar.com");
+    let future_one = download_async(url_one);
+    let future_two = download_async(url_two);
 
     // Run both futures to completion at the same time.
     join!(future_one, future_two);
 }
 // ANCHOR_END: get_two_sites_async
 
+/// A server on a loopback port that answers every request with the path
+/// it asked for, and remembers the paths.
+fn serve_pages() -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = format!("http://{}", listener.local_addr().unwrap());
+    let requested = Arc::new(Mutex::new(Vec::new()));
+    let paths = requested.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut lines = BufReader::new(&stream).lines();
+            let request_line = lines.next().unwrap().unwrap();
+            let path = request_line.split(' ').nth(1).unwrap().to_string();
+            // Read the rest of the request, so that closing the connection
+            // doesn't reset it.
+            lines.find(|line| line.as_ref().unwrap().is_empty());
+            paths.lock().unwrap().push(path.clone());
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                path.len(),
+                path
+            )
+            .unwrap();
+        }
+    });
+    (address, requested)
+}
+
+#[test]
+fn downloads_pages() {
+    let (address, _) = serve_pages();
+    assert_eq!(download(&format!("{}/foo", address)), "/foo");
+}
+
+#[test]
+fn get_two_sites_test() {
+    let (address, requested) = serve_pages();
+    get_two_sites(&format!("{}/foo", address), &format!("{}/bar", address));
+    let mut requested = requested.lock().unwrap().clone();
+    requested.sort();
+    assert_eq!(requested, ["/bar", "/foo"]);
+}
+
 #[test]
 fn get_two_sites_async_test() {
-    block_on(get_two_sites_async());
+    let (address, requested) = serve_pages();
+    block_on(get_two_sites_async(
+        &format!("{}/foo", address),
+        &format!("{}/bar", address),
+    ));
+    let mut requested = requested.lock().unwrap().clone();
+    requested.sort();
+    assert_eq!(requested, ["/bar", "/foo"]);
 }
@@ -1,5 +1,8 @@
 #![cfg(test)]
 
+// A more complete executor, built on the ideas from the book.
+mod runtime;
+
 // ANCHOR: imports
 use {
     futures::{
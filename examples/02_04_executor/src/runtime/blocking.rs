@@ -0,0 +1,252 @@
+//! A thread pool for running blocking code next to the executor.
+//!
+//! The executor polls every task on the thread that called `Executor::run`.
+//! A task that calls something like `std::fs::read_to_string` stops every
+//! other task for as long as the call takes. `Spawner::spawn_blocking`
+//! moves such a call onto a pool thread instead, and hands back a
+//! `JoinHandle`: a future which completes with the call's result.
+//!
+//! The pool is elastic. It starts without any threads, starts a new one
+//! whenever work arrives and all existing threads are busy (up to a
+//! maximum), and lets threads exit after they've been idle for a while.
+//!
+//! Only tasks on this executor can use the pool. The servers in chapter 9
+//! run on async-std, which has its own `task::spawn_blocking` and
+//! `async_std::fs`; the slow request example blocks on purpose, to show
+//! what goes wrong.
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{Arc, Condvar, Mutex},
+    task::{Context, Poll, Waker},
+    thread,
+    time::Duration,
+};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A handle to an elastic pool of threads for blocking work.
+#[derive(Clone)]
+pub struct BlockingPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    state: Mutex<PoolState>,
+    /// Signalled whenever a job is added to the queue.
+    work_available: Condvar,
+    max_threads: usize,
+    idle_timeout: Duration,
+}
+
+struct PoolState {
+    /// Jobs that no thread has picked up yet.
+    queue: VecDeque<Job>,
+    /// Number of threads alive.
+    threads: usize,
+    /// Number of threads waiting for a job.
+    idle: usize,
+}
+
+impl Default for BlockingPool {
+    fn default() -> Self {
+        BlockingPool::new(64, Duration::from_secs(10))
+    }
+}
+
+impl BlockingPool {
+    /// Create a pool that runs at most `max_threads` jobs at once, and lets
+    /// threads exit after waiting `idle_timeout` for a new job.
+    pub fn new(max_threads: usize, idle_timeout: Duration) -> Self {
+        assert!(max_threads > 0, "a blocking pool needs at least one thread");
+        BlockingPool {
+            inner: Arc::new(PoolInner {
+                state: Mutex::new(PoolState {
+                    queue: VecDeque::new(),
+                    threads: 0,
+                    idle: 0,
+                }),
+                work_available: Condvar::new(),
+                max_threads,
+                idle_timeout,
+            }),
+        }
+    }
+
+    /// Number of threads currently in the pool.
+    pub fn threads(&self) -> usize {
+        self.inner.state.lock().unwrap().threads
+    }
+
+    /// Run `f` on a pool thread.
+    pub fn spawn<F, T>(&self, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let shared = Arc::new(Mutex::new(JoinState {
+            result: None,
+            waker: None,
+        }));
+        let job_shared = shared.clone();
+        let job = Box::new(move || {
+            // Catch panics, so that they end up in the `JoinHandle` rather
+            // than taking down the pool thread.
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            let mut state = job_shared.lock().unwrap();
+            state.result = Some(result);
+            if let Some(waker) = state.waker.take() {
+                waker.wake()
+            }
+        });
+
+        let mut state = self.inner.state.lock().unwrap();
+        state.queue.push_back(job);
+        if state.idle >= state.queue.len() {
+            // An idle thread will pick up the job.
+            self.inner.work_available.notify_one();
+        } else if state.threads < self.inner.max_threads {
+            state.threads += 1;
+            let inner = self.inner.clone();
+            thread::Builder::new()
+                .name("blocking-pool".into())
+                .spawn(move || inner.work())
+                .expect("failed to start a blocking pool thread");
+        }
+        // Otherwise every thread is busy and the pool is at its maximum
+        // size; the job waits in the queue for the next free thread.
+
+        JoinHandle { shared }
+    }
+}
+
+impl PoolInner {
+    /// The body of a pool thread.
+    fn work(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(job) = state.queue.pop_front() {
+                drop(state);
+                job();
+                state = self.state.lock().unwrap();
+                continue;
+            }
+
+            state.idle += 1;
+            let (next_state, wait) = self
+                .work_available
+                .wait_timeout(state, self.idle_timeout)
+                .unwrap();
+            state = next_state;
+            state.idle -= 1;
+            if wait.timed_out() && state.queue.is_empty() {
+                state.threads -= 1;
+                return;
+            }
+        }
+    }
+}
+
+/// Shared state between a `JoinHandle` and the job it is waiting for.
+struct JoinState<T> {
+    result: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+/// A future that resolves to the return value of a blocking job.
+///
+/// If the job panicked, the panic is resumed in the task awaiting it.
+pub struct JoinHandle<T> {
+    shared: Arc<Mutex<JoinState<T>>>,
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.shared.lock().unwrap();
+        match state.result.take() {
+            Some(Ok(value)) => Poll::Ready(value),
+            Some(Err(payload)) => panic::resume_unwind(payload),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[test]
+fn blocking_work_does_not_stall_other_tasks() {
+    use {super::new_executor_and_spawner, std::sync::mpsc::channel, timer_future::TimerFuture};
+
+    let (executor, spawner) = new_executor_and_spawner();
+    let spawner = spawner.with_blocking_pool(BlockingPool::new(1, Duration::from_secs(1)));
+    let (order_sender, order) = channel();
+    {
+        let order_sender = order_sender.clone();
+        let blocking = spawner.clone();
+        spawner.spawn(async move {
+            let slept = blocking
+                .spawn_blocking(|| {
+                    thread::sleep(Duration::from_millis(200));
+                    "slow"
+                })
+                .await;
+            order_sender.send(slept).unwrap();
+        });
+    }
+    spawner.spawn(async move {
+        TimerFuture::new(Duration::from_millis(10)).await;
+        order_sender.send("fast").unwrap();
+    });
+    drop(spawner);
+    executor.run();
+
+    assert_eq!(order.try_iter().collect::<Vec<_>>(), vec!["fast", "slow"]);
+}
+
+#[test]
+fn pool_never_exceeds_max_threads() {
+    use {
+        futures::{executor::block_on, future::join_all},
+        std::sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    let pool = BlockingPool::new(2, Duration::from_secs(10));
+    let running = Arc::new(AtomicUsize::new(0));
+    let peak = Arc::new(AtomicUsize::new(0));
+    let jobs = (0..6).map(|_| {
+        let running = running.clone();
+        let peak = peak.clone();
+        pool.spawn(move || {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(20));
+            running.fetch_sub(1, Ordering::SeqCst);
+        })
+    });
+    block_on(join_all(jobs));
+
+    assert_eq!(peak.load(Ordering::SeqCst), 2);
+    assert_eq!(pool.threads(), 2);
+}
+
+#[test]
+fn idle_threads_exit() {
+    use futures::executor::block_on;
+
+    let pool = BlockingPool::new(4, Duration::from_millis(20));
+    assert_eq!(block_on(pool.spawn(|| 1 + 1)), 2);
+    assert_eq!(pool.threads(), 1);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(pool.threads(), 0);
+}
+
+#[test]
+#[should_panic(expected = "oops")]
+fn panics_reach_the_awaiting_task() {
+    futures::executor::block_on(BlockingPool::default().spawn(|| panic!("oops")))
+}
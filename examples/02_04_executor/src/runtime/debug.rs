@@ -0,0 +1,330 @@
+//! Debug mode for the executor.
+//!
+//! A future that returns `Poll::Pending` without holding on to the waker
+//! from its `Context` will never be polled again: nothing is left that
+//! could put its task back onto the ready queue. In debug mode, each poll
+//! hands the future a waker that records whether it was used, and the
+//! executor warns about pending polls that left no way of being woken.
+//! A watchdog thread additionally warns about tasks that have been pending
+//! for longer than a configurable threshold.
+
+use {
+    super::{Task, TaskInfo},
+    futures::{
+        future::BoxFuture,
+        task::{waker_ref, ArcWake},
+    },
+    std::{
+        collections::HashMap,
+        fmt,
+        sync::atomic::{AtomicBool, Ordering},
+        sync::{Arc, Mutex},
+        task::Context,
+        thread::{self, JoinHandle},
+        time::{Duration, Instant},
+    },
+};
+
+/// Something suspicious the executor noticed about a task.
+#[derive(Clone, Debug)]
+pub enum Warning {
+    /// The task returned `Poll::Pending` without cloning or waking the
+    /// waker it was polled with, so it can never be woken again.
+    NoWakerRegistered(TaskInfo),
+    /// The task has been waiting to be woken for longer than the
+    /// configured idle threshold.
+    Idle { task: TaskInfo, idle_for: Duration },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::NoWakerRegistered(task) => write!(
+                f,
+                "{} returned `Poll::Pending` without registering its waker \
+                 and will never be woken",
+                task
+            ),
+            Warning::Idle { task, idle_for } => {
+                write!(f, "{} has not been woken for {:?}", task, idle_for)
+            }
+        }
+    }
+}
+
+/// Settings for `Executor::with_debug`.
+#[derive(Clone)]
+pub struct DebugConfig {
+    idle_threshold: Option<Duration>,
+    report: Arc<dyn Fn(&Warning) + Send + Sync>,
+}
+
+impl Default for DebugConfig {
+    /// Print warnings to stderr, and look for tasks idle for ten seconds.
+    fn default() -> Self {
+        DebugConfig {
+            idle_threshold: Some(Duration::from_secs(10)),
+            report: Arc::new(|warning| eprintln!("executor warning: {}", warning)),
+        }
+    }
+}
+
+impl DebugConfig {
+    /// How long a task may wait to be woken before the watchdog reports it.
+    /// `None` turns the watchdog off.
+    pub fn idle_threshold(mut self, threshold: Option<Duration>) -> Self {
+        self.idle_threshold = threshold;
+        self
+    }
+
+    /// Send warnings to `report` instead of stderr. It is called from both
+    /// the executor and the watchdog thread.
+    pub fn on_warning(mut self, report: impl Fn(&Warning) + Send + Sync + 'static) -> Self {
+        self.report = Arc::new(report);
+        self
+    }
+}
+
+/// A task that is waiting to be woken.
+struct Idle {
+    task: TaskInfo,
+    since: Instant,
+    reported: bool,
+}
+
+/// The debug state of an executor.
+pub(super) struct Monitor {
+    config: DebugConfig,
+    idle: Arc<Mutex<HashMap<u64, Idle>>>,
+}
+
+/// The waker handed to a task's future in debug mode. It forwards to the
+/// task, noting whether it was woken along the way.
+struct WakerProbe {
+    task: Arc<Task>,
+    woken: AtomicBool,
+}
+
+impl ArcWake for WakerProbe {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.woken.store(true, Ordering::Relaxed);
+        ArcWake::wake_by_ref(&arc_self.task);
+    }
+}
+
+impl Monitor {
+    pub(super) fn new(config: DebugConfig) -> Self {
+        Monitor {
+            config,
+            idle: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Poll `future`, which belongs to `task`, returning whether it is
+    /// still pending.
+    pub(super) fn poll(&self, task: &Arc<Task>, future: &mut BoxFuture<'static, ()>) -> bool {
+        // The task was woken, or it wouldn't be polled now.
+        self.idle.lock().unwrap().remove(&task.info.id);
+
+        let probe = Arc::new(WakerProbe {
+            task: task.clone(),
+            woken: AtomicBool::new(false),
+        });
+        let waker = waker_ref(&probe);
+        let context = &mut Context::from_waker(&waker);
+        if future.as_mut().poll(context).is_ready() {
+            return false;
+        }
+
+        // A task that woke itself, like one that yields, is already back
+        // on the ready queue rather than waiting.
+        if probe.woken.load(Ordering::Relaxed) {
+            return true;
+        }
+        // `waker_ref` doesn't own a reference to `probe`, so any other
+        // reference is a clone of the waker that outlived the poll.
+        if Arc::strong_count(&probe) == 1 {
+            (self.config.report)(&Warning::NoWakerRegistered(task.info.clone()));
+        }
+        self.idle.lock().unwrap().insert(
+            task.info.id,
+            Idle {
+                task: task.info.clone(),
+                since: Instant::now(),
+                reported: false,
+            },
+        );
+        true
+    }
+
+    /// Start the watchdog thread, if there should be one. It runs until
+    /// the returned guard is dropped.
+    pub(super) fn start_watchdog(&self) -> Option<Watchdog> {
+        let threshold = self.config.idle_threshold?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            let idle = self.idle.clone();
+            let report = self.config.report.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    for entry in idle.lock().unwrap().values_mut() {
+                        let idle_for = entry.since.elapsed();
+                        if !entry.reported && idle_for >= threshold {
+                            entry.reported = true;
+                            report(&Warning::Idle {
+                                task: entry.task.clone(),
+                                idle_for,
+                            });
+                        }
+                    }
+                    thread::park_timeout(threshold / 4);
+                }
+            })
+        };
+        Some(Watchdog {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+/// Stops the watchdog thread when dropped.
+pub(super) struct Watchdog {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A config whose warnings end up in the returned channel.
+fn collect_warnings(config: DebugConfig) -> (DebugConfig, std::sync::mpsc::Receiver<Warning>) {
+    let (sender, warnings) = std::sync::mpsc::channel();
+    let sender = Mutex::new(sender);
+    let config = config.on_warning(move |warning| {
+        let _ = sender.lock().unwrap().send(warning.clone());
+    });
+    (config, warnings)
+}
+
+#[test]
+fn warns_about_pending_without_waker() {
+    use {super::new_executor_and_spawner, futures::future::poll_fn, std::task::Poll};
+
+    let (config, warnings) = collect_warnings(DebugConfig::default().idle_threshold(None));
+    let (executor, spawner) = new_executor_and_spawner();
+    let executor = executor.with_debug(config);
+    spawner.spawn_named("forgetful", poll_fn(|_| Poll::<()>::Pending));
+    drop(spawner);
+    executor.run();
+
+    match warnings.try_recv() {
+        Ok(Warning::NoWakerRegistered(task)) => {
+            assert_eq!(task.name.as_deref(), Some("forgetful"));
+            assert_eq!(task.spawned_at.file(), file!());
+        }
+        other => panic!("expected a missing waker warning, got {:?}", other),
+    }
+}
+
+#[test]
+fn no_warnings_for_well_behaved_tasks() {
+    use {super::new_executor_and_spawner, timer_future::TimerFuture};
+
+    let (config, warnings) = collect_warnings(DebugConfig::default());
+    let (executor, spawner) = new_executor_and_spawner();
+    let executor = executor.with_debug(config);
+    spawner.spawn(TimerFuture::new(Duration::from_millis(10)));
+    drop(spawner);
+    executor.run();
+
+    assert!(warnings.try_recv().is_err());
+}
+
+#[test]
+fn watchdog_reports_idle_tasks() {
+    use {
+        super::new_executor_and_spawner,
+        futures::future::poll_fn,
+        std::{task::Poll, task::Waker},
+    };
+
+    let threshold = Duration::from_millis(50);
+    let (config, warnings) =
+        collect_warnings(DebugConfig::default().idle_threshold(Some(threshold)));
+    let (executor, spawner) = new_executor_and_spawner();
+    let executor = executor.with_debug(config);
+
+    // Park the waker where the test can get at it, but never wake it.
+    let stashed: Arc<Mutex<Option<Waker>>> = Arc::new(Mutex::new(None));
+    let released = Arc::new(AtomicBool::new(false));
+    {
+        let stashed = stashed.clone();
+        let released = released.clone();
+        spawner.spawn_named(
+            "sleepy",
+            poll_fn(move |cx| {
+                if released.load(Ordering::SeqCst) {
+                    Poll::Ready(())
+                } else {
+                    *stashed.lock().unwrap() = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }),
+        );
+    }
+    drop(spawner);
+    let executor = thread::spawn(move || executor.run());
+
+    match warnings.recv_timeout(Duration::from_secs(5)) {
+        Ok(Warning::Idle { task, idle_for }) => {
+            assert_eq!(task.name.as_deref(), Some("sleepy"));
+            assert!(idle_for >= threshold);
+        }
+        other => panic!("expected an idle warning, got {:?}", other),
+    }
+
+    released.store(true, Ordering::SeqCst);
+    stashed.lock().unwrap().take().unwrap().wake();
+    executor.join().unwrap();
+}
+
+#[test]
+fn watchdog_ignores_tasks_that_woke_themselves() {
+    use {super::new_executor_and_spawner, futures::future::poll_fn, std::task::Poll};
+
+    let threshold = Duration::from_millis(50);
+    let (config, warnings) =
+        collect_warnings(DebugConfig::default().idle_threshold(Some(threshold)));
+    let (executor, spawner) = new_executor_and_spawner();
+    let executor = executor.with_debug(config);
+
+    // Yield once, then wait behind a task that holds up the executor for
+    // longer than the threshold.
+    let mut yielded = false;
+    spawner.spawn_named(
+        "polite",
+        poll_fn(move |cx| {
+            if yielded {
+                Poll::Ready(())
+            } else {
+                yielded = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }),
+    );
+    spawner.spawn(async move { thread::sleep(threshold * 4) });
+    drop(spawner);
+    executor.run();
+
+    assert!(warnings.try_recv().is_err());
+}
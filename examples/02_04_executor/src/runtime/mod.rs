@@ -0,0 +1,233 @@
+//! A fuller version of the executor above.
+//!
+//! It is built the same way, but every task remembers an id, an optional
+//! name and the place it was spawned from, so that the executor has
+//! something useful to say about a task when it misbehaves.
+
+use {
+    futures::{
+        future::{BoxFuture, FutureExt},
+        task::{waker_ref, ArcWake},
+    },
+    std::{
+        fmt,
+        future::Future,
+        panic::Location,
+        sync::atomic::{AtomicU64, Ordering},
+        sync::{Arc, Mutex},
+        task::Context,
+    },
+};
+
+mod blocking;
+pub mod debug;
+mod queue;
+pub mod scope;
+pub mod sim;
+
+pub use self::{
+    blocking::{BlockingPool, JoinHandle},
+    queue::{Priority, Weights},
+};
+
+/// Task executor that receives tasks off of the ready queue and runs them.
+pub struct Executor {
+    ready_queue: queue::TaskReceiver,
+    /// Present when the executor was put into debug mode.
+    monitor: Option<debug::Monitor>,
+}
+
+/// `Spawner` spawns new futures onto the ready queue.
+#[derive(Clone)]
+pub struct Spawner {
+    task_sender: queue::TaskSender,
+    next_id: Arc<AtomicU64>,
+    blocking: BlockingPool,
+}
+
+/// A future that can reschedule itself to be polled by an `Executor`.
+struct Task {
+    /// In-progress future that should be pushed to completion.
+    future: Mutex<Option<BoxFuture<'static, ()>>>,
+
+    /// Handle to place the task itself back onto the task queue.
+    task_sender: queue::TaskSender,
+
+    /// Which of the ready queues the task goes on.
+    priority: Priority,
+
+    /// What the executor reports about this task.
+    info: TaskInfo,
+}
+
+/// Describes a task in diagnostics.
+#[derive(Clone, Debug)]
+pub struct TaskInfo {
+    /// Unique (per spawner) id of the task.
+    pub id: u64,
+    /// The name given to `Spawner::spawn_named`, if any.
+    pub name: Option<String>,
+    /// Where in the source the task was spawned.
+    pub spawned_at: &'static Location<'static>,
+}
+
+impl fmt::Display for TaskInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "task #{}", self.id)?;
+        if let Some(name) = &self.name {
+            write!(f, " ({:?})", name)?;
+        }
+        write!(f, " spawned at {}", self.spawned_at)
+    }
+}
+
+pub fn new_executor_and_spawner() -> (Executor, Spawner) {
+    let (task_sender, ready_queue) = queue::ready_queue();
+    (
+        Executor {
+            ready_queue,
+            monitor: None,
+        },
+        Spawner {
+            task_sender,
+            next_id: Arc::new(AtomicU64::new(0)),
+            blocking: BlockingPool::default(),
+        },
+    )
+}
+
+impl Spawner {
+    /// Spawn an unnamed task with `Priority::Normal`.
+    #[track_caller]
+    pub fn spawn(&self, future: impl Future<Output = ()> + 'static + Send) {
+        self.spawn_task(None, Priority::Normal, future.boxed(), Location::caller());
+    }
+
+    /// Spawn an unnamed task onto the ready queue for `priority`.
+    #[track_caller]
+    pub fn spawn_with_priority(
+        &self,
+        priority: Priority,
+        future: impl Future<Output = ()> + 'static + Send,
+    ) {
+        self.spawn_task(None, priority, future.boxed(), Location::caller());
+    }
+
+    /// Spawn a task carrying `name`, which shows up in any diagnostics
+    /// the executor reports about it.
+    #[track_caller]
+    pub fn spawn_named(
+        &self,
+        name: impl Into<String>,
+        future: impl Future<Output = ()> + 'static + Send,
+    ) {
+        self.spawn_task(
+            Some(name.into()),
+            Priority::Normal,
+            future.boxed(),
+            Location::caller(),
+        );
+    }
+
+    /// Run the blocking function `f` on the blocking pool rather than on
+    /// the executor thread, see the `blocking` module.
+    pub fn spawn_blocking<F, T>(&self, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.blocking.spawn(f)
+    }
+
+    /// Use `pool` for `spawn_blocking` instead of the default pool.
+    pub fn with_blocking_pool(mut self, pool: BlockingPool) -> Self {
+        self.blocking = pool;
+        self
+    }
+
+    fn spawn_task(
+        &self,
+        name: Option<String>,
+        priority: Priority,
+        future: BoxFuture<'static, ()>,
+        spawned_at: &'static Location<'static>,
+    ) {
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(future)),
+            task_sender: self.task_sender.clone(),
+            priority,
+            info: TaskInfo {
+                id: self.next_id.fetch_add(1, Ordering::Relaxed),
+                name,
+                spawned_at,
+            },
+        });
+        self.task_sender.send(task);
+    }
+}
+
+impl ArcWake for Task {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        let cloned = arc_self.clone();
+        arc_self.task_sender.send(cloned);
+    }
+}
+
+impl Executor {
+    /// Put the executor into debug mode, see `DebugConfig`.
+    pub fn with_debug(mut self, config: debug::DebugConfig) -> Self {
+        self.monitor = Some(debug::Monitor::new(config));
+        self
+    }
+
+    /// Change how the executor divides its time between priorities, see
+    /// the `queue` module.
+    pub fn with_weights(self, weights: Weights) -> Self {
+        self.ready_queue.set_weights(weights);
+        self
+    }
+
+    pub fn run(&self) {
+        let _watchdog = self.monitor.as_ref().map(debug::Monitor::start_watchdog);
+        while let Some(task) = self.ready_queue.recv() {
+            let mut future_slot = task.future.lock().unwrap();
+            if let Some(mut future) = future_slot.take() {
+                let pending = match &self.monitor {
+                    Some(monitor) => monitor.poll(&task, &mut future),
+                    None => {
+                        let waker = waker_ref(&task);
+                        let context = &mut Context::from_waker(&waker);
+                        future.as_mut().poll(context).is_pending()
+                    }
+                };
+                if pending {
+                    *future_slot = Some(future);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn tasks_run_to_completion() {
+    use {
+        std::{sync::mpsc::channel, time::Duration},
+        timer_future::TimerFuture,
+    };
+
+    let (executor, spawner) = new_executor_and_spawner();
+    let (done_sender, done) = channel();
+    for i in 0..3 {
+        let done_sender = done_sender.clone();
+        spawner.spawn(async move {
+            TimerFuture::new(Duration::from_millis(10)).await;
+            done_sender.send(i).unwrap();
+        });
+    }
+    drop(spawner);
+    executor.run();
+
+    let mut finished: Vec<_> = done.try_iter().collect();
+    finished.sort();
+    assert_eq!(finished, vec![0, 1, 2]);
+}
@@ -0,0 +1,297 @@
+//! The ready queue, split up by task priority.
+//!
+//! It replaces the `sync_channel` used by the executor in the book with
+//! one queue per `Priority`, but keeps the shape of a channel: tasks hold
+//! a `TaskSender` to put themselves back onto the queue, and the executor
+//! stops once the queue is empty and every `TaskSender` is gone.
+//!
+//! Which queue the executor takes the next task from is decided by
+//! weighted round robin. Every round, each priority may have as many tasks
+//! polled as its weight, with higher priorities going first. A busy
+//! `High` queue gets most of the executor's time, but can't starve the
+//! lower priorities completely.
+
+use {
+    super::Task,
+    std::{
+        collections::VecDeque,
+        mem,
+        sync::{Arc, Condvar, Mutex},
+    },
+};
+
+/// How urgently a task should be polled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    /// Latency sensitive work, such as answering requests.
+    High,
+    /// The priority of tasks spawned without one.
+    Normal,
+    /// Bulk work that can wait until nothing else needs doing.
+    Background,
+}
+
+impl Priority {
+    fn index(self) -> usize {
+        match self {
+            Priority::High => 0,
+            Priority::Normal => 1,
+            Priority::Background => 2,
+        }
+    }
+}
+
+/// How many tasks of each priority are polled per scheduling round.
+#[derive(Clone, Copy, Debug)]
+pub struct Weights {
+    pub high: u32,
+    pub normal: u32,
+    pub background: u32,
+}
+
+impl Default for Weights {
+    fn default() -> Self {
+        Weights {
+            high: 8,
+            normal: 4,
+            background: 1,
+        }
+    }
+}
+
+impl Weights {
+    fn by_index(self) -> [u32; 3] {
+        [self.high, self.normal, self.background]
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Signalled when a task is queued or the last sender goes away.
+    changed: Condvar,
+}
+
+struct State {
+    /// One queue per priority, indexed by `Priority::index`.
+    queues: [VecDeque<Arc<Task>>; 3],
+    weights: [u32; 3],
+    /// What is left of each priority's weight in the current round.
+    credits: [u32; 3],
+    senders: usize,
+    /// Set once the executor is gone, after which tasks are dropped
+    /// instead of queued.
+    closed: bool,
+}
+
+/// Sending half of the ready queue.
+pub(super) struct TaskSender {
+    shared: Arc<Shared>,
+}
+
+/// Receiving half of the ready queue, owned by the executor.
+pub(super) struct TaskReceiver {
+    shared: Arc<Shared>,
+}
+
+pub(super) fn ready_queue() -> (TaskSender, TaskReceiver) {
+    let weights = Weights::default().by_index();
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queues: Default::default(),
+            weights,
+            credits: weights,
+            senders: 1,
+            closed: false,
+        }),
+        changed: Condvar::new(),
+    });
+    (
+        TaskSender {
+            shared: shared.clone(),
+        },
+        TaskReceiver { shared },
+    )
+}
+
+impl TaskSender {
+    pub(super) fn send(&self, task: Arc<Task>) {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return;
+        }
+        state.queues[task.priority.index()].push_back(task);
+        self.shared.changed.notify_one();
+    }
+}
+
+impl Clone for TaskSender {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        TaskSender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for TaskSender {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            self.shared.changed.notify_all();
+        }
+    }
+}
+
+impl TaskReceiver {
+    /// Block until there is a task to poll, or return `None` once there
+    /// never will be.
+    pub(super) fn recv(&self) -> Option<Arc<Task>> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(task) = state.next() {
+                return Some(task);
+            }
+            if state.senders == 0 {
+                return None;
+            }
+            state = self.shared.changed.wait(state).unwrap();
+        }
+    }
+
+    pub(super) fn set_weights(&self, weights: Weights) {
+        let weights = weights.by_index();
+        assert!(
+            weights.iter().all(|&weight| weight > 0),
+            "every priority needs a weight of at least one"
+        );
+        let mut state = self.shared.state.lock().unwrap();
+        state.weights = weights;
+        state.credits = weights;
+    }
+}
+
+impl Drop for TaskReceiver {
+    fn drop(&mut self) {
+        let queues = {
+            let mut state = self.shared.state.lock().unwrap();
+            state.closed = true;
+            mem::take(&mut state.queues)
+        };
+        // Queued tasks hold senders, which lock the state when dropped.
+        drop(queues);
+    }
+}
+
+impl State {
+    /// Take the next task according to the weights, if any task is queued.
+    fn next(&mut self) -> Option<Arc<Task>> {
+        if self.queues.iter().all(VecDeque::is_empty) {
+            return None;
+        }
+        loop {
+            for (index, queue) in self.queues.iter_mut().enumerate() {
+                if self.credits[index] > 0 && !queue.is_empty() {
+                    self.credits[index] -= 1;
+                    return queue.pop_front();
+                }
+            }
+            // Every priority with queued tasks has used up its weight.
+            self.credits = self.weights;
+        }
+    }
+}
+
+/// Spawn a task per entry of `priorities`, each of which records its
+/// priority when it is first polled, and return the recorded order.
+fn poll_order(weights: Weights, priorities: &[Priority]) -> Vec<Priority> {
+    use super::new_executor_and_spawner;
+
+    let (executor, spawner) = new_executor_and_spawner();
+    let executor = executor.with_weights(weights);
+    let order = Arc::new(Mutex::new(Vec::new()));
+    for &priority in priorities {
+        let order = order.clone();
+        spawner.spawn_with_priority(priority, async move {
+            order.lock().unwrap().push(priority);
+        });
+    }
+    drop(spawner);
+    executor.run();
+    let order = order.lock().unwrap().clone();
+    order
+}
+
+#[test]
+fn higher_priorities_go_first() {
+    use Priority::*;
+
+    let order = poll_order(
+        Weights::default(),
+        &[Background, Normal, High, Background, Normal, High],
+    );
+    assert_eq!(order, [High, High, Normal, Normal, Background, Background]);
+}
+
+#[test]
+fn weights_share_the_executor_under_contention() {
+    use Priority::*;
+
+    let weights = Weights {
+        high: 2,
+        normal: 1,
+        background: 1,
+    };
+    let mut spawned = Vec::new();
+    for _ in 0..4 {
+        spawned.extend_from_slice(&[Background, Normal, High]);
+    }
+    let order = poll_order(weights, &spawned);
+    assert_eq!(
+        order,
+        [
+            High, High, Normal, Background, // first round
+            High, High, Normal, Background, // second round, `High` is done
+            Normal, Background, // third round
+            Normal, Background,
+        ]
+    );
+}
+
+#[test]
+fn busy_background_tasks_do_not_delay_high_priority_ones() {
+    use {super::new_executor_and_spawner, futures::future::poll_fn, std::task::Poll};
+
+    /// Yield back to the executor `times` times before completing.
+    fn busy(mut times: u32) -> impl std::future::Future<Output = ()> {
+        poll_fn(move |cx| {
+            if times == 0 {
+                return Poll::Ready(());
+            }
+            times -= 1;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        })
+    }
+
+    let (executor, spawner) = new_executor_and_spawner();
+    let finished = Arc::new(Mutex::new(Vec::new()));
+    for i in 0..10 {
+        let finished = finished.clone();
+        spawner.spawn_with_priority(Priority::Background, async move {
+            busy(100).await;
+            finished.lock().unwrap().push(format!("background {}", i));
+        });
+    }
+    {
+        let finished = finished.clone();
+        spawner.spawn_with_priority(Priority::High, async move {
+            busy(100).await;
+            finished.lock().unwrap().push("high".to_string());
+        });
+    }
+    drop(spawner);
+    executor.run();
+
+    assert_eq!(finished.lock().unwrap()[0], "high");
+}
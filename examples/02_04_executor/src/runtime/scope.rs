@@ -0,0 +1,278 @@
+//! Structured concurrency: child tasks that can't outlive their parent.
+//!
+//! `Spawner::spawn` needs a `'static` future, because nothing stops the
+//! spawned task from running after whoever spawned it is gone. `scope`
+//! turns this around. The children spawned onto a `Scope` are owned by
+//! the future that `scope` returns, and are polled as part of it, so they
+//! are free to borrow anything that outlives the call to `scope`:
+//!
+//! ```ignore
+//! let names = vec!["a", "b"];
+//! scope(|s| async move {
+//!     for name in &names {
+//!         s.spawn(async move { greet(name).await });
+//!     }
+//!     Ok(())
+//! })
+//! .await
+//! ```
+//!
+//! The scope only completes once its body and all of its children have
+//! completed. If any of them fails, the scope drops (and with that,
+//! cancels) everything still running inside it and returns the error.
+
+use {
+    futures::{
+        future::{BoxFuture, FutureExt},
+        stream::{FuturesUnordered, StreamExt},
+    },
+    std::{
+        future::Future,
+        mem,
+        pin::Pin,
+        sync::{Arc, Mutex},
+        task::{Context, Poll},
+    },
+};
+
+type Child<'env, E> = BoxFuture<'env, Result<(), E>>;
+
+/// Handle for spawning children onto a scope, see `scope`.
+pub struct Scope<'env, E> {
+    /// Children spawned since the scope future last looked.
+    spawned: Arc<Mutex<Spawned<'env, E>>>,
+}
+
+struct Spawned<'env, E> {
+    children: Vec<Child<'env, E>>,
+    /// Set once the scope has completed. Anything spawned after that is
+    /// dropped right away, as no one would poll it.
+    closed: bool,
+}
+
+impl<E> Clone for Scope<'_, E> {
+    fn clone(&self) -> Self {
+        Scope {
+            spawned: self.spawned.clone(),
+        }
+    }
+}
+
+impl<'env, E> Scope<'env, E> {
+    /// Run `future` concurrently with the rest of the scope.
+    pub fn spawn(&self, future: impl Future<Output = Result<(), E>> + Send + 'env) {
+        let mut spawned = self.spawned.lock().unwrap();
+        if !spawned.closed {
+            spawned.children.push(future.boxed());
+        }
+    }
+}
+
+/// Run `body` with a `Scope` that it can spawn borrowing children onto.
+///
+/// Resolves to the body's result once all children have completed, or to
+/// the first error returned by the body or any child.
+pub fn scope<'env, F, Fut, T, E>(body: F) -> ScopeFuture<'env, T, E>
+where
+    F: FnOnce(Scope<'env, E>) -> Fut,
+    Fut: Future<Output = Result<T, E>> + Send + 'env,
+{
+    let scope = Scope {
+        spawned: Arc::new(Mutex::new(Spawned {
+            children: Vec::new(),
+            closed: false,
+        })),
+    };
+    let spawned = scope.spawned.clone();
+    ScopeFuture {
+        body: Some(body(scope).boxed()),
+        output: None,
+        children: FuturesUnordered::new(),
+        spawned,
+    }
+}
+
+/// The future returned by `scope`.
+pub struct ScopeFuture<'env, T, E> {
+    /// The body, until it completes.
+    body: Option<BoxFuture<'env, Result<T, E>>>,
+    /// What the body returned, once it completes.
+    output: Option<T>,
+    children: FuturesUnordered<Child<'env, E>>,
+    spawned: Arc<Mutex<Spawned<'env, E>>>,
+}
+
+// Nothing in a `ScopeFuture` is pinned: the futures in it are boxed.
+impl<T, E> Unpin for ScopeFuture<'_, T, E> {}
+
+impl<T, E> ScopeFuture<'_, T, E> {
+    /// Move newly spawned children into `children`, returning whether
+    /// there were any.
+    fn adopt_children(&mut self) -> bool {
+        let spawned = mem::take(&mut self.spawned.lock().unwrap().children);
+        let adopted = !spawned.is_empty();
+        self.children.extend(spawned);
+        adopted
+    }
+
+    /// Finish the scope, cancelling whatever is still running in it.
+    fn close(&mut self) {
+        self.spawned.lock().unwrap().closed = true;
+        self.body = None;
+        self.children.clear();
+        // Children spawned by the body or children while they were being
+        // dropped above.
+        let late = mem::take(&mut self.spawned.lock().unwrap().children);
+        drop(late);
+    }
+}
+
+impl<T, E> Future for ScopeFuture<'_, T, E> {
+    type Output = Result<T, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        // Polling may spawn more children, which need a first poll of
+        // their own before we can be sure that they've registered a waker.
+        loop {
+            if let Some(body) = &mut this.body {
+                if let Poll::Ready(result) = body.as_mut().poll(cx) {
+                    this.body = None;
+                    match result {
+                        Ok(output) => this.output = Some(output),
+                        Err(error) => {
+                            this.close();
+                            return Poll::Ready(Err(error));
+                        }
+                    }
+                }
+            }
+            this.adopt_children();
+
+            while let Poll::Ready(Some(result)) = this.children.poll_next_unpin(cx) {
+                if let Err(error) = result {
+                    this.close();
+                    return Poll::Ready(Err(error));
+                }
+            }
+
+            if !this.adopt_children() {
+                break;
+            }
+        }
+
+        if this.body.is_none() && this.children.is_empty() {
+            this.close();
+            let output = this
+                .output
+                .take()
+                .expect("`ScopeFuture` polled after completion");
+            Poll::Ready(Ok(output))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<T, E> Drop for ScopeFuture<'_, T, E> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[test]
+fn children_borrow_from_the_parent() {
+    use {
+        super::new_executor_and_spawner,
+        std::{sync::atomic::AtomicUsize, sync::atomic::Ordering, time::Duration},
+        timer_future::TimerFuture,
+    };
+
+    let (executor, spawner) = new_executor_and_spawner();
+    let (result_sender, result) = std::sync::mpsc::channel();
+    spawner.spawn(async move {
+        let numbers = vec![1, 2, 3, 4];
+        let total = AtomicUsize::new(0);
+        let (numbers, total_ref) = (&numbers, &total);
+        let outcome: Result<&str, ()> = scope(|s| async move {
+            for &n in numbers {
+                s.spawn(async move {
+                    TimerFuture::new(Duration::from_millis(10 * n as u64)).await;
+                    total_ref.fetch_add(n, Ordering::SeqCst);
+                    Ok(())
+                });
+            }
+            Ok("body done")
+        })
+        .await;
+        // Every child has finished by the time the scope returns.
+        result_sender
+            .send((outcome, total.load(Ordering::SeqCst)))
+            .unwrap();
+    });
+    drop(spawner);
+    executor.run();
+
+    assert_eq!(result.recv().unwrap(), (Ok("body done"), 10));
+}
+
+#[test]
+fn children_can_spawn_siblings() {
+    use futures::executor::block_on;
+
+    let log = Mutex::new(Vec::new());
+    let log_ref = &log;
+    let outcome: Result<(), ()> = block_on(scope(|s| async move {
+        let inner = s.clone();
+        s.spawn(async move {
+            log_ref.lock().unwrap().push("child");
+            inner.spawn(async move {
+                log_ref.lock().unwrap().push("grandchild");
+                Ok(())
+            });
+            Ok(())
+        });
+        Ok(())
+    }));
+
+    assert_eq!(outcome, Ok(()));
+    assert_eq!(*log.lock().unwrap(), ["child", "grandchild"]);
+}
+
+#[test]
+fn failing_child_cancels_its_siblings() {
+    use {
+        futures::executor::block_on,
+        std::{sync::atomic::AtomicBool, sync::atomic::Ordering, time::Duration},
+        timer_future::TimerFuture,
+    };
+
+    /// Sets its flag when dropped.
+    struct DropFlag<'a>(&'a AtomicBool);
+    impl Drop for DropFlag<'_> {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let finished = AtomicBool::new(false);
+    let dropped = AtomicBool::new(false);
+    let (finished_ref, dropped_ref) = (&finished, &dropped);
+    let outcome = block_on(scope(|s| async move {
+        s.spawn(async move {
+            let _flag = DropFlag(dropped_ref);
+            TimerFuture::new(Duration::from_secs(5)).await;
+            finished_ref.store(true, Ordering::SeqCst);
+            Ok(())
+        });
+        s.spawn(async {
+            TimerFuture::new(Duration::from_millis(10)).await;
+            Err("child failed")
+        });
+        Ok(())
+    }));
+
+    assert_eq!(outcome, Err("child failed"));
+    assert!(dropped.load(Ordering::SeqCst));
+    assert!(!finished.load(Ordering::SeqCst));
+}
@@ -0,0 +1,143 @@
+//! Virtual time for the simulation executor.
+//!
+//! The clock starts at zero and only moves when the executor runs out of
+//! tasks to poll, at which point it jumps to the earliest timer and wakes
+//! everything waiting on it.
+
+use {
+    super::SimHandle,
+    std::{
+        cell::{Cell, RefCell},
+        collections::BTreeMap,
+        future::Future,
+        pin::Pin,
+        task::{Context, Poll, Waker},
+        time::Duration,
+    },
+};
+
+/// Identifies a registered timer: its deadline, and a sequence number to
+/// tell apart timers with the same deadline.
+pub(super) type TimerKey = (Duration, u64);
+
+#[derive(Default)]
+pub(super) struct ClockState {
+    now: Cell<Duration>,
+    timers: RefCell<BTreeMap<TimerKey, Waker>>,
+    next_timer: Cell<u64>,
+}
+
+impl ClockState {
+    pub(super) fn now(&self) -> Duration {
+        self.now.get()
+    }
+
+    /// Wake `waker` once the clock reaches `deadline`.
+    pub(super) fn register(&self, deadline: Duration, waker: Waker) -> TimerKey {
+        let key = (deadline, self.next_timer.get());
+        self.next_timer.set(key.1 + 1);
+        self.timers.borrow_mut().insert(key, waker);
+        key
+    }
+
+    pub(super) fn cancel(&self, key: TimerKey) {
+        self.timers.borrow_mut().remove(&key);
+    }
+
+    /// Move the clock to the earliest timer and fire all timers due then.
+    /// Returns `false` if there are no timers left.
+    pub(super) fn advance(&self) -> bool {
+        let deadline = match self.timers.borrow().keys().next() {
+            Some(&(deadline, _)) => deadline,
+            None => return false,
+        };
+        self.now.set(self.now.get().max(deadline));
+        let due: Vec<Waker> = {
+            let mut timers = self.timers.borrow_mut();
+            let later = timers.split_off(&(deadline, u64::MAX));
+            std::mem::replace(&mut *timers, later)
+                .into_values()
+                .collect()
+        };
+        for waker in due {
+            waker.wake();
+        }
+        true
+    }
+}
+
+impl SimHandle {
+    /// Simulated time since the simulation started.
+    pub fn now(&self) -> Duration {
+        self.shared.clock.now()
+    }
+
+    /// A future that completes once `duration` of simulated time passed.
+    pub fn sleep(&self, duration: Duration) -> Sleep {
+        Sleep {
+            handle: self.clone(),
+            deadline: self.now() + duration,
+            timer: None,
+        }
+    }
+}
+
+/// Future returned by `SimHandle::sleep`.
+pub struct Sleep {
+    handle: SimHandle,
+    deadline: Duration,
+    timer: Option<TimerKey>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let clock = &self.handle.shared.clock;
+        if let Some(timer) = self.timer {
+            clock.cancel(timer);
+        }
+        if clock.now() >= self.deadline {
+            self.timer = None;
+            return Poll::Ready(());
+        }
+        let timer = clock.register(self.deadline, cx.waker().clone());
+        self.timer = Some(timer);
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(timer) = self.timer {
+            self.handle.shared.clock.cancel(timer);
+        }
+    }
+}
+
+#[test]
+fn sleeping_takes_no_real_time() {
+    use {super::Sim, std::rc::Rc, std::time::Instant};
+
+    let sim = Sim::new(0);
+    let woke_at = Rc::new(RefCell::new(Vec::new()));
+    for secs in &[30, 10, 20] {
+        let handle = sim.handle();
+        let woke_at = woke_at.clone();
+        sim.spawn(async move {
+            handle.sleep(Duration::from_secs(*secs)).await;
+            woke_at.borrow_mut().push(handle.now());
+        });
+    }
+    let started = Instant::now();
+    let report = sim.run();
+
+    assert!(started.elapsed() < Duration::from_secs(1));
+    assert_eq!(report.elapsed, Duration::from_secs(30));
+    assert_eq!(
+        *woke_at.borrow(),
+        [10, 20, 30]
+            .iter()
+            .map(|&s| Duration::from_secs(s))
+            .collect::<Vec<_>>()
+    );
+}
@@ -0,0 +1,360 @@
+//! A deterministic executor for reproducing races.
+//!
+//! The executor above polls tasks in the order they were woken, and wakes
+//! come from timer threads and the OS, so a bug that depends on how two
+//! tasks interleave shows up once in a while and then can't be made to
+//! happen again. The simulation executor removes every source of
+//! nondeterminism instead:
+//!
+//! * Out of all tasks that are ready to be polled, the next one is picked
+//!   by a random number generator seeded by the test.
+//! * Time is virtual (see `clock`). When no task is ready, the clock jumps
+//!   straight to the next timer, so simulated timeouts take no real time.
+//! * Connections go through an in-memory network (see `net`) whose
+//!   latencies are drawn from the same generator.
+//!
+//! Running a simulation twice with the same seed therefore polls the same
+//! tasks in the same order. The order is recorded in the `SimReport`, and
+//! `check` runs a test under many seeds, naming the seed that failed so it
+//! can be replayed with `Sim::new`.
+//!
+//! Tasks must only be woken by things belonging to the simulation; a
+//! `TimerFuture` waking a task from its own thread brings real time back
+//! into the picture.
+
+use {
+    futures::{
+        future::{FutureExt, LocalBoxFuture},
+        task::{waker_ref, ArcWake},
+    },
+    std::{
+        cell::{Cell, RefCell},
+        collections::{BTreeSet, HashMap},
+        future::Future,
+        panic::{self, AssertUnwindSafe},
+        pin::Pin,
+        rc::Rc,
+        sync::{Arc, Mutex},
+        task::{Context, Poll},
+        time::Duration,
+    },
+};
+
+pub mod clock;
+pub mod net;
+
+/// Identifies a task within one simulation, in spawn order.
+pub type TaskId = u64;
+
+/// A small, seedable random number generator (SplitMix64). It's not fit
+/// for anything but picking tasks, but it's the same on every platform.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`.
+    fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}
+
+/// State shared by the executor and every `SimHandle`.
+struct Shared {
+    rng: RefCell<Rng>,
+    next_id: Cell<TaskId>,
+    /// Tasks that haven't completed. A task's future is taken out while
+    /// it is being polled.
+    tasks: RefCell<HashMap<TaskId, Option<LocalBoxFuture<'static, ()>>>>,
+    /// Ids of the tasks that have been woken. Kept sorted, so that picking
+    /// from it only depends on the random number.
+    runnable: Arc<Mutex<BTreeSet<TaskId>>>,
+    clock: clock::ClockState,
+    net: net::NetState,
+}
+
+/// The waker of a simulated task.
+struct SimWaker {
+    id: TaskId,
+    runnable: Arc<Mutex<BTreeSet<TaskId>>>,
+}
+
+impl ArcWake for SimWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.runnable.lock().unwrap().insert(arc_self.id);
+    }
+}
+
+/// A deterministic, single threaded executor.
+pub struct Sim {
+    seed: u64,
+    handle: SimHandle,
+}
+
+/// Access to a running simulation: spawning, the clock and the network.
+#[derive(Clone)]
+pub struct SimHandle {
+    shared: Rc<Shared>,
+}
+
+/// What happened during a simulation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SimReport {
+    /// The seed the simulation ran with.
+    pub seed: u64,
+    /// Every poll, in order, by the id of the task polled.
+    pub schedule: Vec<TaskId>,
+    /// Tasks that were still pending when nothing was left to wake them.
+    pub unfinished: Vec<TaskId>,
+    /// Simulated time at the end of the run.
+    pub elapsed: Duration,
+}
+
+impl Sim {
+    pub fn new(seed: u64) -> Self {
+        Sim {
+            seed,
+            handle: SimHandle {
+                shared: Rc::new(Shared {
+                    rng: RefCell::new(Rng(seed)),
+                    next_id: Cell::new(0),
+                    tasks: RefCell::new(HashMap::new()),
+                    runnable: Arc::new(Mutex::new(BTreeSet::new())),
+                    clock: clock::ClockState::default(),
+                    net: net::NetState::default(),
+                }),
+            },
+        }
+    }
+
+    pub fn handle(&self) -> SimHandle {
+        self.handle.clone()
+    }
+
+    /// Spawn a task onto the simulation, see `SimHandle::spawn`.
+    pub fn spawn(&self, future: impl Future<Output = ()> + 'static) -> TaskId {
+        self.handle.spawn(future)
+    }
+
+    /// Run until every task has completed, or until none of the remaining
+    /// tasks can be woken any more.
+    pub fn run(self) -> SimReport {
+        let shared = &self.handle.shared;
+        let mut schedule = Vec::new();
+        loop {
+            let next = {
+                let mut runnable = shared.runnable.lock().unwrap();
+                if runnable.is_empty() {
+                    None
+                } else {
+                    let pick = shared.rng.borrow_mut().below(runnable.len() as u64);
+                    let id = *runnable.iter().nth(pick as usize).unwrap();
+                    runnable.remove(&id);
+                    Some(id)
+                }
+            };
+            let id = match next {
+                Some(id) => id,
+                // Nothing can run until time moves on.
+                None if shared.clock.advance() => continue,
+                None => break,
+            };
+
+            let future = shared
+                .tasks
+                .borrow_mut()
+                .get_mut(&id)
+                .and_then(Option::take);
+            let mut future = match future {
+                Some(future) => future,
+                // Woken after it completed.
+                None => continue,
+            };
+            schedule.push(id);
+
+            let waker = Arc::new(SimWaker {
+                id,
+                runnable: shared.runnable.clone(),
+            });
+            let waker = waker_ref(&waker);
+            let context = &mut Context::from_waker(&waker);
+            if future.as_mut().poll(context).is_pending() {
+                shared.tasks.borrow_mut().insert(id, Some(future));
+            } else {
+                shared.tasks.borrow_mut().remove(&id);
+            }
+        }
+
+        let mut unfinished: Vec<_> = shared.tasks.borrow().keys().copied().collect();
+        unfinished.sort_unstable();
+        // Unfinished tasks and queued connections may hold handles, which
+        // would keep `shared` alive forever.
+        let tasks = std::mem::take(&mut *shared.tasks.borrow_mut());
+        drop(tasks);
+        shared.net.clear();
+        SimReport {
+            seed: self.seed,
+            schedule,
+            unfinished,
+            elapsed: shared.clock.now(),
+        }
+    }
+}
+
+impl SimHandle {
+    /// Spawn a task. Unlike `Spawner::spawn`, the future doesn't need to be
+    /// `Send`: a simulation runs on a single thread.
+    pub fn spawn(&self, future: impl Future<Output = ()> + 'static) -> TaskId {
+        let id = self.shared.next_id.get();
+        self.shared.next_id.set(id + 1);
+        self.shared
+            .tasks
+            .borrow_mut()
+            .insert(id, Some(future.boxed_local()));
+        self.shared.runnable.lock().unwrap().insert(id);
+        id
+    }
+
+    /// Let the simulation pick which task runs next.
+    pub fn yield_now(&self) -> YieldNow {
+        YieldNow { yielded: false }
+    }
+
+    /// A random number in `0..n`, from the simulation's generator.
+    pub fn random_below(&self, n: u64) -> u64 {
+        self.shared.rng.borrow_mut().below(n)
+    }
+}
+
+/// Future returned by `SimHandle::yield_now`.
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            Poll::Ready(())
+        } else {
+            self.yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+/// Run `test` in a fresh simulation for every seed in `seeds`.
+///
+/// A panic, whether in `test` or in a task it spawned, is reported along
+/// with the seed it happened under.
+pub fn check(seeds: impl IntoIterator<Item = u64>, test: impl Fn(&SimHandle)) {
+    for seed in seeds {
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+            let sim = Sim::new(seed);
+            test(&sim.handle());
+            sim.run()
+        }));
+        if let Err(payload) = outcome {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            panic!("simulation failed with seed {}: {}", seed, message);
+        }
+    }
+}
+
+/// Two tasks increment a shared counter, but read and write it in separate
+/// steps with a yield in between: the classic lost update.
+fn lost_update(sim: &SimHandle) -> Rc<Cell<u32>> {
+    let counter = Rc::new(Cell::new(0));
+    for _ in 0..2 {
+        let counter = counter.clone();
+        let handle = sim.clone();
+        sim.spawn(async move {
+            let read = counter.get();
+            handle.yield_now().await;
+            counter.set(read + 1);
+        });
+    }
+    counter
+}
+
+#[test]
+fn same_seed_same_schedule() {
+    let run = |seed| {
+        let sim = Sim::new(seed);
+        let counter = lost_update(&sim.handle());
+        let report = sim.run();
+        (report, counter.get())
+    };
+
+    for seed in 0..20 {
+        assert_eq!(run(seed), run(seed));
+    }
+}
+
+#[test]
+fn seeds_explore_different_interleavings() {
+    let outcomes: BTreeSet<u32> = (0..20)
+        .map(|seed| {
+            let sim = Sim::new(seed);
+            let counter = lost_update(&sim.handle());
+            sim.run();
+            counter.get()
+        })
+        .collect();
+
+    // Some seeds lose an update and some don't.
+    assert_eq!(outcomes, [1, 2].iter().copied().collect());
+}
+
+#[test]
+fn check_names_the_failing_seed() {
+    let failure = panic::catch_unwind(|| {
+        check(0..20, |sim| {
+            let counter = lost_update(sim);
+            sim.spawn({
+                let handle = sim.clone();
+                async move {
+                    handle.sleep(Duration::from_secs(1)).await;
+                    assert_eq!(counter.get(), 2, "lost an update");
+                }
+            });
+        })
+    })
+    .unwrap_err();
+    let message = failure.downcast_ref::<String>().unwrap();
+    let seed: u64 = message
+        .trim_start_matches("simulation failed with seed ")
+        .split(':')
+        .next()
+        .unwrap()
+        .parse()
+        .unwrap();
+
+    // Replaying the seed loses the update again.
+    let sim = Sim::new(seed);
+    let counter = lost_update(&sim.handle());
+    sim.run();
+    assert_eq!(counter.get(), 1);
+}
+
+#[test]
+fn reports_tasks_that_can_never_finish() {
+    let sim = Sim::new(0);
+    let finishes = sim.spawn(async {});
+    let stuck = sim.spawn(futures::future::pending());
+    let report = sim.run();
+
+    assert!(report.schedule.contains(&finishes));
+    assert_eq!(report.unfinished, vec![stuck]);
+}
@@ -0,0 +1,327 @@
+//! An in-memory network for the simulation executor.
+//!
+//! Listeners are identified by a port number. Every chunk of bytes written
+//! to a `SimStream` is delivered to its peer after a random latency drawn
+//! from the simulation's generator, but always in order, like TCP.
+
+use {
+    super::{clock::TimerKey, SimHandle},
+    futures::io::{AsyncRead, AsyncWrite},
+    std::{
+        cell::{Cell, RefCell},
+        collections::{HashMap, VecDeque},
+        future::Future,
+        io,
+        pin::Pin,
+        rc::Rc,
+        task::{Context, Poll, Waker},
+        time::Duration,
+    },
+};
+
+pub(super) struct NetState {
+    listeners: RefCell<HashMap<u16, Rc<RefCell<Backlog>>>>,
+    max_latency: Cell<Duration>,
+}
+
+impl Default for NetState {
+    fn default() -> Self {
+        NetState {
+            listeners: RefCell::new(HashMap::new()),
+            max_latency: Cell::new(Duration::from_millis(5)),
+        }
+    }
+}
+
+impl NetState {
+    /// Drop all listeners' queued connections.
+    pub(super) fn clear(&self) {
+        let listeners = std::mem::take(&mut *self.listeners.borrow_mut());
+        for backlog in listeners.values() {
+            let streams = std::mem::take(&mut backlog.borrow_mut().streams);
+            drop(streams);
+        }
+    }
+}
+
+/// Connections waiting to be accepted.
+#[derive(Default)]
+struct Backlog {
+    streams: VecDeque<SimStream>,
+    acceptor: Option<Waker>,
+}
+
+/// One direction of a connection.
+#[derive(Default)]
+struct Pipe {
+    /// Written chunks, with the time at which they arrive.
+    in_flight: VecDeque<(Duration, Vec<u8>)>,
+    /// Bytes that have arrived but haven't been read.
+    arrived: VecDeque<u8>,
+    /// Arrival time of the last chunk, so that chunks can't overtake it.
+    last_arrival: Duration,
+    /// When the end of the stream arrives, once the writer closed it.
+    closed_at: Option<Duration>,
+    /// Set when the reading end was dropped.
+    reader_gone: bool,
+    reader: Option<Waker>,
+    timer: Option<TimerKey>,
+}
+
+impl SimHandle {
+    /// Set the upper bound for the latency of each write. Defaults to 5ms.
+    pub fn set_max_latency(&self, latency: Duration) {
+        self.shared.net.max_latency.set(latency);
+    }
+
+    /// Start listening for connections on `port`.
+    pub fn bind(&self, port: u16) -> io::Result<SimListener> {
+        let mut listeners = self.shared.net.listeners.borrow_mut();
+        if listeners.contains_key(&port) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        let backlog = Rc::new(RefCell::new(Backlog::default()));
+        listeners.insert(port, backlog.clone());
+        Ok(SimListener {
+            handle: self.clone(),
+            port,
+            backlog,
+        })
+    }
+
+    /// Connect to the listener on `port`.
+    pub fn connect(&self, port: u16) -> io::Result<SimStream> {
+        let backlog = self
+            .shared
+            .net
+            .listeners
+            .borrow()
+            .get(&port)
+            .cloned()
+            .ok_or(io::ErrorKind::ConnectionRefused)?;
+        let there = Rc::new(RefCell::new(Pipe::default()));
+        let back = Rc::new(RefCell::new(Pipe::default()));
+        let server_end = SimStream {
+            handle: self.clone(),
+            incoming: there.clone(),
+            outgoing: back.clone(),
+        };
+        let mut backlog = backlog.borrow_mut();
+        backlog.streams.push_back(server_end);
+        if let Some(acceptor) = backlog.acceptor.take() {
+            acceptor.wake();
+        }
+        Ok(SimStream {
+            handle: self.clone(),
+            incoming: back,
+            outgoing: there,
+        })
+    }
+
+    /// When something written now arrives.
+    fn arrival_time(&self) -> Duration {
+        let max_latency = self.shared.net.max_latency.get().as_micros() as u64;
+        let latency = Duration::from_micros(self.random_below(max_latency + 1));
+        self.now() + latency
+    }
+}
+
+/// A listening port in the simulated network.
+pub struct SimListener {
+    handle: SimHandle,
+    port: u16,
+    backlog: Rc<RefCell<Backlog>>,
+}
+
+impl SimListener {
+    /// Wait for the next incoming connection.
+    pub fn accept(&self) -> Accept<'_> {
+        Accept { listener: self }
+    }
+}
+
+impl Drop for SimListener {
+    fn drop(&mut self) {
+        self.handle
+            .shared
+            .net
+            .listeners
+            .borrow_mut()
+            .remove(&self.port);
+    }
+}
+
+/// Future returned by `SimListener::accept`.
+pub struct Accept<'a> {
+    listener: &'a SimListener,
+}
+
+impl Future for Accept<'_> {
+    type Output = SimStream;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<SimStream> {
+        let mut backlog = self.listener.backlog.borrow_mut();
+        match backlog.streams.pop_front() {
+            Some(stream) => Poll::Ready(stream),
+            None => {
+                backlog.acceptor = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// One end of a simulated connection.
+pub struct SimStream {
+    handle: SimHandle,
+    incoming: Rc<RefCell<Pipe>>,
+    outgoing: Rc<RefCell<Pipe>>,
+}
+
+impl SimStream {
+    /// Close the writing half, if it isn't already.
+    fn close_outgoing(&self) {
+        let arrival = self.handle.arrival_time();
+        let mut pipe = self.outgoing.borrow_mut();
+        if pipe.closed_at.is_none() {
+            pipe.closed_at = Some(arrival.max(pipe.last_arrival));
+            if let Some(reader) = pipe.reader.take() {
+                reader.wake();
+            }
+        }
+    }
+}
+
+impl AsyncRead for SimStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let now = self.handle.now();
+        let clock = &self.handle.shared.clock;
+        let mut pipe = self.incoming.borrow_mut();
+        if let Some(timer) = pipe.timer.take() {
+            clock.cancel(timer);
+        }
+        while pipe.in_flight.front().is_some_and(|(at, _)| *at <= now) {
+            let (_, chunk) = pipe.in_flight.pop_front().unwrap();
+            pipe.arrived.extend(chunk);
+        }
+
+        if !pipe.arrived.is_empty() {
+            let size = buf.len().min(pipe.arrived.len());
+            for (byte, arrived) in buf.iter_mut().zip(pipe.arrived.drain(..size)) {
+                *byte = arrived;
+            }
+            return Poll::Ready(Ok(size));
+        }
+        if pipe.in_flight.is_empty() && pipe.closed_at.is_some_and(|at| at <= now) {
+            return Poll::Ready(Ok(0));
+        }
+
+        // Wake up for the next write, or when what's in flight arrives.
+        pipe.reader = Some(cx.waker().clone());
+        let next_arrival = pipe.in_flight.front().map(|(at, _)| *at).or(pipe.closed_at);
+        if let Some(at) = next_arrival {
+            pipe.timer = Some(clock.register(at, cx.waker().clone()));
+        }
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for SimStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let arrival = self.handle.arrival_time();
+        let mut pipe = self.outgoing.borrow_mut();
+        if pipe.reader_gone {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if pipe.closed_at.is_some() {
+            return Poll::Ready(Err(io::ErrorKind::NotConnected.into()));
+        }
+        let arrival = arrival.max(pipe.last_arrival);
+        pipe.last_arrival = arrival;
+        pipe.in_flight.push_back((arrival, buf.to_vec()));
+        if let Some(reader) = pipe.reader.take() {
+            reader.wake();
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.close_outgoing();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for SimStream {
+    fn drop(&mut self) {
+        self.close_outgoing();
+        let mut incoming = self.incoming.borrow_mut();
+        incoming.reader_gone = true;
+        if let Some(timer) = incoming.timer.take() {
+            self.handle.shared.clock.cancel(timer);
+        }
+    }
+}
+
+#[test]
+fn echo_over_the_simulated_network() {
+    use {
+        super::Sim,
+        futures::io::{AsyncReadExt, AsyncWriteExt},
+    };
+
+    let run = |seed| {
+        let sim = Sim::new(seed);
+        let handle = sim.handle();
+        handle.set_max_latency(Duration::from_millis(20));
+        let listener = handle.bind(80).unwrap();
+        sim.spawn(async move {
+            let mut stream = listener.accept().await;
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            stream.write_all(&received).await.unwrap();
+        });
+
+        let echoed = Rc::new(RefCell::new(Vec::new()));
+        {
+            let echoed = echoed.clone();
+            sim.spawn(async move {
+                let mut stream = handle.connect(80).unwrap();
+                stream.write_all(b"hello ").await.unwrap();
+                stream.write_all(b"world").await.unwrap();
+                stream.close().await.unwrap();
+                let mut received = Vec::new();
+                stream.read_to_end(&mut received).await.unwrap();
+                *echoed.borrow_mut() = received;
+            });
+        }
+        let report = sim.run();
+        let echoed = echoed.borrow().clone();
+        (report, echoed)
+    };
+
+    let (report, echoed) = run(7);
+    assert_eq!(echoed, b"hello world");
+    assert!(report.unfinished.is_empty());
+    assert!(report.elapsed > Duration::from_secs(0));
+    assert_eq!(run(7), (report, echoed));
+}
+
+#[test]
+fn connecting_to_a_closed_port_is_refused() {
+    let sim = super::Sim::new(0);
+    let handle = sim.handle();
+    drop(handle.bind(80).unwrap());
+    let error = handle.connect(80).err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+}
@@ -0,0 +1,520 @@
+//! A line for every request served.
+//!
+//! Lines come in one of the formats web servers have long used, so that
+//! the usual tools can read them:
+//!
+//! ```text
+//! 127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /hello/you HTTP/1.1" 200 11
+//! ```
+//!
+//! That's the Common Log Format. The Combined Log Format adds the
+//! `Referer` and `User-Agent` headers, and there's JSON for tools that
+//! would rather not parse either, which also has how long the request
+//! took.
+//!
+//! Writing to a file or a terminal can be slow, and no connection should
+//! have to wait for it. So lines are handed to a task of their own over a
+//! bounded channel; should that task fall too far behind, lines are
+//! dropped, and counted, rather than held up for.
+
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_std::fs::{self, File, OpenOptions};
+use async_std::io;
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use async_std::task::{self, JoinHandle};
+use futures::channel::mpsc;
+
+/// How many lines may be waiting to be written before we drop any.
+const BACKLOG: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Common,
+    Combined,
+    Json,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LogTarget {
+    Off,
+    Stdout,
+    /// A file that's moved aside to `<path>.1` once it would grow past
+    /// `max_size` bytes, with the one before it moving on to `<path>.2`,
+    /// and so on, keeping `keep` of them.
+    File {
+        path: PathBuf,
+        max_size: u64,
+        keep: usize,
+    },
+}
+
+/// A stream that may know the address of the client on its other end.
+pub trait Peer {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+impl Peer for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+}
+
+impl<T: Peer + ?Sized> Peer for &mut T {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        (**self).peer_addr()
+    }
+}
+
+/// What we log about a request.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub remote: Option<SocketAddr>,
+    /// When the request arrived.
+    pub time: SystemTime,
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub status: u16,
+    /// The length of the response body.
+    pub bytes: u64,
+    /// From the request arriving to the response having been sent.
+    pub latency: Duration,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl Entry {
+    pub fn format(&self, format: LogFormat) -> String {
+        let remote = self
+            .remote
+            .map_or_else(|| "-".to_string(), |remote| remote.ip().to_string());
+        match format {
+            LogFormat::Common | LogFormat::Combined => {
+                let mut line = format!(
+                    "{} - - [{}] \"{} {} {}\" {} {}",
+                    remote,
+                    clf_time(self.time),
+                    self.method,
+                    self.target,
+                    self.version,
+                    self.status,
+                    // No body is written `-`, rather than 0.
+                    if self.bytes == 0 {
+                        "-".to_string()
+                    } else {
+                        self.bytes.to_string()
+                    }
+                );
+                if format == LogFormat::Combined {
+                    let quoted = |value: &Option<String>| match value {
+                        Some(value) => format!("\"{}\"", clf_escape(value)),
+                        None => "\"-\"".to_string(),
+                    };
+                    write!(
+                        line,
+                        " {} {}",
+                        quoted(&self.referer),
+                        quoted(&self.user_agent)
+                    )
+                    .unwrap();
+                }
+                line
+            }
+            LogFormat::Json => {
+                let string = |value: &str| format!("\"{}\"", json_escape(value));
+                let optional = |value: &Option<String>| {
+                    value.as_deref().map_or_else(|| "null".to_string(), string)
+                };
+                format!(
+                    "{{\"remote\":{},\"time\":\"{}\",\"method\":{},\"target\":{},\
+                     \"version\":{},\"status\":{},\"bytes\":{},\"latency_ms\":{:.3},\
+                     \"referer\":{},\"user_agent\":{}}}",
+                    self.remote
+                        .map_or_else(|| "null".to_string(), |_| string(&remote)),
+                    iso_time(self.time),
+                    string(&self.method),
+                    string(&self.target),
+                    string(&self.version),
+                    self.status,
+                    self.bytes,
+                    self.latency.as_secs_f64() * 1000.0,
+                    optional(&self.referer),
+                    optional(&self.user_agent),
+                )
+            }
+        }
+    }
+}
+
+fn json_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// `value` escaped the way Apache escapes quoted fields, so that it can't
+/// end its quotes early, or its line.
+fn clf_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_ascii_control() => write!(escaped, "\\x{:02x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// `time` in UTC, as `(year, month, day, hour, minute, second)`.
+fn utc(time: SystemTime) -> (i64, u32, u32, u64, u64, u64) {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, seconds) = ((seconds / 86400) as i64, seconds % 86400);
+    // Days since 1970-01-01 to a date in the proleptic Gregorian calendar,
+    // as in Howard Hinnant's `civil_from_days`.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+    )
+}
+
+/// `10/Oct/2000:13:55:36 +0000`
+fn clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (year, month, day, hour, minute, second) = utc(time);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hour,
+        minute,
+        second
+    )
+}
+
+/// `2000-10-10T13:55:36Z`
+fn iso_time(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second) = utc(time);
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, hour, minute, second
+    )
+}
+
+pub struct AccessLog {
+    format: LogFormat,
+    lines: Mutex<Option<mpsc::Sender<String>>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+    dropped: AtomicU64,
+}
+
+impl AccessLog {
+    /// Start writing lines in `format` to `target`.
+    pub fn new(target: LogTarget, format: LogFormat) -> Self {
+        let (lines, writer) = if target == LogTarget::Off {
+            (None, None)
+        } else {
+            let (sender, receiver) = mpsc::channel(BACKLOG);
+            (
+                Some(sender),
+                Some(task::spawn(write_lines(receiver, target))),
+            )
+        };
+        AccessLog {
+            format,
+            lines: Mutex::new(lines),
+            writer: Mutex::new(writer),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Log `entry`, unless the writer is too far behind.
+    pub fn log(&self, entry: &Entry) {
+        let mut lines = self.lines.lock().unwrap();
+        let lines = match &mut *lines {
+            Some(lines) => lines,
+            None => return,
+        };
+        if lines.try_send(entry.format(self.format)).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// How many lines have been dropped.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Stop logging, once the lines already logged have been written.
+    pub async fn close(&self) {
+        self.lines.lock().unwrap().take();
+        let writer = self.writer.lock().unwrap().take();
+        if let Some(writer) = writer {
+            writer.await;
+        }
+        if self.dropped() > 0 {
+            eprintln!("access log: dropped {} lines", self.dropped());
+        }
+    }
+}
+
+async fn write_lines(mut lines: mpsc::Receiver<String>, target: LogTarget) {
+    let mut output = match Output::open(target).await {
+        Ok(output) => output,
+        Err(error) => {
+            eprintln!("access log: {}", error);
+            return;
+        }
+    };
+    while let Some(line) = lines.next().await {
+        if let Err(error) = output.write_line(&line).await {
+            eprintln!("access log: {}", error);
+        }
+    }
+    if let Err(error) = output.flush().await {
+        eprintln!("access log: {}", error);
+    }
+}
+
+enum Output {
+    Stdout(io::Stdout),
+    File(RotatingFile),
+}
+
+impl Output {
+    async fn open(target: LogTarget) -> io::Result<Self> {
+        Ok(match target {
+            // Never opened.
+            LogTarget::Off => unreachable!(),
+            LogTarget::Stdout => Output::Stdout(io::stdout()),
+            LogTarget::File {
+                path,
+                max_size,
+                keep,
+            } => Output::File(RotatingFile::open(path, max_size, keep).await?),
+        })
+    }
+
+    async fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let line = format!("{}\n", line);
+        match self {
+            Output::Stdout(stdout) => {
+                stdout.write_all(line.as_bytes()).await?;
+                stdout.flush().await
+            }
+            Output::File(file) => file.write(line.as_bytes()).await,
+        }
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout(stdout) => stdout.flush().await,
+            Output::File(file) => file.file.flush().await,
+        }
+    }
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    async fn open(path: PathBuf, max_size: u64, keep: usize) -> io::Result<Self> {
+        let file = append(&path).await?;
+        let size = file.metadata().await?.len();
+        Ok(RotatingFile {
+            path,
+            max_size,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + bytes.len() as u64 > self.max_size {
+            self.rotate().await?;
+        }
+        self.file.write_all(bytes).await?;
+        self.size += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Move each file along by one, dropping the oldest, and start afresh.
+    async fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush().await?;
+        for n in (1..self.keep).rev() {
+            rename_if_exists(&numbered(&self.path, n), &numbered(&self.path, n + 1)).await?;
+        }
+        if self.keep > 0 {
+            rename_if_exists(&self.path, &numbered(&self.path, 1)).await?;
+        } else {
+            fs::remove_file(&self.path).await?;
+        }
+        self.file = append(&self.path).await?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+async fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+}
+
+/// `access.log` numbered 2 is `access.log.2`.
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{}", n));
+    PathBuf::from(path)
+}
+
+async fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to).await {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> Entry {
+        Entry {
+            remote: Some("127.0.0.1:52000".parse().unwrap()),
+            time: UNIX_EPOCH + Duration::from_secs(971_186_136),
+            method: "GET".to_string(),
+            target: "/hello/you?x=\"1\"".to_string(),
+            version: "HTTP/1.1".to_string(),
+            status: 200,
+            bytes: 11,
+            latency: Duration::from_micros(1500),
+            referer: None,
+            user_agent: Some("curl/8.0".to_string()),
+        }
+    }
+
+    #[test]
+    fn formats_entries() {
+        let entry = entry();
+        assert_eq!(
+            entry.format(LogFormat::Common),
+            r#"127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /hello/you?x="1" HTTP/1.1" 200 11"#
+        );
+        assert_eq!(
+            entry.format(LogFormat::Combined),
+            r#"127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /hello/you?x="1" HTTP/1.1" 200 11 "-" "curl/8.0""#
+        );
+        assert_eq!(
+            entry.format(LogFormat::Json),
+            r#"{"remote":"127.0.0.1","time":"2000-10-10T13:55:36Z","method":"GET","target":"/hello/you?x=\"1\"","version":"HTTP/1.1","status":200,"bytes":11,"latency_ms":1.500,"referer":null,"user_agent":"curl/8.0"}"#
+        );
+
+        let forged = Entry {
+            referer: Some("a\\\"b".to_string()),
+            user_agent: Some("x\"\n127.0.0.1 - - [] \"GET /\x7f".to_string()),
+            ..entry.clone()
+        };
+        assert!(forged
+            .format(LogFormat::Combined)
+            .ends_with(r#" 200 11 "a\\\"b" "x\"\n127.0.0.1 - - [] \"GET /\x7f""#));
+
+        let entry = Entry {
+            remote: None,
+            bytes: 0,
+            ..entry
+        };
+        assert!(entry.format(LogFormat::Common).starts_with("- - - ["));
+        assert!(entry.format(LogFormat::Common).ends_with(" 200 -"));
+        assert!(entry
+            .format(LogFormat::Json)
+            .starts_with(r#"{"remote":null,"#));
+    }
+
+    #[test]
+    fn converts_times_to_dates() {
+        let date = |seconds| utc(UNIX_EPOCH + Duration::from_secs(seconds));
+        assert_eq!(date(0), (1970, 1, 1, 0, 0, 0));
+        assert_eq!(date(951_782_400), (2000, 2, 29, 0, 0, 0));
+        assert_eq!(date(1_709_251_199), (2024, 2, 29, 23, 59, 59));
+        assert_eq!(date(4_102_444_800), (2100, 1, 1, 0, 0, 0));
+    }
+
+    #[async_std::test]
+    async fn rotates_files() {
+        let dir = std::env::temp_dir().join(format!("final-tcp-server-{}-log", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let target = LogTarget::File {
+            path: path.clone(),
+            max_size: 10,
+            keep: 2,
+        };
+        let log = AccessLog::new(target, LogFormat::Common);
+        for status in [200, 201, 202, 203] {
+            log.log(&Entry { status, ..entry() });
+        }
+        log.close().await;
+
+        let read = |path: &Path| std::fs::read_to_string(path).unwrap();
+        // Every line is over the limit, so each one gets a file of its own.
+        assert!(read(&path).contains("\" 203 "));
+        assert!(read(&numbered(&path, 1)).contains("\" 202 "));
+        assert!(read(&numbered(&path, 2)).contains("\" 201 "));
+        assert!(!numbered(&path, 3).exists());
+        assert_eq!(log.dropped(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,253 @@
+//! Response bodies.
+//!
+//! A body is either bytes in memory or a reader it's streamed from, so
+//! that sending a large file doesn't mean holding all of it in memory. A
+//! streamed body is read one chunk at a time, and the next chunk is only
+//! read once the previous one has been written: a slow client slows down
+//! the reading instead of making the unsent part pile up.
+//!
+//! If a streamed body's length isn't known up front, it's sent with
+//! `Transfer-Encoding: chunked`.
+
+use std::fmt;
+use std::marker::Unpin;
+
+use async_std::io::{self, Read, Write};
+use async_std::prelude::*;
+
+/// How much of a streamed body is read before it's written out.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub enum Body {
+    Bytes(Vec<u8>),
+    Reader {
+        reader: Box<dyn Read + Send + Unpin>,
+        /// The number of bytes `reader` will produce, if known.
+        length: Option<u64>,
+    },
+}
+
+impl Body {
+    pub fn empty() -> Self {
+        Body::Bytes(Vec::new())
+    }
+
+    /// A body streamed from `reader`. With a `length`, exactly that many
+    /// bytes are read from it; without one, it's read to the end.
+    pub fn from_reader(reader: impl Read + Send + Unpin + 'static, length: Option<u64>) -> Self {
+        Body::Reader {
+            reader: Box::new(reader),
+            length,
+        }
+    }
+
+    /// The length of the body, if known before it's read.
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::Reader { length, .. } => *length,
+        }
+    }
+
+    /// Read the whole body into memory.
+    #[cfg(test)]
+    pub async fn into_bytes(self) -> io::Result<Vec<u8>> {
+        match self {
+            Body::Bytes(bytes) => Ok(bytes),
+            Body::Reader { reader, length } => {
+                let mut bytes = Vec::new();
+                let mut reader = reader.take(length.unwrap_or(u64::MAX));
+                reader.read_to_end(&mut bytes).await?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    /// Write the body onto `stream`, chunked if its length isn't known and
+    /// `chunked` is set. Returns the length of the body, not counting the
+    /// chunked encoding.
+    pub async fn write_to(
+        self,
+        stream: &mut (impl Write + Unpin),
+        chunked: bool,
+    ) -> io::Result<u64> {
+        let (reader, length) = match self {
+            Body::Bytes(bytes) => {
+                stream.write_all(&bytes).await?;
+                return Ok(bytes.len() as u64);
+            }
+            Body::Reader { reader, length } => (reader, length),
+        };
+        let mut reader = reader.take(length.unwrap_or(u64::MAX));
+        let mut buffer = vec![0; CHUNK_SIZE];
+        let mut written = 0;
+        loop {
+            let size = reader.read(&mut buffer).await?;
+            if size == 0 {
+                break;
+            }
+            if length.is_some() || !chunked {
+                stream.write_all(&buffer[..size]).await?;
+            } else {
+                let mut chunk = format!("{:x}\r\n", size).into_bytes();
+                chunk.extend_from_slice(&buffer[..size]);
+                chunk.extend_from_slice(b"\r\n");
+                stream.write_all(&chunk).await?;
+            }
+            written += size as u64;
+        }
+        match length {
+            // We promised the client more than there was; all it can do
+            // now is notice the connection closing.
+            Some(length) if written < length => Err(io::ErrorKind::UnexpectedEof.into()),
+            None if chunked => {
+                stream.write_all(b"0\r\n\r\n").await?;
+                Ok(written)
+            }
+            _ => Ok(written),
+        }
+    }
+}
+
+impl Default for Body {
+    fn default() -> Self {
+        Body::empty()
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Bytes(bytes) => write!(f, "Body::Bytes({} bytes)", bytes.len()),
+            Body::Reader { length, .. } => write!(f, "Body::Reader {{ length: {:?} }}", length),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for Body {
+    fn from(bytes: &[u8]) -> Self {
+        Body::Bytes(bytes.to_vec())
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Self {
+        Body::Bytes(text.into_bytes())
+    }
+}
+
+impl From<&str> for Body {
+    fn from(text: &str) -> Self {
+        Body::Bytes(text.as_bytes().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+
+    /// Produces `remaining` bytes, counting how many it has handed out.
+    struct CountingReader {
+        remaining: u64,
+        handed_out: Arc<AtomicU64>,
+    }
+
+    impl Read for CountingReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            let size = buf.len().min(self.remaining as usize);
+            let offset = self.handed_out.fetch_add(size as u64, Ordering::SeqCst);
+            for (i, byte) in buf[..size].iter_mut().enumerate() {
+                *byte = ((offset + i as u64) % 251) as u8;
+            }
+            self.remaining -= size as u64;
+            Poll::Ready(Ok(size))
+        }
+    }
+
+    /// Accepts a little at a time, and only every other time it's asked,
+    /// recording how far ahead of it the reader got.
+    struct SlowWriter {
+        written: Vec<u8>,
+        handed_out: Arc<AtomicU64>,
+        max_ahead: u64,
+        ready: bool,
+    }
+
+    impl Write for SlowWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let ahead = self.handed_out.load(Ordering::SeqCst) - self.written.len() as u64;
+            self.max_ahead = self.max_ahead.max(ahead);
+            let size = buf.len().min(1000);
+            self.written.extend_from_slice(&buf[..size]);
+            Poll::Ready(Ok(size))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[async_std::test]
+    async fn reads_no_further_ahead_than_a_chunk() {
+        let length = 3 * 1024 * 1024 + 17;
+        let handed_out = Arc::new(AtomicU64::new(0));
+        let reader = CountingReader {
+            remaining: length,
+            handed_out: handed_out.clone(),
+        };
+        let mut writer = SlowWriter {
+            written: Vec::new(),
+            handed_out,
+            max_ahead: 0,
+            ready: false,
+        };
+        Body::from_reader(reader, Some(length))
+            .write_to(&mut writer, true)
+            .await
+            .unwrap();
+
+        assert_eq!(writer.written.len() as u64, length);
+        assert!(writer
+            .written
+            .iter()
+            .enumerate()
+            .all(|(i, byte)| *byte == (i % 251) as u8));
+        assert!(
+            writer.max_ahead <= CHUNK_SIZE as u64,
+            "{}",
+            writer.max_ahead
+        );
+    }
+
+    #[async_std::test]
+    async fn fails_if_the_reader_ends_early() {
+        let body = Body::from_reader(&b"short"[..], Some(10));
+        let error = body.write_to(&mut Vec::new(), true).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,202 @@
+//! Settings for the server.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::access_log::{LogFormat, LogTarget};
+use crate::limit::WhenFull;
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// How long we wait for the next request on a connection before
+    /// closing it.
+    pub idle_timeout: Duration,
+    /// How long the rest of a request's head may take to arrive once it
+    /// has started, before the client gets a 408.
+    pub header_timeout: Duration,
+    /// How long we wait for the next piece of a request body before the
+    /// client gets a 408.
+    pub body_timeout: Duration,
+    /// How long a handler may take to respond before the client gets a
+    /// 503 instead.
+    pub handler_timeout: Duration,
+    /// How long a write may stall, because the client isn't reading,
+    /// before the connection is dropped.
+    pub write_timeout: Duration,
+    /// The largest request body we accept, in bytes.
+    pub max_body_size: u64,
+    /// The directory whose files are served under `/static/`.
+    pub static_root: PathBuf,
+    /// How many connections we serve at once.
+    pub max_connections: usize,
+    /// What happens to clients beyond `max_connections`.
+    pub when_full: WhenFull,
+    /// Where we listen for connections.
+    pub address: String,
+    /// How long open connections get to finish once we're asked to shut
+    /// down, before they're closed regardless.
+    pub shutdown_timeout: Duration,
+    /// The bearer token that `/stats` requires, if any.
+    pub admin_token: Option<String>,
+    /// Where a line for every request goes.
+    pub access_log: LogTarget,
+    /// What those lines look like.
+    pub log_format: LogFormat,
+    /// The PEM files with the certificate chain and private key to serve
+    /// HTTPS with, instead of plain HTTP. Only of use with the `tls`
+    /// feature.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+}
+
+impl Config {
+    /// The default settings, changed by command line arguments:
+    ///
+    /// * `--max-connections <n>` sets `max_connections`,
+    /// * `--reject-when-full` answers clients beyond it with a 503 instead
+    ///   of making them wait,
+    /// * `--address <address>` sets `address`,
+    /// * `--shutdown-timeout <seconds>` sets `shutdown_timeout`,
+    /// * `--admin-token <token>` sets `admin_token`,
+    /// * `--access-log <stdout|off|path>` sets `access_log`, with files
+    ///   rotated at 10 MiB and five old ones kept,
+    /// * `--log-format <common|combined|json>` sets `log_format`,
+    /// * `--tls-cert <path>` and `--tls-key <path>`, which go together,
+    ///   set `tls_cert` and `tls_key`.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut config = Config::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--max-connections" => {
+                    config.max_connections = args
+                        .next()
+                        .and_then(|max| max.parse().ok())
+                        .filter(|&max| max > 0)
+                        .ok_or("--max-connections needs a number above 0")?;
+                }
+                "--reject-when-full" => config.when_full = WhenFull::Reject,
+                "--address" => {
+                    config.address = args.next().ok_or("--address needs an address")?;
+                }
+                "--admin-token" => {
+                    let token = args.next().ok_or("--admin-token needs a token")?;
+                    config.admin_token = Some(token);
+                }
+                "--shutdown-timeout" => {
+                    config.shutdown_timeout = args
+                        .next()
+                        .and_then(|seconds| seconds.parse().ok())
+                        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                        .ok_or("--shutdown-timeout needs a number of seconds")?;
+                }
+                "--access-log" => {
+                    config.access_log = match args.next().as_deref() {
+                        Some("stdout") => LogTarget::Stdout,
+                        Some("off") => LogTarget::Off,
+                        Some(path) => LogTarget::File {
+                            path: PathBuf::from(path),
+                            max_size: 10 * 1024 * 1024,
+                            keep: 5,
+                        },
+                        None => return Err("--access-log needs stdout, off or a path".into()),
+                    };
+                }
+                "--log-format" => {
+                    config.log_format = match args.next().as_deref() {
+                        Some("common") => LogFormat::Common,
+                        Some("combined") => LogFormat::Combined,
+                        Some("json") => LogFormat::Json,
+                        _ => return Err("--log-format needs common, combined or json".into()),
+                    };
+                }
+                "--tls-cert" => {
+                    let path = args.next().ok_or("--tls-cert needs a path")?;
+                    config.tls_cert = Some(PathBuf::from(path));
+                }
+                "--tls-key" => {
+                    let path = args.next().ok_or("--tls-key needs a path")?;
+                    config.tls_key = Some(PathBuf::from(path));
+                }
+                _ => return Err(format!("unknown argument: {}", arg)),
+            }
+        }
+        if config.tls_cert.is_some() != config.tls_key.is_some() {
+            return Err("--tls-cert and --tls-key go together".into());
+        }
+        Ok(config)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            idle_timeout: Duration::from_secs(5),
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(10),
+            handler_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(10),
+            max_body_size: 1024 * 1024,
+            static_root: PathBuf::from("static"),
+            max_connections: 1024,
+            when_full: WhenFull::Queue,
+            address: "127.0.0.1:7878".to_string(),
+            shutdown_timeout: Duration::from_secs(10),
+            admin_token: None,
+            access_log: LogTarget::Stdout,
+            log_format: LogFormat::Common,
+            tls_cert: None,
+            tls_key: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn from_args(args: &[&str]) -> Result<Config, String> {
+        Config::from_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn reads_command_line_arguments() {
+        let config = from_args(&[]).unwrap();
+        assert_eq!(
+            (config.max_connections, config.when_full),
+            (1024, WhenFull::Queue)
+        );
+        let config = from_args(&["--reject-when-full", "--max-connections", "8"]).unwrap();
+        assert_eq!(
+            (config.max_connections, config.when_full),
+            (8, WhenFull::Reject)
+        );
+        assert!(from_args(&["--max-connections"]).is_err());
+        assert!(from_args(&["--max-connections", "0"]).is_err());
+        let config = from_args(&["--address", "0.0.0.0:80", "--shutdown-timeout", "0.5"]).unwrap();
+        assert_eq!(config.address, "0.0.0.0:80");
+        assert_eq!(config.shutdown_timeout, Duration::from_millis(500));
+        assert!(from_args(&["--shutdown-timeout", "soon"]).is_err());
+        // Negative, or not a number at all.
+        assert!(from_args(&["--shutdown-timeout", "-1"]).is_err());
+        assert!(from_args(&["--shutdown-timeout", "NaN"]).is_err());
+        let config = from_args(&["--admin-token", "s3cret"]).unwrap();
+        assert_eq!(config.admin_token.as_deref(), Some("s3cret"));
+        let config = from_args(&["--access-log", "off", "--log-format", "json"]).unwrap();
+        assert_eq!(
+            (config.access_log, config.log_format),
+            (LogTarget::Off, LogFormat::Json)
+        );
+        let config = from_args(&["--access-log", "access.log"]).unwrap();
+        assert!(
+            matches!(config.access_log, LogTarget::File { path, .. } if path == Path::new("access.log"))
+        );
+        assert!(from_args(&["--log-format", "xml"]).is_err());
+        let config = from_args(&["--tls-cert", "cert.pem", "--tls-key", "key.pem"]).unwrap();
+        assert_eq!(config.tls_cert.as_deref(), Some(Path::new("cert.pem")));
+        assert_eq!(config.tls_key.as_deref(), Some(Path::new("key.pem")));
+        assert!(from_args(&["--tls-cert", "cert.pem"]).is_err());
+        assert!(from_args(&["--verbose"]).is_err());
+    }
+}
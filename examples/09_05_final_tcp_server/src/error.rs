@@ -0,0 +1,100 @@
+//! What can go wrong while serving a connection.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::request::ParseError;
+
+/// The error a handler fails with. Anything that implements `Error` can be
+/// turned into one with `?`.
+pub type HandlerError = Box<dyn Error + Send + Sync>;
+
+#[derive(Debug)]
+pub enum ServerError {
+    /// Reading from or writing to the connection failed, usually because
+    /// the client went away.
+    Io(io::Error),
+    /// The client sent something that isn't a valid request.
+    Request(ParseError),
+    /// A handler failed. The client got a 500 for it.
+    Handler(HandlerError),
+    /// The client, or a handler, took too long.
+    Timeout(Phase),
+}
+
+/// What we were waiting for when we ran out of patience.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// The rest of a request's head, once it has started arriving.
+    Head,
+    /// The next piece of a request's body.
+    Body,
+    /// A handler's response.
+    Handler,
+}
+
+impl ServerError {
+    /// The status to tell the client about the error with, if it can be
+    /// told at all.
+    pub fn status_code(&self) -> Option<u16> {
+        match self {
+            ServerError::Io(_) => None,
+            ServerError::Request(error) => Some(error.status_code()),
+            ServerError::Handler(_) => Some(500),
+            ServerError::Timeout(Phase::Head) | ServerError::Timeout(Phase::Body) => Some(408),
+            ServerError::Timeout(Phase::Handler) => Some(503),
+        }
+    }
+
+    /// A copy of the error for the handler reading the request body, which
+    /// can only be told about it through `io::Error`s.
+    pub fn to_io_error(&self) -> io::Error {
+        match self {
+            ServerError::Io(error) => io::Error::new(error.kind(), error.to_string()),
+            ServerError::Request(error) => {
+                io::Error::new(io::ErrorKind::InvalidData, error.clone())
+            }
+            ServerError::Handler(error) => io::Error::other(error.to_string()),
+            ServerError::Timeout(_) => io::Error::new(io::ErrorKind::TimedOut, self.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::Io(error) => write!(f, "connection error: {}", error),
+            ServerError::Request(error) => write!(f, "bad request: {}", error),
+            ServerError::Handler(error) => write!(f, "handler failed: {}", error),
+            ServerError::Timeout(Phase::Head) => f.write_str("timed out reading the request head"),
+            ServerError::Timeout(Phase::Body) => f.write_str("timed out reading the request body"),
+            ServerError::Timeout(Phase::Handler) => {
+                f.write_str("timed out waiting for the handler")
+            }
+        }
+    }
+}
+
+impl Error for ServerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ServerError::Io(error) => Some(error),
+            ServerError::Request(error) => Some(error),
+            ServerError::Handler(error) => Some(&**error),
+            ServerError::Timeout(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(error: io::Error) -> Self {
+        ServerError::Io(error)
+    }
+}
+
+impl From<ParseError> for ServerError {
+    fn from(error: ParseError) -> Self {
+        ServerError::Request(error)
+    }
+}
@@ -0,0 +1,731 @@
+//! HTTP/2, with the `h2` crate. Only built with the `http2` feature.
+//!
+//! A client can start speaking HTTP/2 straight away, over plain TCP, if it
+//! knows we understand it ("prior knowledge"), or after agreeing on it
+//! during the TLS handshake (ALPN, see `tls`). Either way, the first thing
+//! it sends is `PREFACE`, which is how we tell it apart from an HTTP/1
+//! client.
+//!
+//! Each request arrives on a stream of its own, and many streams share a
+//! connection. The requests go to the same `Router` as HTTP/1 requests do,
+//! and are answered concurrently, in whatever order their handlers finish.
+
+use std::marker::Unpin;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime};
+
+use async_std::future::timeout;
+use async_std::io::{self, Read, Write};
+use async_std::prelude::*;
+use async_std::task;
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::future::{self, Either, FutureExt};
+use futures::io::AsyncWriteExt;
+use futures::pin_mut;
+use futures::sink::SinkExt;
+use futures::stream::{FuturesUnordered, StreamExt};
+use h2::server::SendResponse;
+use h2::{RecvStream, SendStream};
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+
+use crate::access_log::{AccessLog, Entry, Peer};
+use crate::config::Config;
+use crate::error::{Phase, ServerError};
+use crate::headers::Headers;
+use crate::request::{Params, ParseError, Request, Version};
+use crate::request_body::RequestBody;
+use crate::response::{reason_phrase, Response};
+use crate::router::Router;
+use crate::shutdown::Shutdown;
+use crate::write_timeout::WriteTimeout;
+
+/// What an HTTP/2 client starts a connection with.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// How many requests a client may have open at once on one connection.
+const MAX_STREAMS: u32 = 100;
+
+/// Headers that only mean something to HTTP/1 connections, and that
+/// HTTP/2 forbids.
+const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Answer requests on `stream` in HTTP/2 if the client starts with its
+/// preface, or HTTP/1 otherwise, as `serve_connection` does.
+pub async fn serve_any_version(
+    stream: impl Read + Write + Peer + Unpin,
+    config: &Config,
+    router: &Router,
+    shutdown: &Shutdown,
+    access_log: &AccessLog,
+) -> Result<(), ServerError> {
+    let sniffed = {
+        let sniff = timeout(config.idle_timeout, sniff(stream));
+        pin_mut!(sniff);
+        match future::select(sniff, shutdown.wait()).await {
+            Either::Left((Ok(sniffed), _)) => sniffed?,
+            // The client didn't say anything, or we're shutting down.
+            Either::Left((Err(_), _)) | Either::Right(_) => return Ok(()),
+        }
+    };
+    match sniffed {
+        (true, stream) => serve_connection(stream, config, router, shutdown, access_log).await,
+        (false, stream) => {
+            crate::serve_connection(stream, config, router, shutdown, access_log).await
+        }
+    }
+}
+
+/// Read as much from `stream` as it takes to tell whether it starts with
+/// `PREFACE`, and put it back.
+async fn sniff<S: Read + Unpin>(mut stream: S) -> io::Result<(bool, Rewind<S>)> {
+    let mut read = Vec::new();
+    let mut buffer = [0; PREFACE.len()];
+    while read.len() < PREFACE.len() && PREFACE.starts_with(&read) {
+        let size = stream
+            .read(&mut buffer[..PREFACE.len() - read.len()])
+            .await?;
+        if size == 0 {
+            break;
+        }
+        read.extend_from_slice(&buffer[..size]);
+    }
+    Ok((
+        read == PREFACE,
+        Rewind {
+            read,
+            inner: stream,
+        },
+    ))
+}
+
+/// A stream with what has been read from it already put back in front.
+pub struct Rewind<S> {
+    read: Vec<u8>,
+    inner: S,
+}
+
+impl<S: Read + Unpin> Read for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.read.is_empty() {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+        let size = self.read.len().min(buf.len());
+        buf[..size].copy_from_slice(&self.read[..size]);
+        self.read.drain(..size);
+        Poll::Ready(Ok(size))
+    }
+}
+
+impl<S: Write + Unpin> Write for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+impl<S: Peer> Peer for Rewind<S> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
+
+/// A request, and where its response goes.
+type Incoming = (http::Request<RecvStream>, SendResponse<Bytes>);
+
+/// What can happen while we wait on a connection.
+enum Event {
+    /// A request arrived, or, with `None`, the client is done sending them.
+    Accepted(Option<Box<Incoming>>),
+    Failed(h2::Error),
+    Served(Result<(), ServerError>),
+    /// The client went quiet, or we're shutting down.
+    Close,
+}
+
+/// Answer HTTP/2 requests on `stream`, which starts with the client's
+/// preface, until the client closes it or stays quiet for longer than
+/// `config.idle_timeout`.
+///
+/// Requests are answered as `crate::serve_connection` answers them, but
+/// concurrently. A request going wrong only resets its own stream.
+/// `shutdown` lets the requests in hand finish, but no more start.
+pub async fn serve_connection(
+    stream: impl Read + Write + Peer + Unpin,
+    config: &Config,
+    router: &Router,
+    shutdown: &Shutdown,
+    access_log: &AccessLog,
+) -> Result<(), ServerError> {
+    let remote = stream.peer_addr();
+    let handshake = h2::server::Builder::new()
+        .max_concurrent_streams(MAX_STREAMS)
+        .handshake(stream.compat());
+    let mut connection = match timeout(config.header_timeout, handshake).await {
+        Ok(connection) => connection.map_err(io_error)?,
+        Err(_) => return Err(ServerError::Timeout(Phase::Head)),
+    };
+    let mut streams = FuturesUnordered::new();
+    let mut closing = false;
+    loop {
+        let idle = streams.is_empty();
+        let close = async {
+            if closing {
+                future::pending().await
+            } else if idle {
+                let quiet = task::sleep(config.idle_timeout);
+                pin_mut!(quiet);
+                future::select(quiet, shutdown.wait()).await;
+            } else {
+                shutdown.wait().await
+            }
+        };
+        let event = futures::select! {
+            accepted = connection.accept().fuse() => match accepted {
+                Some(Ok(incoming)) => Event::Accepted(Some(Box::new(incoming))),
+                Some(Err(error)) => Event::Failed(error),
+                None => Event::Accepted(None),
+            },
+            served = streams.select_next_some() => Event::Served(served),
+            () = close.fuse() => Event::Close,
+        };
+        match event {
+            Event::Accepted(Some(incoming)) => {
+                let (request, respond) = *incoming;
+                streams.push(serve_stream(
+                    request, respond, remote, config, router, access_log,
+                ));
+            }
+            Event::Accepted(None) => break,
+            Event::Failed(error) => return Err(io_error(error).into()),
+            Event::Served(Err(error)) => eprintln!("{}", error),
+            Event::Served(Ok(())) => {}
+            Event::Close => {
+                closing = true;
+                connection.graceful_shutdown();
+            }
+        }
+    }
+    // The responses still going out need the connection to go out on.
+    let finish = streams.for_each(|served| async move {
+        if let Err(error) = served {
+            eprintln!("{}", error);
+        }
+    });
+    let closed = future::poll_fn(|cx| connection.poll_closed(cx));
+    let ((), closed) = future::join(finish, closed).await;
+    closed.map_err(|error| io_error(error).into())
+}
+
+/// Answer the request on one stream, and log it.
+async fn serve_stream(
+    request: http::Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    remote: Option<SocketAddr>,
+    config: &Config,
+    router: &Router,
+    access_log: &AccessLog,
+) -> Result<(), ServerError> {
+    let started = Instant::now();
+    let (head, body) = request.into_parts();
+    let mut request = to_request(head);
+    let mut entry = Entry {
+        remote,
+        time: SystemTime::now(),
+        method: request.method.clone(),
+        target: request.target.clone(),
+        version: Version::Http2.to_string(),
+        status: 0,
+        bytes: 0,
+        latency: Default::default(),
+        referer: request.headers.get("referer").map(str::to_string),
+        user_agent: request.headers.get("user-agent").map(str::to_string),
+    };
+    let too_large = request
+        .headers
+        .get("content-length")
+        .and_then(|length| length.parse::<u64>().ok())
+        .is_some_and(|length| length > config.max_body_size);
+
+    let (sender, request_body) = RequestBody::channel();
+    request.body = request_body;
+    let handler = crate::respond(router, config, request);
+    let body = async {
+        if too_large {
+            return Err(ParseError::BodyTooLarge.into());
+        }
+        read_body(body, config, sender).await
+    };
+    pin_mut!(handler, body);
+    // As with HTTP/1, a bad body gets answered rather than whatever the
+    // handler makes of it.
+    let (sent, body) = match future::select(body, handler).await {
+        Either::Left((Ok(()), handler)) => {
+            let response = refuse_upgrade(handler.await);
+            entry.status = response.status;
+            (send_response(&mut respond, response, config).await, Ok(()))
+        }
+        Either::Left((Err(error), _)) => {
+            let status = match error.status_code() {
+                Some(status) => status,
+                // Dropping `respond` resets the stream, which gets logged
+                // with a status of 0, as nothing was sent.
+                None => {
+                    entry.latency = started.elapsed();
+                    access_log.log(&entry);
+                    return Err(error);
+                }
+            };
+            let response = Response::text(status, format!("{}\n", reason_phrase(status)));
+            entry.status = status;
+            (
+                send_response(&mut respond, response, config).await,
+                Err(error),
+            )
+        }
+        Either::Right((response, body)) => {
+            let response = refuse_upgrade(response);
+            entry.status = response.status;
+            future::join(send_response(&mut respond, response, config), body).await
+        }
+    };
+    // A response that didn't get out is logged too, with the status we
+    // tried to send it with.
+    if let Ok(bytes) = sent {
+        entry.bytes = bytes;
+    }
+    entry.latency = started.elapsed();
+    access_log.log(&entry);
+    sent?;
+    body
+}
+
+/// HTTP/2 has no switching protocols, so a handler that answers with it,
+/// or with anything else that would take the connection over, gets a 501
+/// sent in its place.
+fn refuse_upgrade(response: Response) -> Response {
+    if response.status != 101 && response.upgrade.is_none() {
+        return response;
+    }
+    Response::text(501, format!("{}\n", reason_phrase(501)))
+}
+
+/// A request for the router, without its body.
+fn to_request(head: http::request::Parts) -> Request {
+    let mut headers = Headers::new();
+    // What HTTP/1 has a `Host` header for.
+    if let Some(authority) = head.uri.authority() {
+        headers.append("host", authority.as_str());
+    }
+    for (name, value) in &head.headers {
+        if name != http::header::HOST || !headers.contains("host") {
+            headers.append(name.as_str(), String::from_utf8_lossy(value.as_bytes()));
+        }
+    }
+    let target = head
+        .uri
+        .path_and_query()
+        .map_or("/", |target| target.as_str());
+    Request {
+        method: head.method.to_string(),
+        target: target.to_string(),
+        version: Version::Http2,
+        headers,
+        body: RequestBody::empty(),
+        params: Params::new(),
+    }
+}
+
+/// Pass the body arriving on `body` on to `sender`, as `request_body::read_body`
+/// does for HTTP/1.
+async fn read_body(
+    mut body: RecvStream,
+    config: &Config,
+    mut sender: mpsc::Sender<io::Result<Vec<u8>>>,
+) -> Result<(), ServerError> {
+    let mut received = 0;
+    let result = loop {
+        let data = match timeout(config.body_timeout, body.data()).await {
+            Ok(Some(Ok(data))) => data,
+            Ok(Some(Err(error))) => break Err(io_error(error).into()),
+            Ok(None) => return Ok(()),
+            Err(_) => break Err(ServerError::Timeout(Phase::Body)),
+        };
+        // Let the client send more.
+        let _ = body.flow_control().release_capacity(data.len());
+        received += data.len() as u64;
+        if received > config.max_body_size {
+            break Err(ParseError::BodyTooLarge.into());
+        }
+        // The handler has no use for the rest. Unlike HTTP/1, we don't
+        // have to read it to get to the next request.
+        if sender.feed(Ok(data.to_vec())).await.is_err() {
+            return Ok(());
+        }
+    };
+    if let Err(error) = &result {
+        let _ = sender.feed(Err(error.to_io_error())).await;
+    }
+    result
+}
+
+/// Send `response` on a stream, returning the length of its body.
+async fn send_response(
+    respond: &mut SendResponse<Bytes>,
+    response: Response,
+    config: &Config,
+) -> io::Result<u64> {
+    let mut head = http::Response::builder().status(response.status);
+    for (name, value) in response.fields(Version::Http2).iter() {
+        if !CONNECTION_HEADERS
+            .iter()
+            .any(|header| name.eq_ignore_ascii_case(header))
+        {
+            head = head.header(name, value);
+        }
+    }
+    let head = head
+        .body(())
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    let empty = response.body.len() == Some(0) || response.status == 204 || response.status == 304;
+    let stream = respond.send_response(head, empty).map_err(io_error)?;
+    if empty {
+        return Ok(0);
+    }
+    let mut writer = WriteTimeout::new(BodyWriter { stream }, config.write_timeout);
+    let written = response.body.write_to(&mut writer, false).await?;
+    writer.close().await?;
+    Ok(written)
+}
+
+/// Writes a response body as DATA frames, as fast as the client's flow
+/// control window allows.
+struct BodyWriter {
+    stream: SendStream<Bytes>,
+}
+
+impl Write for BodyWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        self.stream.reserve_capacity(buf.len());
+        let capacity = match self.stream.poll_capacity(cx) {
+            Poll::Ready(Some(Ok(capacity))) => capacity,
+            Poll::Ready(Some(Err(error))) => return Poll::Ready(Err(io_error(error))),
+            // The client reset the stream.
+            Poll::Ready(None) => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            Poll::Pending => return Poll::Pending,
+        };
+        let size = capacity.min(buf.len());
+        let data = Bytes::copy_from_slice(&buf[..size]);
+        self.stream.send_data(data, false).map_err(io_error)?;
+        Poll::Ready(Ok(size))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Frames go out as the connection gets polled.
+        Poll::Ready(Ok(()))
+    }
+
+    /// End the stream.
+    fn poll_close(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let ended = self.stream.send_data(Bytes::new(), true);
+        Poll::Ready(ended.map_err(io_error))
+    }
+}
+
+fn io_error(error: h2::Error) -> io::Error {
+    if error.is_io() {
+        error.into_io().unwrap()
+    } else {
+        io::Error::other(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::channel;
+    use h2::client::SendRequest;
+    use mock_stream::{duplex, MockStream};
+
+    use crate::access_log::LogFormat;
+    use crate::access_log::LogTarget;
+
+    /// An HTTP/2 client, on a connection to a server answering with
+    /// `router` and `config`.
+    async fn connect(router: Router, config: Config) -> SendRequest<Bytes> {
+        let (client, served) = connect_logging(router, config, LogTarget::Off).await;
+        task::spawn(async move { served.await.unwrap() });
+        client
+    }
+
+    /// As `connect`, with the server logging to `target`. The server's
+    /// task ends, with its log written, once the client has been dropped.
+    async fn connect_logging(
+        router: Router,
+        config: Config,
+        target: LogTarget,
+    ) -> (
+        SendRequest<Bytes>,
+        task::JoinHandle<Result<(), ServerError>>,
+    ) {
+        let (client, server) = duplex(16 * 1024);
+        let served = task::spawn(async move {
+            let access_log = AccessLog::new(target, LogFormat::Common);
+            let served =
+                serve_any_version(server, &config, &router, &Shutdown::new(), &access_log).await;
+            access_log.close().await;
+            served
+        });
+        let (client, connection) = h2::client::handshake(client.compat()).await.unwrap();
+        task::spawn(async move { connection.await.unwrap() });
+        (client, served)
+    }
+
+    /// Make a request, and wait for the status and body of the response.
+    async fn request(
+        client: &SendRequest<Bytes>,
+        method: &str,
+        path: &str,
+        body: &[u8],
+    ) -> (u16, Vec<u8>) {
+        let request = http::Request::builder()
+            .method(method)
+            .uri(format!("http://localhost{}", path))
+            .body(())
+            .unwrap();
+        let mut client = client.clone().ready().await.unwrap();
+        let (response, mut sending) = client.send_request(request, body.is_empty()).unwrap();
+        if !body.is_empty() {
+            sending
+                .send_data(Bytes::copy_from_slice(body), true)
+                .unwrap();
+        }
+        let (head, mut received) = response.await.unwrap().into_parts();
+        let mut body = Vec::new();
+        while let Some(data) = received.data().await {
+            let data = data.unwrap();
+            received
+                .flow_control()
+                .release_capacity(data.len())
+                .unwrap();
+            body.extend_from_slice(&data);
+        }
+        (head.status.as_u16(), body)
+    }
+
+    fn text(response: (u16, Vec<u8>)) -> (u16, String) {
+        (response.0, String::from_utf8(response.1).unwrap())
+    }
+
+    #[async_std::test]
+    async fn answers_requests_on_one_connection() {
+        let config = Config::default();
+        let limit = crate::limit::ConnectionLimit::new(1);
+        let client = connect(crate::routes(&config, &limit), config).await;
+        let (hello, post, missing) = future::join3(
+            request(&client, "GET", "/hello/you", b""),
+            request(&client, "POST", "/hello", b"there"),
+            request(&client, "GET", "/missing", b""),
+        )
+        .await;
+        assert_eq!(text(hello), (200, "Hello, you!\n".into()));
+        assert_eq!(text(post), (200, "Hello, there!\n".into()));
+        assert_eq!(missing.0, 404);
+    }
+
+    #[async_std::test]
+    async fn answers_requests_concurrently() {
+        // `/wait` can't finish until `/go` has been answered, so answering
+        // one request after another would never get anywhere.
+        let (go, wait) = channel::bounded(1);
+        let router = Router::new()
+            .get("/wait", move |_| {
+                let wait = wait.clone();
+                async move {
+                    wait.recv().await?;
+                    Ok(Response::text(200, "waited"))
+                }
+            })
+            .get("/go", move |_| {
+                let go = go.clone();
+                async move {
+                    go.send(()).await?;
+                    Ok(Response::text(200, "went"))
+                }
+            });
+        let client = connect(router, Config::default()).await;
+        let wait = request(&client, "GET", "/wait", b"");
+        let go = request(&client, "GET", "/go", b"");
+        let both = timeout(std::time::Duration::from_secs(5), future::join(wait, go));
+        let (wait, go) = both.await.unwrap();
+        assert_eq!(text(wait), (200, "waited".into()));
+        assert_eq!(text(go), (200, "went".into()));
+    }
+
+    #[async_std::test]
+    async fn streams_bodies_bigger_than_the_window() {
+        let config = Config::default();
+        let limit = crate::limit::ConnectionLimit::new(1);
+        let client = connect(crate::routes(&config, &limit), config).await;
+        // HTTP/2 only lets 64 KiB through before the other end asks for
+        // more.
+        let body: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let (status, echoed) = request(&client, "POST", "/echo", &body).await;
+        assert_eq!(status, 200);
+        assert!(echoed == body, "echoed {} bytes", echoed.len());
+    }
+
+    #[async_std::test]
+    async fn rejects_bodies_over_the_limit() {
+        let config = Config {
+            max_body_size: 4,
+            ..Config::default()
+        };
+        let router = Router::new().route("POST", "/", |request| async move {
+            request.body.into_bytes().await?;
+            Ok(Response::new(204))
+        });
+        let client = connect(router, config).await;
+        let (status, _) = request(&client, "POST", "/", b"too long").await;
+        assert_eq!(status, 413);
+        // Only the stream is done for, not the connection.
+        let (status, _) = request(&client, "POST", "/", b"ok").await;
+        assert_eq!(status, 204);
+    }
+
+    #[async_std::test]
+    async fn refuses_upgrades() {
+        let config = Config::default();
+        let limit = crate::limit::ConnectionLimit::new(1);
+        let router = crate::routes(&config, &limit).get("/switch", |_| async {
+            Ok(Response::new(101).with_header("Upgrade", "anything"))
+        });
+        let client = connect(router, config).await;
+        let (status, _) = request(&client, "GET", "/websocket/echo", b"").await;
+        assert_eq!(status, 426);
+        let (status, _) = request(&client, "GET", "/switch", b"").await;
+        assert_eq!(status, 501);
+    }
+
+    #[async_std::test]
+    async fn logs_streams_that_fail() {
+        let path =
+            std::env::temp_dir().join(format!("final-tcp-server-http2-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let target = LogTarget::File {
+            path: path.clone(),
+            max_size: u64::MAX,
+            keep: 0,
+        };
+        let router = Router::new()
+            .route("POST", "/stuck", |_| future::pending())
+            .get("/big", |_| async {
+                Ok(Response::text(200, "x".repeat(1 << 20)))
+            });
+        // The server only finishes once the connection has gone idle.
+        let config = Config {
+            idle_timeout: std::time::Duration::from_millis(100),
+            ..Config::default()
+        };
+        let (client, served) = connect_logging(router, config, target).await;
+
+        // The client gives up on the request halfway through its body.
+        let post = http::Request::post("http://localhost/stuck")
+            .body(())
+            .unwrap();
+        let mut ready = client.clone().ready().await.unwrap();
+        let (_, mut sending) = ready.send_request(post, false).unwrap();
+        sending.send_data(Bytes::from_static(b"x"), false).unwrap();
+        sending.send_reset(h2::Reason::CANCEL);
+
+        // And on a response halfway through its body.
+        let get = http::Request::get("http://localhost/big").body(()).unwrap();
+        let mut ready = client.clone().ready().await.unwrap();
+        let (response, _) = ready.send_request(get, true).unwrap();
+        drop(response.await.unwrap());
+
+        // The client hanging up may well cut the server off mid-write.
+        drop((client, ready));
+        let _ = served.await;
+        let logged = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(
+            logged.contains(r#"] "POST /stuck HTTP/2" 0 -"#),
+            "{}",
+            logged
+        );
+        assert!(logged.contains(r#"] "GET /big HTTP/2" 200 "#), "{}", logged);
+    }
+
+    #[async_std::test]
+    async fn tells_http2_from_http1() {
+        let input = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\nframes";
+        let (http2, mut rewound) = sniff(MockStream::new().send_slowly(input)).await.unwrap();
+        assert!(http2);
+        let mut read = Vec::new();
+        rewound.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, input);
+
+        for input in [
+            &b"GET / HTTP/1.1\r\n\r\n"[..],
+            b"PRI",
+            b"PRI * HTTP/1.1\r\n",
+        ] {
+            let (http2, mut rewound) = sniff(MockStream::new().send(input)).await.unwrap();
+            assert!(!http2);
+            let mut read = Vec::new();
+            rewound.read_to_end(&mut read).await.unwrap();
+            assert_eq!(read, input);
+        }
+    }
+
+    #[async_std::test]
+    async fn still_speaks_http1() {
+        let mut stream =
+            MockStream::new().send(b"GET /hello/you HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let server = crate::server();
+        let access_log = AccessLog::new(LogTarget::Off, LogFormat::Common);
+        serve_any_version(
+            &mut stream,
+            &server.config,
+            &server.router,
+            &Shutdown::new(),
+            &access_log,
+        )
+        .await
+        .unwrap();
+        let written = String::from_utf8(stream.take_written()).unwrap();
+        assert!(written.starts_with("HTTP/1.1 200 OK\r\n"), "{}", written);
+        assert!(written.ends_with("\r\n\r\nHello, you!\n"));
+    }
+}
@@ -0,0 +1,226 @@
+//! Bounding the number of connections served at once.
+//!
+//! Every connection is spawned onto its own task, and nothing else stops
+//! a flood of clients from using up all our file descriptors and memory.
+//! So a connection is only served while it holds a `Permit` from the
+//! server's `ConnectionLimit`, which hands out at most `max` of them at a
+//! time, a semaphore in all but name. Once they're all out, a new client
+//! either waits for one to be given back, or is turned away with a 503,
+//! depending on `Config::when_full`.
+//!
+//! Only this server has a limit. The concurrent server in 09_04 is the
+//! book's first look at `for_each_concurrent`, and keeps the `None` that
+//! lets it take on any number of connections.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// What to do with a client that arrives when every permit is taken.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WhenFull {
+    /// Stop accepting connections until one closes. Clients wait in the
+    /// listener's backlog.
+    Queue,
+    /// Answer with `503 Service Unavailable` and close the connection.
+    Reject,
+}
+
+#[derive(Clone)]
+pub struct ConnectionLimit {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    max: usize,
+    current: usize,
+    peak: usize,
+    rejected: u64,
+    /// Tasks waiting in `acquire` or `drained` for a permit to be given
+    /// back.
+    waiting: Vec<Waker>,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> Self {
+        ConnectionLimit {
+            state: Arc::new(Mutex::new(State {
+                max,
+                current: 0,
+                peak: 0,
+                rejected: 0,
+                waiting: Vec::new(),
+            })),
+        }
+    }
+
+    /// Wait for a permit.
+    pub fn acquire(&self) -> Acquire {
+        Acquire {
+            limit: self.clone(),
+        }
+    }
+
+    /// Take a permit if there's one left, or count a rejected client.
+    pub fn try_acquire(&self) -> Option<Permit> {
+        let permit = self.take();
+        if permit.is_none() {
+            self.state.lock().unwrap().rejected += 1;
+        }
+        permit
+    }
+
+    fn take(&self) -> Option<Permit> {
+        let mut state = self.state.lock().unwrap();
+        if state.current == state.max {
+            return None;
+        }
+        state.current += 1;
+        state.peak = state.peak.max(state.current);
+        Some(Permit {
+            state: self.state.clone(),
+        })
+    }
+
+    /// Wait until no connections are being served.
+    pub fn drained(&self) -> Drained {
+        Drained {
+            limit: self.clone(),
+        }
+    }
+
+    /// How many connections are being served right now.
+    pub fn current(&self) -> usize {
+        self.state.lock().unwrap().current
+    }
+
+    /// The most connections that have been served at once.
+    pub fn peak(&self) -> usize {
+        self.state.lock().unwrap().peak
+    }
+
+    /// How many clients `try_acquire` has turned away.
+    pub fn rejected(&self) -> u64 {
+        self.state.lock().unwrap().rejected
+    }
+}
+
+/// Future returned by `ConnectionLimit::acquire`.
+pub struct Acquire {
+    limit: ConnectionLimit,
+}
+
+impl Future for Acquire {
+    type Output = Permit;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Permit> {
+        if let Some(permit) = self.limit.take() {
+            return Poll::Ready(permit);
+        }
+        let mut state = self.limit.state.lock().unwrap();
+        // A permit may have been given back since `take` let go of the lock.
+        if state.current < state.max {
+            cx.waker().wake_by_ref();
+        } else {
+            state.waiting.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+/// Future returned by `ConnectionLimit::drained`.
+pub struct Drained {
+    limit: ConnectionLimit,
+}
+
+impl Future for Drained {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.limit.state.lock().unwrap();
+        if state.current == 0 {
+            return Poll::Ready(());
+        }
+        state.waiting.push(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// The right to serve one connection, given back when dropped.
+pub struct Permit {
+    state: Arc<Mutex<State>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let waiting = {
+            let mut state = self.state.lock().unwrap();
+            state.current -= 1;
+            std::mem::take(&mut state.waiting)
+        };
+        // Everyone waiting gets to try again; those who lose out to another
+        // task go back to waiting.
+        for waker in waiting {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::future::timeout;
+    use async_std::task;
+    use std::time::Duration;
+
+    #[test]
+    fn hands_out_at_most_max_permits() {
+        let limit = ConnectionLimit::new(2);
+        let first = limit.try_acquire().unwrap();
+        let second = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+        assert_eq!((limit.current(), limit.peak(), limit.rejected()), (2, 2, 1));
+
+        drop(first);
+        assert_eq!((limit.current(), limit.peak()), (1, 2));
+        let _third = limit.try_acquire().unwrap();
+        drop(second);
+        assert_eq!((limit.current(), limit.peak(), limit.rejected()), (1, 2, 1));
+    }
+
+    #[async_std::test]
+    async fn queues_until_a_permit_is_given_back() {
+        let limit = ConnectionLimit::new(1);
+        let permit = limit.acquire().await;
+        let waiting = task::spawn({
+            let limit = limit.clone();
+            async move { limit.acquire().await }
+        });
+        task::sleep(Duration::from_millis(50)).await;
+        assert_eq!(limit.current(), 1);
+
+        drop(permit);
+        let permit = timeout(Duration::from_secs(1), waiting).await.unwrap();
+        assert_eq!((limit.current(), limit.peak()), (1, 1));
+        drop(permit);
+        assert_eq!(limit.current(), 0);
+    }
+
+    #[async_std::test]
+    async fn drains_once_every_permit_is_given_back() {
+        let limit = ConnectionLimit::new(2);
+        let first = limit.try_acquire().unwrap();
+        let second = limit.try_acquire().unwrap();
+        let drained = task::spawn({
+            let limit = limit.clone();
+            async move { limit.drained().await }
+        });
+        drop(first);
+        task::sleep(Duration::from_millis(50)).await;
+        assert_eq!(limit.current(), 1);
+
+        drop(second);
+        timeout(Duration::from_secs(1), drained).await.unwrap();
+    }
+}
@@ -1,41 +1,544 @@
-use std::fs;
-
 use futures::stream::StreamExt;
 
-use async_std::net::TcpListener;
-use async_std::prelude::*;
+use async_std::net::{TcpListener, TcpStream};
 // ANCHOR: main_func
 use async_std::task::spawn;
 
 #[async_std::main]
-async fn main() {
-    let listener = TcpListener::bind("127.0.0.1:7878").await.unwrap();
-    listener
-        .incoming()
-        .for_each_concurrent(/* limit */ None, |stream| async move {
-            let stream = stream.unwrap();
-            spawn(handle_connection(stream));
-        })
-        .await;
+async fn main() -> std::io::Result<()> {
+    let config = Config::from_args(std::env::args().skip(1))
+        .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidInput, error))?;
+    let server = Arc::new(Server::new(config)?);
+    server.shutdown.on_signals()?;
+    let listener = TcpListener::bind(&server.config.address).await?;
+    println!("listening on {}", listener.local_addr()?);
+    let mut incoming = listener.incoming().take_until(server.shutdown.wait());
+    while let Some(stream) = incoming.next().await {
+        let stream = match stream {
+            Ok(stream) => stream,
+            // For example, we've run out of file descriptors. That's
+            // no reason to stop serving the connections we have.
+            Err(error) => {
+                eprintln!("failed to accept a connection: {}", error);
+                continue;
+            }
+        };
+        // Spawned tasks aren't bounded by anything, so each connection
+        // holds on to a permit until it's done.
+        let permit = match server.admit(&stream).await {
+            Some(permit) => permit,
+            // Turned away, or we're shutting down.
+            None => continue,
+        };
+        let server = server.clone();
+        spawn(async move {
+            let connection = accept_connection(&server, stream);
+            pin_mut!(connection);
+            // Cut short if the shutdown timeout runs out first.
+            future::select(connection, server.closing.wait()).await;
+            drop(permit);
+        });
+    }
+    // Stop accepting connections, and let the open ones finish.
+    drop(incoming);
+    drop(listener);
+    server.drain().await;
+    server.access_log.close().await;
+    Ok(())
 }
 // ANCHOR_END: main_func
 
+use async_std::future::timeout;
 use async_std::io::{Read, Write};
+use async_std::prelude::*;
+use futures::future::{self, Either};
+use futures::io::AsyncReadExt;
+use futures::pin_mut;
+use futures::sink::SinkExt;
+use futures::stream::TryStreamExt;
 use std::marker::Unpin;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+
+mod access_log;
+mod body;
+mod config;
+mod error;
+mod headers;
+#[cfg(feature = "http2")]
+mod http2;
+mod limit;
+mod middleware;
+mod request;
+mod request_body;
+mod response;
+mod router;
+mod shutdown;
+mod sse;
+mod static_files;
+#[cfg(feature = "tls")]
+mod tls;
+mod upgrade;
+mod websocket;
+mod write_timeout;
+
+use access_log::{AccessLog, Entry, Peer};
+use body::Body;
+use config::Config;
+use error::{Phase, ServerError};
+use limit::{ConnectionLimit, Permit, WhenFull};
+use middleware::{BearerAuth, CatchPanic, Compress, Timing};
+use request::{fill, read_request, ParseError, Request, RequestParser, Version};
+use request_body::{read_body, RequestBody};
+use response::{reason_phrase, Response};
+use router::Router;
+use shutdown::Shutdown;
+use sse::{Event, Sse};
+use static_files::StaticFiles;
+#[cfg(feature = "tls")]
+use tls::Tls;
+use upgrade::{OnUpgrade, Upgraded};
+use websocket::{Message, WebSocket};
+use write_timeout::WriteTimeout;
+
+/// What every connection is served with.
+struct Server {
+    config: Config,
+    limit: ConnectionLimit,
+    router: Router,
+    access_log: AccessLog,
+    /// What we serve HTTPS with, if we do.
+    #[cfg(feature = "tls")]
+    tls: Option<Tls>,
+    /// Triggered when we're asked to stop.
+    shutdown: Shutdown,
+    /// Triggered when the connections still open are to be closed.
+    closing: Shutdown,
+}
+
+impl Server {
+    fn new(config: Config) -> std::io::Result<Self> {
+        #[cfg(feature = "tls")]
+        let tls = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => Some(Tls::from_pem_files(cert, key)?),
+            _ => None,
+        };
+        #[cfg(not(feature = "tls"))]
+        if config.tls_cert.is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "serving HTTPS needs the `tls` feature",
+            ));
+        }
+        let limit = ConnectionLimit::new(config.max_connections);
+        let router = routes(&config, &limit);
+        let access_log = AccessLog::new(config.access_log.clone(), config.log_format);
+        Ok(Server {
+            config,
+            limit,
+            router,
+            access_log,
+            #[cfg(feature = "tls")]
+            tls,
+            shutdown: Shutdown::new(),
+            closing: Shutdown::new(),
+        })
+    }
+
+    /// Get a permit to serve `stream`, according to `config.when_full`.
+    async fn admit(&self, stream: &TcpStream) -> Option<Permit> {
+        match self.config.when_full {
+            WhenFull::Queue => {
+                match future::select(self.limit.acquire(), self.shutdown.wait()).await {
+                    Either::Left((permit, _)) => Some(permit),
+                    Either::Right(_) => None,
+                }
+            }
+            WhenFull::Reject => {
+                let permit = self.limit.try_acquire();
+                if permit.is_none() {
+                    spawn(reject(stream.clone(), self.config.write_timeout));
+                }
+                permit
+            }
+        }
+    }
+
+    /// Give the open connections `config.shutdown_timeout` to finish, then
+    /// close the rest.
+    async fn drain(&self) {
+        let drained = timeout(self.config.shutdown_timeout, self.limit.drained()).await;
+        if drained.is_err() {
+            eprintln!("closing {} connections", self.limit.current());
+            self.closing.trigger();
+            self.limit.drained().await;
+        }
+    }
+}
+
+/// A server with the default config, for tests, which have no use for an
+/// access log.
+#[cfg(test)]
+fn server() -> Server {
+    Server::new(Config {
+        access_log: access_log::LogTarget::Off,
+        ..Config::default()
+    })
+    .expect("the default config needs no files")
+}
+
+/// Serve a connection we've just accepted, over TLS if we've been set up
+/// for it.
+async fn accept_connection(server: &Server, stream: TcpStream) {
+    #[cfg(feature = "tls")]
+    if let Some(tls) = &server.tls {
+        // Nothing is read until the handshake is done, so it gets no
+        // longer than a request's head would.
+        match timeout(server.config.header_timeout, tls.accept(stream)).await {
+            Ok(Ok(stream)) => handle_connection(server, stream).await,
+            Ok(Err(error)) => eprintln!("TLS handshake failed: {}", error),
+            Err(_) => eprintln!("TLS handshake timed out"),
+        }
+        return;
+    }
+    handle_connection(server, stream).await
+}
+
+async fn handle_connection(server: &Server, mut stream: impl Read + Write + Peer + Unpin) {
+    #[cfg(feature = "http2")]
+    let serve = http2::serve_any_version;
+    #[cfg(not(feature = "http2"))]
+    let serve = serve_connection;
+    let served = serve(
+        &mut stream,
+        &server.config,
+        &server.router,
+        &server.shutdown,
+        &server.access_log,
+    )
+    .await;
+    if let Err(error) = served {
+        eprintln!("{}", error);
+    }
+    // Over TLS, this is how the client knows it has had all we sent,
+    // rather than the connection being cut short. A client that has
+    // stopped reading doesn't get to hold us up, though.
+    let close = futures::io::AsyncWriteExt::close(&mut stream);
+    let _ = timeout(server.config.write_timeout, close).await;
+}
+
+/// Turn away a client we have no room for. These tasks aren't counted
+/// against the limit, so one that doesn't read the answer gets no more
+/// than `write_timeout` to hold on to it.
+async fn reject(mut stream: impl Write + Unpin, write_timeout: std::time::Duration) {
+    let response = Response::text(503, "Service Unavailable\n")
+        .with_header("Retry-After", "1")
+        .with_header("Connection", "close");
+    // The client may well have given up already.
+    let _ = timeout(write_timeout, response.write_to(&mut stream)).await;
+}
+
+fn routes(config: &Config, limit: &ConnectionLimit) -> Router {
+    let files = StaticFiles::new(&config.static_root);
+    let home = files.clone();
+    let sleep = files.clone();
+    let not_found = files.clone();
+    let limit = limit.clone();
+    let keep_alive = config.idle_timeout;
+    let router = Router::new()
+        .get("/", move |request| {
+            let files = home.clone();
+            async move { Ok(files.serve(&request, "hello.html").await?) }
+        })
+        .get("/hello/:name", |request| async move {
+            let name = request.param("name").unwrap_or_default();
+            Ok(Response::text(200, format!("Hello, {}!\n", name)))
+        })
+        .route("POST", "/hello", |request| async move {
+            let name = request.body.into_bytes().await?;
+            let name = String::from_utf8_lossy(&name);
+            Ok(Response::text(200, format!("Hello, {}!\n", name)))
+        })
+        .route("POST", "/echo", |request| async move {
+            let content_type = request
+                .headers
+                .get("content-type")
+                .unwrap_or("application/octet-stream")
+                .to_string();
+            // Sent back as it arrives.
+            let body = Body::from_reader(request.body.into_async_read(), None);
+            Ok(Response::new(200).with_body(&content_type, body))
+        })
+        .get("/sleep", move |request| {
+            let files = sleep.clone();
+            async move {
+                async_std::task::sleep(std::time::Duration::from_secs(5)).await;
+                Ok(files.serve(&request, "hello.html").await?)
+            }
+        })
+        .get("/websocket/echo", |request| async move {
+            Ok(websocket::accept(&request, echo))
+        })
+        .get("/countdown", move |_| async move {
+            // Events come from a channel, fed by a task that finds out the
+            // client has gone when sending fails.
+            let (mut sender, events) = futures::channel::mpsc::channel(0);
+            spawn(async move {
+                for n in (0..10).rev() {
+                    let event = Event::new(n.to_string())
+                        .with_event("tick")
+                        .with_id(n.to_string());
+                    if sender.send(event).await.is_err() {
+                        return;
+                    }
+                    async_std::task::sleep(std::time::Duration::from_secs(1)).await;
+                }
+                let _ = sender.send(Event::new("liftoff").with_event("done")).await;
+            });
+            // A client that hears nothing for as long as we'd wait on it
+            // has every reason to think we've gone.
+            Ok(Sse::new(events).with_keep_alive(keep_alive).into_response())
+        })
+        .get("/stats", move |_| {
+            let text = format!(
+                "connections: {}\npeak: {}\nrejected: {}\n",
+                limit.current(),
+                limit.peak(),
+                limit.rejected()
+            );
+            async move { Ok(Response::text(200, text)) }
+        })
+        .get("/static/*path", move |request| {
+            let files = files.clone();
+            async move {
+                let path = request.param("path").unwrap_or_default();
+                Ok(files.serve(&request, path).await?)
+            }
+        })
+        .not_found(move |_| {
+            let files = not_found.clone();
+            async move { Ok(files.not_found().await) }
+        })
+        .wrap(Timing)
+        .wrap(CatchPanic)
+        .wrap(Compress::default());
+    match &config.admin_token {
+        Some(token) => router.wrap(BearerAuth::new("/stats", token)),
+        None => router,
+    }
+}
+
+/// Send every message on `socket` back where it came from.
+async fn echo(mut socket: WebSocket<Upgraded>) {
+    while let Some(Ok(message)) = socket.next().await {
+        // Pings are answered for us, and a close is the last message.
+        if let Message::Text(_) | Message::Binary(_) = message {
+            if socket.send(message).await.is_err() {
+                break;
+            }
+        }
+    }
+    let _ = socket.close().await;
+}
 
-async fn handle_connection(mut stream: impl Read + Write + Unpin) {
-    let mut buffer = [0; 1024];
-    stream.read(&mut buffer).await.unwrap();
-    let get = b"GET / HTTP/1.1\r\n";
-    let (status_line, filename) = if buffer.starts_with(get) {
-        ("HTTP/1.1 200 OK\r\n\r\n", "hello.html")
-    } else {
-        ("HTTP/1.1 404 NOT FOUND\r\n\r\n", "404.html")
+/// Answer requests on `stream` until the client closes it, asks us to, or
+/// stays quiet for longer than `config.idle_timeout`.
+///
+/// Pipelined requests need no special treatment: whatever the client sent
+/// after a request stays in `parser` until we get around to it, and we
+/// answer each request before reading the next.
+///
+/// A handler failing, or taking longer than `config.handler_timeout`,
+/// doesn't end the connection: the client gets a 500 or 503 and we carry
+/// on. A bad request, or the connection failing, does. So does
+/// `shutdown`, once the request in hand has been answered.
+///
+/// Every response to a request goes in `access_log`.
+async fn serve_connection(
+    stream: impl Read + Write + Peer + Unpin,
+    config: &Config,
+    router: &Router,
+    shutdown: &Shutdown,
+    access_log: &AccessLog,
+) -> Result<(), ServerError> {
+    let remote = stream.peer_addr();
+    // A handler may answer before it has read the whole request body, or
+    // stream it back, so reading and writing have to be able to go on at
+    // the same time.
+    let (mut reader, writer) = stream.split();
+    let mut writer = WriteTimeout::new(writer, config.write_timeout);
+    let mut parser = RequestParser::new();
+    loop {
+        // A request may be a while coming, and if we're shutting down,
+        // there's no need to wait for it.
+        if parser.is_empty() {
+            let arrived = {
+                let read = timeout(config.idle_timeout, fill(&mut reader, &mut parser));
+                pin_mut!(read);
+                match future::select(read, shutdown.wait()).await {
+                    Either::Left((Ok(arrived), _)) => arrived?,
+                    // The client went quiet, or we're shutting down.
+                    Either::Left((Err(_), _)) | Either::Right(_) => return Ok(()),
+                }
+            };
+            // The client hung up in between requests.
+            if arrived == 0 {
+                return Ok(());
+            }
+        }
+        // Once it has started, though, the rest had better be quick.
+        let mut request = match timeout(
+            config.header_timeout,
+            read_request(&mut reader, &mut parser),
+        )
+        .await
+        {
+            Ok(Ok(Some(request))) => request,
+            Ok(Ok(None)) => return Ok(()),
+            Ok(Err(error)) => return Err(write_error(&mut writer, error).await),
+            Err(_) => return Err(write_error(&mut writer, ServerError::Timeout(Phase::Head)).await),
+        };
+        if parser
+            .body_length()
+            .is_some_and(|length| length > config.max_body_size)
+        {
+            return Err(write_error(&mut writer, ParseError::BodyTooLarge.into()).await);
+        }
+        let started = Instant::now();
+        let mut entry = Entry {
+            remote,
+            time: SystemTime::now(),
+            method: request.method.clone(),
+            target: request.target.clone(),
+            version: request.version.to_string(),
+            status: 0,
+            bytes: 0,
+            latency: Default::default(),
+            referer: request.headers.get("referer").map(str::to_string),
+            user_agent: request.headers.get("user-agent").map(str::to_string),
+        };
+        let version = request.version;
+        let mut keep_alive = request.keep_alive();
+        let mut upgrade = None;
+        if version == Version::Http11
+            && parser.body_length() != Some(0)
+            && request.headers.has_token("expect", "100-continue")
+        {
+            // The client is waiting to hear that we want the body.
+            writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+        }
+
+        // Done with the reader by the time it's needed for an upgrade.
+        let body = {
+            let (sender, body) = RequestBody::channel();
+            request.body = body;
+            let handler = respond(router, config, request);
+            let body = read_body(&mut reader, &mut parser, config, sender);
+            pin_mut!(handler, body);
+            // The body goes first, so that if it turns out bad we get to
+            // answer that rather than whatever the handler makes of it.
+            match future::select(body, handler).await {
+                Either::Left((Ok(()), handler)) => {
+                    let response = finish(
+                        handler.await,
+                        version,
+                        &mut keep_alive,
+                        &mut upgrade,
+                        shutdown,
+                    );
+                    entry.status = response.status;
+                    entry.bytes = response.write_for(version, &mut writer).await?;
+                    Ok(())
+                }
+                Either::Left((Err(error), _)) => return Err(write_error(&mut writer, error).await),
+                Either::Right((response, body)) => {
+                    let response =
+                        finish(response, version, &mut keep_alive, &mut upgrade, shutdown);
+                    entry.status = response.status;
+                    let (written, body) =
+                        future::join(response.write_for(version, &mut writer), body).await;
+                    entry.bytes = written?;
+                    body
+                }
+            }
+        };
+        entry.latency = started.elapsed();
+        access_log.log(&entry);
+        // The response is out, so all we can do about a bad body is hang up.
+        body?;
+        if let Some(on_upgrade) = upgrade {
+            // The connection isn't ours any more.
+            return upgrade::serve(reader, parser.take_buffered(), writer, on_upgrade).await;
+        }
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// Have `router` answer `request`. If the handler fails, or takes longer
+/// than `config.handler_timeout`, the client gets a 500 or 503 instead.
+async fn respond(router: &Router, config: &Config, request: Request) -> Response {
+    let (method, target) = (request.method.clone(), request.target.clone());
+    let error = match timeout(config.handler_timeout, router.handle(request)).await {
+        Ok(Ok(response)) => return response,
+        Ok(Err(error)) => ServerError::Handler(error),
+        Err(_) => ServerError::Timeout(Phase::Handler),
+    };
+    eprintln!("{} {}: {}", method, target, error);
+    let status = error.status_code().unwrap_or(500);
+    Response::text(status, format!("{}\n", reason_phrase(status)))
+}
+
+/// Add the `Connection` header to a handler's response, unless it's
+/// switching protocols, in which case `upgrade` gets what takes the
+/// connection over.
+fn finish(
+    mut response: Response,
+    version: Version,
+    keep_alive: &mut bool,
+    upgrade: &mut Option<OnUpgrade>,
+    shutdown: &Shutdown,
+) -> Response {
+    // HTTP/1.0 has no switching protocols.
+    *upgrade = response
+        .upgrade
+        .take()
+        .filter(|_| response.status == 101 && version == Version::Http11);
+    if upgrade.is_some() {
+        return response;
+    }
+    // Without chunked encoding, the only way to end a body of unknown
+    // length is to close the connection.
+    if version == Version::Http10 && response.body.len().is_none() {
+        *keep_alive = false;
+    }
+    if shutdown.is_triggered() {
+        *keep_alive = false;
+    }
+    let connection = if *keep_alive { "keep-alive" } else { "close" };
+    response.with_header("Connection", connection)
+}
+
+/// Tell the client about a request we couldn't make sense of, or that
+/// took too long to arrive, before closing the connection. Returns the
+/// error to end the connection with.
+async fn write_error(writer: &mut (impl Write + Unpin), error: ServerError) -> ServerError {
+    let status = match error.status_code() {
+        Some(status) => status,
+        // There's no telling the client about the connection failing.
+        None => return error,
     };
-    let contents = fs::read_to_string(filename).unwrap();
-    let response = format!("{}{}", status_line, contents);
-    stream.write(response.as_bytes()).await.unwrap();
-    stream.flush().await.unwrap();
+    let message = match &error {
+        ServerError::Request(request_error) => request_error.to_string(),
+        _ => error.to_string(),
+    };
+    let response =
+        Response::text(status, format!("{}\n", message)).with_header("Connection", "close");
+    match response.write_to(writer).await {
+        Ok(()) => error,
+        Err(write_error) => write_error.into(),
+    }
 }
 
 #[cfg(test)]
@@ -43,8 +546,11 @@ async fn handle_connection(mut stream: impl Read + Write + Unpin) {
 mod tests {
     // ANCHOR: mock_read
     use super::*;
+    use access_log::LogTarget;
     use futures::io::Error;
     use futures::task::{Context, Poll};
+    use mock_stream::{duplex, DuplexStream, MockStream};
+    use std::io::ErrorKind::ConnectionReset;
 
     use std::cmp::min;
     use std::pin::Pin;
@@ -56,12 +562,13 @@ mod tests {
 
     impl Read for MockTcpStream {
         fn poll_read(
-            self: Pin<&mut Self>,
+            mut self: Pin<&mut Self>,
             _: &mut Context,
             buf: &mut [u8],
         ) -> Poll<Result<usize, Error>> {
             let size: usize = min(self.read_data.len(), buf.len());
             buf[..size].copy_from_slice(&self.read_data[..size]);
+            self.read_data.drain(..size);
             Poll::Ready(Ok(size))
         }
     }
@@ -74,7 +581,7 @@ mod tests {
             _: &mut Context,
             buf: &[u8],
         ) -> Poll<Result<usize, Error>> {
-            self.write_data = Vec::from(buf);
+            self.write_data.extend_from_slice(buf);
             return Poll::Ready(Ok(buf.len()));
         }
         fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), Error>> {
@@ -91,26 +598,655 @@ mod tests {
     impl Unpin for MockTcpStream {}
     // ANCHOR_END: unpin
 
+    impl Peer for MockTcpStream {}
+
+    impl Peer for MockStream {}
+
+    impl Peer for DuplexStream {}
+
+    /// Sends `request`, then nothing more, without ever hanging up.
+    fn quiet(request: &[u8]) -> MockStream {
+        MockStream::new().send(request).hang()
+    }
+
+    /// Sends `request`, then fails with a reset connection, as do writes
+    /// once `write_limit` bytes have been written.
+    fn faulty(request: &[u8], write_limit: usize) -> MockStream {
+        MockStream::new()
+            .send(request)
+            .read_error(ConnectionReset)
+            .write_error_after(write_limit, ConnectionReset)
+    }
+
     // ANCHOR: test
     use std::fs;
 
     #[async_std::test]
     async fn test_handle_connection() {
-        let input_bytes = b"GET / HTTP/1.1\r\n";
-        let mut contents = vec![0u8; 1024];
-        contents[..input_bytes.len()].clone_from_slice(input_bytes);
+        let input_bytes = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
         let mut stream = MockTcpStream {
-            read_data: contents,
+            read_data: input_bytes.to_vec(),
             write_data: Vec::new(),
         };
 
-        handle_connection(&mut stream).await;
-        let mut buf = [0u8; 1024];
-        stream.read(&mut buf).await.unwrap();
+        handle_connection(&server(), &mut stream).await;
 
-        let expected_contents = fs::read_to_string("hello.html").unwrap();
-        let expected_response = format!("HTTP/1.1 200 OK\r\n\r\n{}", expected_contents);
-        assert!(stream.write_data.starts_with(expected_response.as_bytes()));
+        let expected_contents = fs::read_to_string("static/hello.html").unwrap();
+        let response = String::from_utf8(stream.write_data).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        let content_length = format!("Content-Length: {}", expected_contents.len());
+        assert!(head.lines().any(|line| line == content_length));
+        assert_eq!(body, expected_contents);
     }
     // ANCHOR_END: test
+
+    fn no_log() -> AccessLog {
+        AccessLog::new(LogTarget::Off, access_log::LogFormat::Common)
+    }
+
+    /// Split what the server wrote into `(head, body)` pairs, using each
+    /// response's `Content-Length`.
+    fn responses(mut written: &str) -> Vec<(&str, &str)> {
+        let mut responses = Vec::new();
+        while !written.is_empty() {
+            let (head, rest) = written.split_once("\r\n\r\n").unwrap();
+            let length: usize = head
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            responses.push((head, &rest[..length]));
+            written = &rest[length..];
+        }
+        responses
+    }
+
+    async fn exchange(input: &[u8]) -> String {
+        let mut stream = MockTcpStream {
+            read_data: input.to_vec(),
+            write_data: Vec::new(),
+        };
+        handle_connection(&server(), &mut stream).await;
+        String::from_utf8(stream.write_data).unwrap()
+    }
+
+    #[async_std::test]
+    async fn answers_pipelined_requests_in_order() {
+        let written = exchange(
+            b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n\
+              GET /missing HTTP/1.1\r\nHost: localhost\r\n\r\n\
+              GET / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        )
+        .await;
+        let responses = responses(&written);
+        let statuses: Vec<_> = responses
+            .iter()
+            .map(|(head, _)| head.lines().next().unwrap())
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                "HTTP/1.1 200 OK",
+                "HTTP/1.1 404 Not Found",
+                "HTTP/1.1 200 OK"
+            ]
+        );
+        for (head, _) in responses {
+            assert!(head.lines().any(|line| line == "Connection: keep-alive"));
+        }
+    }
+
+    #[async_std::test]
+    async fn stops_after_connection_close() {
+        let written = exchange(
+            b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n\
+              GET / HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        let responses = responses(&written);
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0]
+            .0
+            .lines()
+            .any(|line| line == "Connection: close"));
+    }
+
+    #[async_std::test]
+    async fn http_1_0_closes_unless_asked_not_to() {
+        let written = exchange(b"GET / HTTP/1.0\r\n\r\nGET / HTTP/1.0\r\n\r\n").await;
+        assert_eq!(responses(&written).len(), 1);
+
+        let written = exchange(
+            b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n\
+              GET / HTTP/1.0\r\n\r\n",
+        )
+        .await;
+        assert_eq!(responses(&written).len(), 2);
+    }
+
+    #[async_std::test]
+    async fn closes_idle_connections() {
+        let mut stream = quiet(b"GET / HTTP/1.1\r\n\r\n");
+        let config = Config {
+            idle_timeout: std::time::Duration::from_millis(50),
+            ..Config::default()
+        };
+        // Returns instead of waiting for a second request forever.
+        let router = routes(&config, &ConnectionLimit::new(1));
+        serve_connection(&mut stream, &config, &router, &Shutdown::new(), &no_log())
+            .await
+            .unwrap();
+        let written = String::from_utf8(stream.take_written()).unwrap();
+        assert_eq!(responses(&written).len(), 1);
+    }
+
+    #[async_std::test]
+    async fn guards_stats_with_the_admin_token() {
+        let config = Config {
+            admin_token: Some("s3cret".to_string()),
+            ..Config::default()
+        };
+        let router = routes(&config, &ConnectionLimit::new(1));
+        for target in ["/stats", "//stats", "/stats/", "/stats?x=1"] {
+            let mut stream = quiet(format!("GET {} HTTP/1.1\r\n\r\n", target).as_bytes());
+            serve_impatiently(&mut stream, &router).await.unwrap();
+            let written = String::from_utf8(stream.take_written()).unwrap();
+            assert!(
+                written.starts_with("HTTP/1.1 401 Unauthorized\r\n"),
+                "{}: {}",
+                target,
+                written
+            );
+        }
+        let mut stream = quiet(b"GET //stats HTTP/1.1\r\nAuthorization: Bearer s3cret\r\n\r\n");
+        serve_impatiently(&mut stream, &router).await.unwrap();
+        let written = String::from_utf8(stream.take_written()).unwrap();
+        assert!(written.starts_with("HTTP/1.1 200 OK\r\n"), "{}", written);
+    }
+
+    #[async_std::test]
+    async fn routes_requests_with_path_params() {
+        let router = Router::new()
+            .get("/users/:id", |request| async move {
+                Ok(Response::text(
+                    200,
+                    format!("user {}", request.param("id").unwrap()),
+                ))
+            })
+            .route("DELETE", "/users/:id", |_| async { Ok(Response::new(204)) });
+        let mut stream = MockTcpStream {
+            read_data: b"GET /users/7 HTTP/1.1\r\n\r\n\
+                         PUT /users/7 HTTP/1.1\r\n\r\n\
+                         GET /teams/7 HTTP/1.1\r\n\r\n"
+                .to_vec(),
+            write_data: Vec::new(),
+        };
+        serve_connection(
+            &mut stream,
+            &Config::default(),
+            &router,
+            &Shutdown::new(),
+            &no_log(),
+        )
+        .await
+        .unwrap();
+
+        let written = String::from_utf8(stream.write_data).unwrap();
+        let responses = responses(&written);
+        assert_eq!(responses.len(), 3);
+        assert!(responses[0].0.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(responses[0].1, "user 7");
+        assert!(responses[1]
+            .0
+            .starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(responses[1]
+            .0
+            .lines()
+            .any(|line| line == "Allow: GET, DELETE"));
+        assert!(responses[2].0.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[async_std::test]
+    async fn ends_streamed_bodies_by_closing_for_http_1_0() {
+        let router = Router::new().get("/", |_| async {
+            let body = Body::from_reader(&b"streamed"[..], None);
+            Ok(Response::new(200).with_body("text/plain", body))
+        });
+
+        let written = exchange_with(&router, b"GET / HTTP/1.1\r\n\r\n").await;
+        assert!(written.contains("\r\nTransfer-Encoding: chunked\r\n"));
+        assert!(written.contains("\r\nConnection: keep-alive\r\n"));
+        assert!(written.ends_with("\r\n\r\n8\r\nstreamed\r\n0\r\n\r\n"));
+
+        let written = exchange_with(
+            &router,
+            b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET / HTTP/1.0\r\n\r\n",
+        )
+        .await;
+        assert!(!written.contains("Transfer-Encoding"));
+        assert!(written.contains("\r\nConnection: close\r\n"));
+        assert!(written.ends_with("\r\n\r\nstreamed"));
+    }
+
+    async fn exchange_with(router: &Router, input: &[u8]) -> String {
+        let mut stream = MockTcpStream {
+            read_data: input.to_vec(),
+            write_data: Vec::new(),
+        };
+        serve_connection(
+            &mut stream,
+            &Config::default(),
+            router,
+            &Shutdown::new(),
+            &no_log(),
+        )
+        .await
+        .unwrap();
+        String::from_utf8(stream.write_data).unwrap()
+    }
+
+    #[async_std::test]
+    async fn reads_bodies_by_length_or_chunked() {
+        let router = &server().router;
+        let written = exchange_with(
+            router,
+            b"POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+              POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+              3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n\
+              POST /hello HTTP/1.1\r\nContent-Length: 5\r\nConnection: close\r\n\r\nthere",
+        )
+        .await;
+        let responses: Vec<_> = written.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(responses.len(), 3, "{}", written);
+        assert!(responses[0].ends_with("\r\n\r\n5\r\nhello\r\n0\r\n\r\n"));
+        assert!(responses[1].ends_with("\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n"));
+        assert!(responses[2].ends_with("\r\n\r\nHello, there!\n"));
+    }
+
+    #[async_std::test]
+    async fn reads_requests_that_arrive_in_pieces() {
+        let requests = b"POST /hello HTTP/1.1\r\nContent-Length: 5\r\n\r\nthere\
+                         POST /hello HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                         3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n";
+        for mut stream in [
+            MockStream::new().send_slowly(requests),
+            MockStream::new().send_in_chunks(requests, 7),
+        ] {
+            let router = &server().router;
+            serve_connection(
+                &mut stream,
+                &Config::default(),
+                router,
+                &Shutdown::new(),
+                &no_log(),
+            )
+            .await
+            .unwrap();
+            stream.assert_read_all();
+            let written = String::from_utf8(stream.take_written()).unwrap();
+            let responses: Vec<_> = written.split("HTTP/1.1 ").skip(1).collect();
+            assert_eq!(responses.len(), 2, "{}", written);
+            assert!(responses[0].ends_with("\r\n\r\nHello, there!\n"));
+            assert!(responses[1].ends_with("\r\n\r\nHello, abcde!\n"));
+        }
+    }
+
+    /// Read one response with a `Content-Length` from `stream`.
+    async fn read_response(stream: &mut DuplexStream) -> String {
+        let mut read = Vec::new();
+        let mut byte = [0];
+        while !read.ends_with(b"\r\n\r\n") {
+            AsyncReadExt::read_exact(stream, &mut byte).await.unwrap();
+            read.push(byte[0]);
+        }
+        let head = String::from_utf8(read).unwrap();
+        let length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .unwrap();
+        let mut body = vec![0; length.parse().unwrap()];
+        AsyncReadExt::read_exact(stream, &mut body).await.unwrap();
+        head + std::str::from_utf8(&body).unwrap()
+    }
+
+    #[async_std::test]
+    async fn talks_to_clients_that_wait_for_answers() {
+        let (mut client, server_end) = duplex(64);
+        let (server, shutdown, access_log) = (server(), Shutdown::new(), no_log());
+        let serve = serve_connection(
+            server_end,
+            &server.config,
+            &server.router,
+            &shutdown,
+            &access_log,
+        );
+        let talk = async {
+            client
+                .write_all(b"GET /hello/you HTTP/1.1\r\n\r\n")
+                .await
+                .unwrap();
+            let response = read_response(&mut client).await;
+            assert!(response.ends_with("\r\n\r\nHello, you!\n"), "{}", response);
+
+            // Much more than the pipe holds, so the server has to keep
+            // reading for us to get it all sent.
+            let name = "x".repeat(10_000);
+            let request = format!(
+                "POST /hello HTTP/1.1\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                name.len(),
+                name
+            );
+            client.write_all(request.as_bytes()).await.unwrap();
+            let response = read_response(&mut client).await;
+            assert!(response.ends_with(&format!("\r\n\r\nHello, {}!\n", name)));
+            assert!(response.contains("\r\nConnection: close\r\n"));
+        };
+        let (served, ()) = future::join(serve, talk).await;
+        served.unwrap();
+        // The server hung up.
+        let read = AsyncReadExt::read(&mut client, &mut [0]).await.unwrap();
+        assert_eq!(read, 0);
+    }
+
+    #[async_std::test]
+    async fn hands_websockets_over_to_their_handler() {
+        use crate::websocket::Role;
+
+        let (mut client, server_end) = duplex(1024);
+        let (server, shutdown, access_log) = (server(), Shutdown::new(), no_log());
+        let serve = serve_connection(
+            server_end,
+            &server.config,
+            &server.router,
+            &shutdown,
+            &access_log,
+        );
+        let talk = async {
+            client
+                .write_all(
+                    b"GET /websocket/echo HTTP/1.1\r\n\
+                      Upgrade: websocket\r\n\
+                      Connection: Upgrade\r\n\
+                      Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                      Sec-WebSocket-Version: 13\r\n\r\n",
+                )
+                .await
+                .unwrap();
+            let mut head = Vec::new();
+            let mut byte = [0];
+            while !head.ends_with(b"\r\n\r\n") {
+                AsyncReadExt::read_exact(&mut client, &mut byte)
+                    .await
+                    .unwrap();
+                head.push(byte[0]);
+            }
+            let head = String::from_utf8(head).unwrap();
+            assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+            assert!(head.contains("\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+            assert!(head.contains("\r\nConnection: Upgrade\r\n"));
+
+            let mut socket = WebSocket::new(client, Role::Client);
+            let hello = Message::Text("hello".into());
+            socket.send(hello.clone()).await.unwrap();
+            assert_eq!(socket.next().await.unwrap().unwrap(), hello);
+            socket.close().await.unwrap();
+            let echoed = socket.next().await.unwrap().unwrap();
+            assert!(matches!(echoed, Message::Close(Some(_))));
+            assert!(socket.next().await.is_none());
+        };
+        let (served, ()) = future::join(serve, talk).await;
+        served.unwrap();
+    }
+
+    #[async_std::test]
+    async fn handlers_may_ignore_bodies() {
+        let router = Router::new().route("POST", "/", |_| async { Ok(Response::new(204)) });
+        let big = vec![b'x'; 100_000];
+        let mut input =
+            format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", big.len()).into_bytes();
+        input.extend_from_slice(&big);
+        input.extend_from_slice(b"POST / HTTP/1.1\r\n\r\n");
+        let written = exchange_with(&router, &input).await;
+        assert_eq!(written.matches("HTTP/1.1 204 No Content").count(), 2);
+    }
+
+    #[async_std::test]
+    async fn rejects_bodies_over_the_limit() {
+        let config = Config {
+            max_body_size: 4,
+            ..Config::default()
+        };
+        let router = Router::new().route("POST", "/", |request| async move {
+            match request.body.into_bytes().await {
+                Ok(_) => Ok(Response::new(204)),
+                Err(_) => Ok(Response::new(400)),
+            }
+        });
+        for input in [
+            &b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello"[..],
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n",
+        ] {
+            let mut stream = MockTcpStream {
+                read_data: input.to_vec(),
+                write_data: Vec::new(),
+            };
+            let error = serve_connection(&mut stream, &config, &router, &Shutdown::new(), &no_log())
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                error,
+                ServerError::Request(ParseError::BodyTooLarge)
+            ));
+            let written = String::from_utf8(stream.write_data).unwrap();
+            assert!(written.starts_with("HTTP/1.1 413 Content Too Large\r\n"), "{}", written);
+            assert!(written.contains("\r\nConnection: close\r\n"));
+        }
+    }
+
+    #[async_std::test]
+    async fn answers_expect_100_continue() {
+        let router = &server().router;
+        let written = exchange_with(
+            router,
+            b"POST /echo HTTP/1.1\r\nContent-Length: 2\r\nExpect: 100-continue\r\n\r\nhi",
+        )
+        .await;
+        assert!(written.starts_with("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\n"));
+    }
+
+    fn is_reset(error: &ServerError) -> bool {
+        matches!(error, ServerError::Io(error) if error.kind() == ConnectionReset)
+    }
+
+    #[async_std::test]
+    async fn reports_read_errors() {
+        let router = &server().router;
+        let config = Config::default();
+
+        // Between requests, after answering the first.
+        let mut stream = faulty(b"GET /hello/you HTTP/1.1\r\n\r\n", usize::MAX);
+        let error = serve_connection(&mut stream, &config, router, &Shutdown::new(), &no_log())
+            .await
+            .unwrap_err();
+        assert!(is_reset(&error), "{}", error);
+        let written = String::from_utf8(stream.take_written()).unwrap();
+        assert!(written.ends_with("\r\n\r\nHello, you!\n"));
+
+        // In the middle of a body, with the connection no use for answering.
+        let mut stream = faulty(
+            b"POST /hello HTTP/1.1\r\nContent-Length: 9\r\n\r\nyo",
+            usize::MAX,
+        );
+        let error = serve_connection(&mut stream, &config, router, &Shutdown::new(), &no_log())
+            .await
+            .unwrap_err();
+        assert!(is_reset(&error), "{}", error);
+        assert!(stream.written().is_empty());
+    }
+
+    #[async_std::test]
+    async fn reports_write_errors() {
+        let router = &server().router;
+        let input = b"GET /hello/you HTTP/1.1\r\n\r\nGET /hello/you HTTP/1.1\r\n\r\n";
+        for write_limit in [0, 10, 100] {
+            let mut stream = faulty(input, write_limit);
+            let error = serve_connection(
+                &mut stream,
+                &Config::default(),
+                router,
+                &Shutdown::new(),
+                &no_log(),
+            )
+            .await
+            .unwrap_err();
+            assert!(is_reset(&error), "{}", error);
+        }
+    }
+
+    #[async_std::test]
+    async fn answers_failed_handlers_with_500() {
+        let router = Router::new()
+            .get("/fail", |_| async { Err("no database".into()) })
+            .get("/", |_| async { Ok(Response::new(204)) });
+        let written =
+            exchange_with(&router, b"GET /fail HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n").await;
+        assert!(written.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(!written.contains("no database"));
+        // The connection carries on.
+        assert!(written.contains("HTTP/1.1 204 No Content\r\n"));
+    }
+
+    #[async_std::test]
+    async fn logs_every_response() {
+        let path =
+            std::env::temp_dir().join(format!("final-tcp-server-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let target = LogTarget::File {
+            path: path.clone(),
+            max_size: u64::MAX,
+            keep: 0,
+        };
+        let access_log = AccessLog::new(target, access_log::LogFormat::Combined);
+        let mut stream = MockTcpStream {
+            read_data: b"GET /hello/you HTTP/1.1\r\nUser-Agent: test\r\n\r\n\
+                         GET /missing HTTP/1.0\r\n\r\n"
+                .to_vec(),
+            write_data: Vec::new(),
+        };
+        let server = server();
+        serve_connection(
+            &mut stream,
+            &server.config,
+            &server.router,
+            &Shutdown::new(),
+            &access_log,
+        )
+        .await
+        .unwrap();
+        access_log.close().await;
+
+        let logged = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<_> = logged.lines().collect();
+        assert_eq!(lines.len(), 2, "{}", logged);
+        assert!(lines[0].starts_with("- - - ["));
+        assert!(lines[0].ends_with(r#"] "GET /hello/you HTTP/1.1" 200 12 "-" "test""#));
+        assert!(lines[1].contains(r#"] "GET /missing HTTP/1.0" 404 "#));
+    }
+
+    #[async_std::test]
+    async fn rejects_clients_beyond_the_limit() {
+        let mut stream = MockTcpStream {
+            read_data: b"GET / HTTP/1.1\r\n\r\n".to_vec(),
+            write_data: Vec::new(),
+        };
+        reject(&mut stream, Config::default().write_timeout).await;
+        let written = String::from_utf8(stream.write_data).unwrap();
+        let responses = responses(&written);
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0]
+            .0
+            .starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(responses[0].0.contains("\r\nConnection: close"));
+
+        // One that never reads it doesn't keep us waiting.
+        let mut stream = MockStream::new().stall_writes_after(0);
+        let rejecting = reject(&mut stream, std::time::Duration::from_millis(50));
+        assert!(timeout(std::time::Duration::from_secs(5), rejecting)
+            .await
+            .is_ok());
+    }
+
+    /// Serve `stream` with timeouts of 50 milliseconds.
+    async fn serve_impatiently(
+        stream: &mut MockStream,
+        router: &Router,
+    ) -> Result<(), ServerError> {
+        let timeout = std::time::Duration::from_millis(50);
+        let config = Config {
+            idle_timeout: timeout,
+            header_timeout: timeout,
+            body_timeout: timeout,
+            handler_timeout: timeout,
+            write_timeout: timeout,
+            ..Config::default()
+        };
+        serve_connection(&mut *stream, &config, router, &Shutdown::new(), &no_log()).await
+    }
+
+    #[async_std::test]
+    async fn times_out_stalled_requests() {
+        let router = &server().router;
+        let mut stream = quiet(b"GET / HTTP/1.1\r\nHost: loc");
+        let error = serve_impatiently(&mut stream, router).await.unwrap_err();
+        assert!(
+            matches!(error, ServerError::Timeout(Phase::Head)),
+            "{}",
+            error
+        );
+        let written = String::from_utf8(stream.take_written()).unwrap();
+        assert!(written.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        assert!(written.contains("\r\nConnection: close\r\n"));
+
+        let mut stream = quiet(b"POST /hello HTTP/1.1\r\nContent-Length: 10\r\n\r\nyou");
+        let error = serve_impatiently(&mut stream, router).await.unwrap_err();
+        assert!(
+            matches!(error, ServerError::Timeout(Phase::Body)),
+            "{}",
+            error
+        );
+        let written = String::from_utf8(stream.take_written()).unwrap();
+        assert!(written.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    }
+
+    #[async_std::test]
+    async fn answers_slow_handlers_with_503() {
+        let router = Router::new()
+            .get("/slow", |_| async {
+                async_std::task::sleep(std::time::Duration::from_secs(5)).await;
+                Ok(Response::new(204))
+            })
+            .get("/", |_| async { Ok(Response::new(204)) });
+        let mut stream = quiet(b"GET /slow HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n");
+        // The connection carries on, until the client goes quiet.
+        serve_impatiently(&mut stream, &router).await.unwrap();
+        let written = String::from_utf8(stream.take_written()).unwrap();
+        assert!(written.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(written.contains("HTTP/1.1 204 No Content\r\n"));
+    }
+
+    #[async_std::test]
+    async fn drops_clients_that_stop_reading() {
+        let mut stream = quiet(b"GET / HTTP/1.1\r\n\r\n").stall_writes_after(10);
+        let error = serve_impatiently(&mut stream, &server().router)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&error, ServerError::Io(error) if error.kind() == std::io::ErrorKind::TimedOut),
+            "{}",
+            error
+        );
+    }
 }
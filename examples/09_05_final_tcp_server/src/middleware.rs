@@ -0,0 +1,368 @@
+//! Behaviour shared by every route.
+//!
+//! A middleware sits between the server and the handlers: it gets each
+//! request on its way in, passes it on by calling `next.run(request)`,
+//! and gets the response on its way out. It may also answer the request
+//! itself without passing it on, as `BearerAuth` does for requests that
+//! don't have the token.
+//!
+//! Middleware is added to a `Router` with `Router::wrap`, and runs in the
+//! order it was added: the first one added sees the request first and the
+//! response last, wrapped around everything added after it.
+//!
+//! There's no logging middleware: the server writes every request it
+//! answers to its access log, along with how much of the response got
+//! out, which only the server knows.
+
+use std::any::Any;
+use std::io::Write;
+use std::panic::AssertUnwindSafe;
+use std::time::Instant;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::future::{BoxFuture, FutureExt};
+
+use crate::body::Body;
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::{segments, HandlerResult, Router};
+
+pub trait Middleware: Send + Sync + 'static {
+    fn call<'a>(&'a self, request: Request, next: Next<'a>) -> BoxFuture<'a, HandlerResult>;
+}
+
+/// The rest of the stack, after the middleware that's been given it.
+pub struct Next<'a> {
+    middleware: &'a [Box<dyn Middleware>],
+    router: &'a Router,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(middleware: &'a [Box<dyn Middleware>], router: &'a Router) -> Self {
+        Next { middleware, router }
+    }
+
+    /// Pass `request` on to the next middleware, or to its route's handler
+    /// if there's none left.
+    pub fn run(self, request: Request) -> BoxFuture<'a, HandlerResult> {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.call(request, Next::new(rest, self.router)),
+            None => self.router.dispatch(request).boxed(),
+        }
+    }
+}
+
+/// Reports how long the rest of the stack took to answer, in a
+/// `Server-Timing` header. Streamed bodies are sent after that, so their
+/// time isn't included.
+pub struct Timing;
+
+impl Middleware for Timing {
+    fn call<'a>(&'a self, request: Request, next: Next<'a>) -> BoxFuture<'a, HandlerResult> {
+        async move {
+            let start = Instant::now();
+            let response = next.run(request).await?;
+            let millis = start.elapsed().as_secs_f64() * 1000.0;
+            Ok(response.with_header("Server-Timing", format!("app;dur={:.1}", millis)))
+        }
+        .boxed()
+    }
+}
+
+/// Gzips response bodies for clients that accept it.
+///
+/// Only bodies already in memory are compressed, and only if they're big
+/// enough to be worth it and of a type that compresses well.
+pub struct Compress {
+    /// Smallest body worth compressing, in bytes.
+    pub min_size: usize,
+}
+
+impl Default for Compress {
+    fn default() -> Self {
+        Compress { min_size: 1024 }
+    }
+}
+
+impl Middleware for Compress {
+    fn call<'a>(&'a self, request: Request, next: Next<'a>) -> BoxFuture<'a, HandlerResult> {
+        let accepted = accepts_gzip(&request);
+        async move {
+            let mut response = next.run(request).await?;
+            let bytes = match &response.body {
+                Body::Bytes(bytes) => bytes,
+                Body::Reader { .. } => return Ok(response),
+            };
+            let compressible = response
+                .headers
+                .get("content-type")
+                .is_some_and(is_compressible);
+            if !accepted
+                || !compressible
+                || bytes.len() < self.min_size
+                || response.headers.contains("content-encoding")
+            {
+                return Ok(response);
+            }
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(bytes)?;
+            response.body = Body::Bytes(encoder.finish()?);
+            Ok(response
+                .with_header("Content-Encoding", "gzip")
+                .with_header("Vary", "Accept-Encoding"))
+        }
+        .boxed()
+    }
+}
+
+fn accepts_gzip(request: &Request) -> bool {
+    request
+        .headers
+        .get_all("accept-encoding")
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut parts = coding.split(';');
+            let name = parts.next().unwrap_or_default().trim();
+            // `gzip;q=0` means anything but gzip.
+            let refused = parts.any(|param| {
+                let q = param.trim().strip_prefix("q=");
+                q.and_then(|q| q.trim().parse::<f32>().ok()) == Some(0.0)
+            });
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+        })
+}
+
+fn is_compressible(content_type: &str) -> bool {
+    let content_type = content_type.to_ascii_lowercase();
+    content_type.starts_with("text/")
+        || ["json", "javascript", "xml", "svg"]
+            .iter()
+            .any(|kind| content_type.contains(kind))
+}
+
+/// Turns away requests for paths under `prefix` that don't carry
+/// `Authorization: Bearer <token>`.
+pub struct BearerAuth {
+    prefix: String,
+    token: String,
+}
+
+impl BearerAuth {
+    pub fn new(prefix: &str, token: &str) -> Self {
+        BearerAuth {
+            prefix: prefix.to_string(),
+            token: token.to_string(),
+        }
+    }
+
+    /// Compared segment by segment, the way the router matches paths, so
+    /// that `//stats` can't get past us to the `/stats` route.
+    fn protects(&self, path: &str) -> bool {
+        let mut path = segments(path);
+        segments(&self.prefix).all(|expected| path.next() == Some(expected))
+    }
+}
+
+impl Middleware for BearerAuth {
+    fn call<'a>(&'a self, request: Request, next: Next<'a>) -> BoxFuture<'a, HandlerResult> {
+        if !self.protects(request.path()) {
+            return next.run(request);
+        }
+        let authorized = request
+            .headers
+            .get("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| token.trim() == self.token);
+        if authorized {
+            return next.run(request);
+        }
+        let response =
+            Response::text(401, "Unauthorized\n").with_header("WWW-Authenticate", "Bearer");
+        async move { Ok(response) }.boxed()
+    }
+}
+
+/// Turns a handler panicking into it failing, so that the client gets a
+/// 500 instead of having the connection dropped on it.
+pub struct CatchPanic;
+
+impl Middleware for CatchPanic {
+    fn call<'a>(&'a self, request: Request, next: Next<'a>) -> BoxFuture<'a, HandlerResult> {
+        // Nothing the handler could have left half-done is shared with
+        // anything else, so there's no broken state to worry about.
+        AssertUnwindSafe(next.run(request))
+            .catch_unwind()
+            .map(|result| result.unwrap_or_else(|panic| Err(panic_message(panic).into())))
+            .boxed()
+    }
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    let message = match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown".to_string(),
+        },
+    };
+    format!("handler panicked: {}", message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::RequestParser;
+    use std::sync::{Arc, Mutex};
+
+    fn request(target: &str, headers: &[(&str, &str)]) -> Request {
+        let mut head = format!("GET {} HTTP/1.1\r\n", target);
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        let mut parser = RequestParser::new();
+        parser.feed(head.as_bytes());
+        parser.parse().unwrap().unwrap()
+    }
+
+    fn text_router(text: &'static str) -> Router {
+        Router::new().get("/*", move |_| async move { Ok(Response::text(200, text)) })
+    }
+
+    /// Records the order it sees requests and responses in.
+    struct Record {
+        name: &'static str,
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Middleware for Record {
+        fn call<'a>(&'a self, request: Request, next: Next<'a>) -> BoxFuture<'a, HandlerResult> {
+            async move {
+                self.events
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} in", self.name));
+                let result = next.run(request).await;
+                self.events
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} out", self.name));
+                result
+            }
+            .boxed()
+        }
+    }
+
+    #[async_std::test]
+    async fn runs_in_the_order_added() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorder = |name| Record {
+            name,
+            events: events.clone(),
+        };
+        let router = text_router("hi")
+            .wrap(recorder("outer"))
+            .wrap(recorder("inner"));
+        router.handle(request("/", &[])).await.unwrap();
+        assert_eq!(
+            *events.lock().unwrap(),
+            ["outer in", "inner in", "inner out", "outer out"]
+        );
+    }
+
+    #[async_std::test]
+    async fn reports_timing() {
+        let router = text_router("hi").wrap(Timing);
+        let response = router.handle(request("/", &[])).await.unwrap();
+        let timing = response.headers.get("server-timing").unwrap();
+        assert!(timing.starts_with("app;dur="), "{}", timing);
+    }
+
+    #[async_std::test]
+    async fn compresses_for_clients_that_accept_gzip() {
+        let text = "hello, compressible world! ".repeat(100);
+        let text: &'static str = Box::leak(text.into_boxed_str());
+        let router = text_router(text).wrap(Compress::default());
+
+        let response = router
+            .handle(request("/", &[("Accept-Encoding", "br, gzip")]))
+            .await
+            .unwrap();
+        assert_eq!(response.headers.get("content-encoding"), Some("gzip"));
+        assert_eq!(response.headers.get("vary"), Some("Accept-Encoding"));
+        let compressed = response.body.into_bytes().await.unwrap();
+        assert!(compressed.len() < text.len() / 10);
+        let mut decoder = flate2::read::GzDecoder::new(&compressed[..]);
+        let mut decompressed = String::new();
+        std::io::Read::read_to_string(&mut decoder, &mut decompressed).unwrap();
+        assert_eq!(decompressed, text);
+
+        for headers in [
+            &[][..],
+            &[("Accept-Encoding", "gzip;q=0, br")],
+            &[("Accept-Encoding", "deflate")],
+        ] {
+            let response = router.handle(request("/", headers)).await.unwrap();
+            assert!(
+                !response.headers.contains("content-encoding"),
+                "{:?}",
+                headers
+            );
+        }
+
+        // Too small to bother with.
+        let router = text_router("hi").wrap(Compress::default());
+        let response = router
+            .handle(request("/", &[("Accept-Encoding", "gzip")]))
+            .await
+            .unwrap();
+        assert!(!response.headers.contains("content-encoding"));
+    }
+
+    #[async_std::test]
+    async fn requires_the_token_under_its_prefix() {
+        let router = text_router("secret").wrap(BearerAuth::new("/admin", "s3cret"));
+        let status = |target, headers: &'static [(&'static str, &'static str)]| {
+            let router = &router;
+            async move {
+                router
+                    .handle(request(target, headers))
+                    .await
+                    .unwrap()
+                    .status
+            }
+        };
+        assert_eq!(status("/admin", &[]).await, 401);
+        assert_eq!(
+            status("/admin/users", &[("Authorization", "Bearer nope")]).await,
+            401
+        );
+        assert_eq!(
+            status("/admin/users", &[("Authorization", "Bearer s3cret")]).await,
+            200
+        );
+        for target in ["//admin", "/admin/", "//admin//users", "/admin?q=1"] {
+            assert_eq!(status(target, &[]).await, 401, "{}", target);
+        }
+        assert_eq!(status("/administrator", &[]).await, 200);
+        assert_eq!(status("/", &[]).await, 200);
+
+        let response = router.handle(request("/admin", &[])).await.unwrap();
+        assert_eq!(response.headers.get("www-authenticate"), Some("Bearer"));
+    }
+
+    #[async_std::test]
+    async fn turns_panics_into_errors() {
+        let router = Router::new()
+            .get("/", |_| async {
+                if true {
+                    panic!("oops");
+                }
+                Ok(Response::new(204))
+            })
+            .wrap(CatchPanic);
+        let error = router.handle(request("/", &[])).await.unwrap_err();
+        assert_eq!(error.to_string(), "handler panicked: oops");
+    }
+}
@@ -0,0 +1,505 @@
+//! Parsing HTTP/1.1 requests.
+//!
+//! A request doesn't necessarily arrive in one `read`: a client may send
+//! it in pieces, and it may be bigger than our read buffer. So the parser
+//! is incremental. Bytes are fed to a `RequestParser` as they arrive, and
+//! `RequestParser::parse` returns a `Request` once its head, the request
+//! line and headers, has been buffered. The body follows it in the buffer,
+//! to be taken out with `RequestParser::parse_body` (see `request_body`).
+
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::Unpin;
+
+use async_std::io::{self, Read};
+use async_std::prelude::*;
+
+use crate::error::ServerError;
+use crate::headers::Headers;
+use crate::request_body::{BodyDecoder, BodyPart, RequestBody};
+
+/// Largest request line plus header section we're willing to buffer.
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+    /// Only with the `http2` feature, and never from the parser.
+    #[cfg(feature = "http2")]
+    Http2,
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+            #[cfg(feature = "http2")]
+            Version::Http2 => "HTTP/2",
+        })
+    }
+}
+
+/// Values taken from the path by a route's `:name` and `*name` segments.
+pub type Params = HashMap<String, String>;
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    /// The request target, usually a path like `/index.html?lang=en`.
+    pub target: String,
+    pub version: Version,
+    pub headers: Headers,
+    pub body: RequestBody,
+    /// Filled in by the `Router` when it picks a route.
+    pub params: Params,
+}
+
+impl Request {
+    /// The request target without its query string.
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or_default()
+    }
+
+    /// The path parameter called `name`.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+
+    /// Whether the client wants to send more requests on this connection.
+    /// HTTP/1.1 connections stay open unless the client asks to close
+    /// them; HTTP/1.0 ones only if it asks to keep them alive.
+    pub fn keep_alive(&self) -> bool {
+        match self.version {
+            Version::Http11 => !self.headers.has_token("connection", "close"),
+            Version::Http10 => self.headers.has_token("connection", "keep-alive"),
+            // Requests are streams on a connection that stays open
+            // regardless.
+            #[cfg(feature = "http2")]
+            Version::Http2 => true,
+        }
+    }
+}
+
+/// Why a request couldn't be parsed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// The first line isn't `method target version`.
+    InvalidRequestLine,
+    /// The request is for an HTTP version other than 1.0 or 1.1.
+    UnsupportedVersion(String),
+    /// A header line isn't `name: value`.
+    InvalidHeader(String),
+    /// The request line and headers exceed `MAX_HEAD_SIZE`.
+    HeadTooLarge,
+    /// `Content-Length` is not a number, or appears with different values.
+    InvalidContentLength,
+    /// The request uses a `Transfer-Encoding` we can't decode.
+    UnsupportedTransferEncoding(String),
+    /// A chunk of a chunked body is malformed.
+    InvalidChunk,
+    /// The body is larger than the server accepts.
+    BodyTooLarge,
+    /// The connection was closed in the middle of a request.
+    UnexpectedEof,
+}
+
+impl ParseError {
+    /// The status code to answer the request with.
+    pub fn status_code(&self) -> u16 {
+        match self {
+            ParseError::UnsupportedVersion(_) => 505,
+            ParseError::HeadTooLarge => 431,
+            ParseError::UnsupportedTransferEncoding(_) => 501,
+            ParseError::BodyTooLarge => 413,
+            _ => 400,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::InvalidRequestLine => write!(f, "malformed request line"),
+            ParseError::UnsupportedVersion(version) => {
+                write!(f, "unsupported HTTP version {:?}", version)
+            }
+            ParseError::InvalidHeader(line) => write!(f, "malformed header {:?}", line),
+            ParseError::HeadTooLarge => write!(f, "request headers too large"),
+            ParseError::InvalidContentLength => write!(f, "invalid Content-Length"),
+            ParseError::UnsupportedTransferEncoding(coding) => {
+                write!(f, "unsupported transfer coding {:?}", coding)
+            }
+            ParseError::InvalidChunk => write!(f, "malformed chunk in request body"),
+            ParseError::BodyTooLarge => write!(f, "request body too large"),
+            ParseError::UnexpectedEof => write!(f, "connection closed mid-request"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Default)]
+pub struct RequestParser {
+    buffer: Vec<u8>,
+    /// Set while the body of the last request hasn't been parsed.
+    body: Option<BodyDecoder>,
+}
+
+impl RequestParser {
+    pub fn new() -> Self {
+        RequestParser::default()
+    }
+
+    /// Add bytes received from the client.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Whether nothing of a next request has been received.
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty() && self.body.is_none()
+    }
+
+    /// Take the head of the next request out of the buffer, or return
+    /// `Ok(None)` if it hasn't been received completely yet.
+    ///
+    /// Whatever is left of the previous request's body is skipped first.
+    pub fn parse(&mut self) -> Result<Option<Request>, ParseError> {
+        loop {
+            match self.parse_body()? {
+                BodyPart::Data(_) => {}
+                BodyPart::Incomplete => return Ok(None),
+                BodyPart::End => break,
+            }
+        }
+
+        let head_end = match find(&self.buffer, b"\r\n\r\n") {
+            Some(end) => end,
+            None if self.buffer.len() > MAX_HEAD_SIZE => return Err(ParseError::HeadTooLarge),
+            None => return Ok(None),
+        };
+        if head_end > MAX_HEAD_SIZE {
+            return Err(ParseError::HeadTooLarge);
+        }
+        let (request, body) = parse_head(&self.buffer[..head_end])?;
+        self.buffer.drain(..head_end + 4);
+        self.body = Some(body).filter(|body| *body != BodyDecoder::Length(0));
+        Ok(Some(request))
+    }
+
+    /// Take the next part of the last request's body out of the buffer.
+    pub fn parse_body(&mut self) -> Result<BodyPart, ParseError> {
+        let part = match &mut self.body {
+            Some(body) => body.decode(&mut self.buffer)?,
+            None => BodyPart::End,
+        };
+        if part == BodyPart::End {
+            self.body = None;
+        }
+        Ok(part)
+    }
+
+    /// How much of the last request's body is left, if it has a
+    /// `Content-Length`.
+    pub fn body_length(&self) -> Option<u64> {
+        match self.body {
+            Some(BodyDecoder::Length(length)) => Some(length),
+            None => Some(0),
+            Some(_) => None,
+        }
+    }
+
+    /// Take whatever has been received after the last request, for when
+    /// the connection stops speaking HTTP.
+    pub fn take_buffered(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buffer)
+    }
+}
+
+/// Read the next request's head from `stream`, using (and leaving behind
+/// in) `parser` whatever was read past its end.
+///
+/// Returns `Ok(None)` if the client closed the connection in between
+/// requests.
+pub async fn read_request(
+    stream: &mut (impl Read + Unpin),
+    parser: &mut RequestParser,
+) -> Result<Option<Request>, ServerError> {
+    loop {
+        if let Some(request) = parser.parse()? {
+            return Ok(Some(request));
+        }
+        if fill(stream, parser).await? == 0 {
+            return if parser.is_empty() {
+                Ok(None)
+            } else {
+                Err(ParseError::UnexpectedEof.into())
+            };
+        }
+    }
+}
+
+/// Feed `parser` the next read from `stream`, returning its size.
+pub async fn fill(
+    stream: &mut (impl Read + Unpin),
+    parser: &mut RequestParser,
+) -> io::Result<usize> {
+    let mut buffer = [0; 1024];
+    let size = stream.read(&mut buffer).await?;
+    parser.feed(&buffer[..size]);
+    Ok(size)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Whether `s` is a token, which is what methods and header names are.
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+fn parse_head(head: &[u8]) -> Result<(Request, BodyDecoder), ParseError> {
+    let head = std::str::from_utf8(head).map_err(|_| ParseError::InvalidRequestLine)?;
+    let mut lines = head.split("\r\n");
+
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Err(ParseError::InvalidRequestLine),
+    };
+    if !is_token(method) || target.is_empty() || target.bytes().any(|b| b.is_ascii_control()) {
+        return Err(ParseError::InvalidRequestLine);
+    }
+    let version = match version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        other if other.starts_with("HTTP/") => {
+            return Err(ParseError::UnsupportedVersion(other.to_string()))
+        }
+        _ => return Err(ParseError::InvalidRequestLine),
+    };
+
+    let mut headers = Headers::new();
+    for line in lines {
+        let invalid = || ParseError::InvalidHeader(line.to_string());
+        let (name, value) = line.split_once(':').ok_or_else(invalid)?;
+        // Whitespace before the colon, or a line starting with whitespace
+        // (the obsolete line folding), make the name an invalid token.
+        if !is_token(name) {
+            return Err(invalid());
+        }
+        // A bare CR or LF would end the line for someone else, and nothing
+        // that writes the value out, like the access log, expects a NUL.
+        if value.chars().any(|c| c.is_ascii_control() && c != '\t') {
+            return Err(invalid());
+        }
+        headers.append(name, value.trim_matches(|c| c == ' ' || c == '\t'));
+    }
+
+    let mut body_length = None;
+    for value in headers.get_all("content-length") {
+        // Only digits: `parse` would take a sign too, and a proxy in front
+        // of us might not.
+        if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err(ParseError::InvalidContentLength);
+        }
+        let length: u64 = value
+            .parse()
+            .map_err(|_| ParseError::InvalidContentLength)?;
+        if body_length.is_some_and(|previous| previous != length) {
+            return Err(ParseError::InvalidContentLength);
+        }
+        body_length = Some(length);
+    }
+    let body = if headers.contains("transfer-encoding") {
+        let codings = headers
+            .get_all("transfer-encoding")
+            .collect::<Vec<_>>()
+            .join(",");
+        if !codings.trim().eq_ignore_ascii_case("chunked") {
+            return Err(ParseError::UnsupportedTransferEncoding(codings));
+        }
+        // With both, a proxy in front of us might have used the other one
+        // to tell where the request ends.
+        if body_length.is_some() {
+            return Err(ParseError::InvalidContentLength);
+        }
+        BodyDecoder::ChunkSize
+    } else {
+        BodyDecoder::Length(body_length.unwrap_or(0))
+    };
+
+    let request = Request {
+        method: method.to_string(),
+        target: target.to_string(),
+        version,
+        headers,
+        body: RequestBody::empty(),
+        params: Params::new(),
+    };
+    Ok((request, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all(bytes: &[u8]) -> Result<Option<Request>, ParseError> {
+        let mut parser = RequestParser::new();
+        parser.feed(bytes);
+        parser.parse()
+    }
+
+    #[test]
+    fn parses_a_request_fed_byte_by_byte() {
+        let bytes =
+            b"POST /submit?x=1 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello";
+        let head_length = bytes.len() - 5;
+        let mut parser = RequestParser::new();
+        for (i, byte) in bytes[..head_length].iter().enumerate() {
+            assert!(
+                parser.parse().unwrap().is_none(),
+                "complete after {} bytes",
+                i
+            );
+            parser.feed(&[*byte]);
+        }
+        let request = parser.parse().unwrap().unwrap();
+        let mut body = Vec::new();
+        for byte in &bytes[head_length..] {
+            assert_eq!(parser.parse_body(), Ok(BodyPart::Incomplete));
+            parser.feed(&[*byte]);
+            match parser.parse_body() {
+                Ok(BodyPart::Data(data)) => body.extend(data),
+                other => panic!("{:?}", other),
+            }
+        }
+        assert_eq!(parser.parse_body(), Ok(BodyPart::End));
+
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.target, "/submit?x=1");
+        assert_eq!(request.path(), "/submit");
+        assert_eq!(request.version, Version::Http11);
+        assert_eq!(request.headers.get("host"), Some("localhost"));
+        assert_eq!(body, b"hello");
+        assert!(parser.is_empty());
+    }
+
+    #[test]
+    fn header_names_are_case_insensitive_and_may_repeat() {
+        let request =
+            parse_all(b"GET / HTTP/1.1\r\nACCEPT: text/html\r\naccept:  text/plain \r\n\r\n")
+                .unwrap()
+                .unwrap();
+        assert_eq!(request.headers.get("Accept"), Some("text/html"));
+        assert_eq!(
+            request.headers.get_all("accept").collect::<Vec<_>>(),
+            ["text/html", "text/plain"]
+        );
+
+        // Tabs are the one control character a value may have.
+        let request = parse_all(b"GET / HTTP/1.1\r\nX-Tabbed:\ta\tb\t\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.headers.get("x-tabbed"), Some("a\tb"));
+    }
+
+    #[test]
+    fn handles_requests_larger_than_a_read() {
+        let long_value = "x".repeat(4000);
+        let bytes = format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", long_value);
+        let request = parse_all(bytes.as_bytes()).unwrap().unwrap();
+        assert_eq!(request.headers.get("x-long"), Some(long_value.as_str()));
+    }
+
+    #[test]
+    fn leaves_the_next_request_in_the_buffer() {
+        let mut parser = RequestParser::new();
+        parser.feed(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n");
+        assert_eq!(parser.parse().unwrap().unwrap().target, "/a");
+        assert_eq!(parser.parse().unwrap().unwrap().target, "/b");
+        assert!(parser.parse().unwrap().is_none());
+    }
+
+    #[test]
+    fn skips_unread_bodies() {
+        let mut parser = RequestParser::new();
+        parser.feed(b"POST /a HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc");
+        parser.feed(b"POST /b HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\nx\r\n0\r\n\r\n");
+        parser.feed(b"GET /c HTTP/1.1\r\n\r\n");
+        assert_eq!(parser.parse().unwrap().unwrap().target, "/a");
+        assert_eq!(parser.parse().unwrap().unwrap().target, "/b");
+        assert_eq!(parser.body_length(), None);
+        assert_eq!(parser.parse().unwrap().unwrap().target, "/c");
+        assert!(parser.is_empty());
+    }
+
+    #[test]
+    fn keep_alive_depends_on_version_and_connection_header() {
+        let keep_alive = |bytes: &[u8]| parse_all(bytes).unwrap().unwrap().keep_alive();
+        assert!(keep_alive(b"GET / HTTP/1.1\r\n\r\n"));
+        assert!(!keep_alive(
+            b"GET / HTTP/1.1\r\nConnection: Upgrade, close\r\n\r\n"
+        ));
+        assert!(!keep_alive(b"GET / HTTP/1.0\r\n\r\n"));
+        assert!(keep_alive(
+            b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n"
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        let cases: &[(&[u8], u16)] = &[
+            (b"GET /\r\n\r\n", 400),
+            (b"GET  / HTTP/1.1\r\n\r\n", 400),
+            (b"G(T / HTTP/1.1\r\n\r\n", 400),
+            (b"GET / HTTP/2.0\r\n\r\n", 505),
+            (b"GET / FTP/1.0\r\n\r\n", 400),
+            (b"GET / HTTP/1.1\r\nno colon\r\n\r\n", 400),
+            (b"GET / HTTP/1.1\r\nHost : x\r\n\r\n", 400),
+            (b"GET / HTTP/1.1\r\nA: b\r\n folded\r\n\r\n", 400),
+            (b"GET / HTTP/1.1\r\nA: b\nX-Forged: 1\r\n\r\n", 400),
+            (b"GET / HTTP/1.1\r\nA: b\rc\r\n\r\n", 400),
+            (b"GET / HTTP/1.1\r\nA: b\0c\r\n\r\n", 400),
+            (b"GET / HTTP/1.1\r\nA: b\x1bc\r\n\r\n", 400),
+            (b"GET / HTTP/1.1\r\nA: b\x7f\r\n\r\n", 400),
+            (b"GET / HTTP/1.1\r\nContent-Length: -1\r\n\r\n", 400),
+            (b"GET / HTTP/1.1\r\nContent-Length: +5\r\n\r\n", 400),
+            (b"GET / HTTP/1.1\r\nContent-Length: 5 5\r\n\r\n", 400),
+            (b"GET / HTTP/1.1\r\nContent-Length:\r\n\r\n", 400),
+            (
+                b"GET / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n",
+                400,
+            ),
+            (
+                b"GET / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n",
+                501,
+            ),
+            (
+                b"GET / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 1\r\n\r\n",
+                400,
+            ),
+        ];
+        for (bytes, status) in cases {
+            let error = parse_all(bytes).unwrap_err();
+            assert_eq!(error.status_code(), *status, "{:?}: {}", bytes, error);
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_heads() {
+        let bytes = format!("GET / HTTP/1.1\r\nX: {}", "x".repeat(MAX_HEAD_SIZE));
+        assert_eq!(
+            parse_all(bytes.as_bytes()).unwrap_err(),
+            ParseError::HeadTooLarge
+        );
+    }
+}
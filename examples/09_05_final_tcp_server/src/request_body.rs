@@ -0,0 +1,314 @@
+//! Reading request bodies.
+//!
+//! A body is delimited by its `Content-Length`, or sent with
+//! `Transfer-Encoding: chunked` as a series of chunks, each preceded by
+//! its size in hex, ending with an empty one:
+//!
+//! ```text
+//! 5\r\n
+//! hello\r\n
+//! 0\r\n
+//! \r\n
+//! ```
+//!
+//! The handler gets the body as a `RequestBody`, a stream of pieces that
+//! it can start working on before the whole body has arrived. The pieces
+//! are handed over one at a time, so a handler that reads slowly also
+//! slows down how fast we read from the client.
+
+use std::fmt;
+use std::io;
+use std::marker::Unpin;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_std::future::timeout;
+use async_std::io::Read;
+use futures::channel::mpsc;
+use futures::sink::SinkExt;
+use futures::stream::{Stream, StreamExt};
+
+use crate::config::Config;
+use crate::error::{Phase, ServerError};
+use crate::request::{fill, ParseError, RequestParser};
+
+/// Longest chunk size line, or trailer field, we accept.
+const MAX_LINE_SIZE: usize = 4 * 1024;
+
+/// What the parser has of a body.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BodyPart {
+    Data(Vec<u8>),
+    /// More bytes have to arrive before there's anything to return.
+    Incomplete,
+    End,
+}
+
+/// Where the body of a request is up to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BodyDecoder {
+    /// This many bytes of a `Content-Length` body are left.
+    Length(u64),
+    /// Expecting a chunk size line.
+    ChunkSize,
+    /// This many bytes of the current chunk are left.
+    ChunkData(u64),
+    /// Expecting the CRLF that follows the chunk data.
+    ChunkEnd,
+    /// After the last chunk, there may be trailer fields up to a blank line.
+    Trailers,
+}
+
+impl BodyDecoder {
+    /// Take the next part of the body out of `buffer`.
+    pub fn decode(&mut self, buffer: &mut Vec<u8>) -> Result<BodyPart, ParseError> {
+        loop {
+            match *self {
+                BodyDecoder::Length(0) => return Ok(BodyPart::End),
+                BodyDecoder::Length(remaining) | BodyDecoder::ChunkData(remaining) => {
+                    if buffer.is_empty() {
+                        return Ok(BodyPart::Incomplete);
+                    }
+                    let size = buffer.len().min(remaining as usize);
+                    let data = buffer.drain(..size).collect();
+                    let remaining = remaining - size as u64;
+                    *self = match self {
+                        BodyDecoder::Length(_) => BodyDecoder::Length(remaining),
+                        _ if remaining == 0 => BodyDecoder::ChunkEnd,
+                        _ => BodyDecoder::ChunkData(remaining),
+                    };
+                    return Ok(BodyPart::Data(data));
+                }
+                BodyDecoder::ChunkSize => {
+                    let line = match take_line(buffer)? {
+                        Some(line) => line,
+                        None => return Ok(BodyPart::Incomplete),
+                    };
+                    // Chunk extensions, after a `;`, are ignored.
+                    let size = line.split(|&b| b == b';').next().unwrap_or_default();
+                    let size = std::str::from_utf8(size)
+                        .ok()
+                        .map(str::trim)
+                        .filter(|size| !size.is_empty())
+                        .and_then(|size| u64::from_str_radix(size, 16).ok())
+                        .ok_or(ParseError::InvalidChunk)?;
+                    *self = if size == 0 {
+                        BodyDecoder::Trailers
+                    } else {
+                        BodyDecoder::ChunkData(size)
+                    };
+                }
+                BodyDecoder::ChunkEnd => {
+                    if buffer.len() < 2 {
+                        return Ok(BodyPart::Incomplete);
+                    }
+                    if buffer[..2] != *b"\r\n" {
+                        return Err(ParseError::InvalidChunk);
+                    }
+                    buffer.drain(..2);
+                    *self = BodyDecoder::ChunkSize;
+                }
+                BodyDecoder::Trailers => match take_line(buffer)? {
+                    None => return Ok(BodyPart::Incomplete),
+                    Some(line) if line.is_empty() => {
+                        *self = BodyDecoder::Length(0);
+                        return Ok(BodyPart::End);
+                    }
+                    // We have no use for trailer fields.
+                    Some(_) => {}
+                },
+            }
+        }
+    }
+}
+
+/// Take a CRLF-terminated line out of `buffer`, without the CRLF.
+fn take_line(buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>, ParseError> {
+    match buffer.windows(2).position(|window| window == b"\r\n") {
+        Some(end) => {
+            let line = buffer[..end].to_vec();
+            buffer.drain(..end + 2);
+            Ok(Some(line))
+        }
+        None if buffer.len() > MAX_LINE_SIZE => Err(ParseError::InvalidChunk),
+        None => Ok(None),
+    }
+}
+
+type Piece = io::Result<Vec<u8>>;
+
+/// The body of a request, as a stream of the pieces it arrives in.
+///
+/// If the body turns out to be malformed or too large, the stream's last
+/// item is an error.
+pub struct RequestBody {
+    pieces: Option<mpsc::Receiver<Piece>>,
+}
+
+impl RequestBody {
+    pub fn empty() -> Self {
+        RequestBody { pieces: None }
+    }
+
+    /// A body whose pieces are sent through the returned sender.
+    pub fn channel() -> (mpsc::Sender<Piece>, RequestBody) {
+        let (sender, receiver) = mpsc::channel(0);
+        let body = RequestBody {
+            pieces: Some(receiver),
+        };
+        (sender, body)
+    }
+
+    /// Wait for the whole body.
+    pub async fn into_bytes(mut self) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        while let Some(piece) = self.next().await {
+            bytes.extend_from_slice(&piece?);
+        }
+        Ok(bytes)
+    }
+}
+
+impl Default for RequestBody {
+    fn default() -> Self {
+        RequestBody::empty()
+    }
+}
+
+impl Stream for RequestBody {
+    type Item = Piece;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Piece>> {
+        match &mut self.pieces {
+            Some(pieces) => pieces.poll_next_unpin(cx),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+impl fmt::Debug for RequestBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RequestBody")
+    }
+}
+
+/// Read the body of the request `parser` last returned, passing it on to
+/// `sender` piece by piece. Fails once more than `config.max_body_size`
+/// bytes of it have arrived, or if the client stops sending it for longer
+/// than `config.body_timeout`.
+///
+/// If the receiving `RequestBody` is dropped, the rest of the body is read
+/// and thrown away, to get to the next request.
+pub async fn read_body(
+    stream: &mut (impl Read + Unpin),
+    parser: &mut RequestParser,
+    config: &Config,
+    sender: mpsc::Sender<Piece>,
+) -> Result<(), ServerError> {
+    let mut sender = Some(sender);
+    let mut received = 0;
+    let result: Result<(), ServerError> = loop {
+        match parser.parse_body() {
+            Ok(BodyPart::Data(data)) => {
+                received += data.len() as u64;
+                if received > config.max_body_size {
+                    break Err(ParseError::BodyTooLarge.into());
+                }
+                if let Some(pieces) = &mut sender {
+                    if pieces.feed(Ok(data)).await.is_err() {
+                        sender = None;
+                    }
+                }
+            }
+            Ok(BodyPart::Incomplete) => {
+                match timeout(config.body_timeout, fill(stream, parser)).await {
+                    Ok(Ok(0)) => break Err(ParseError::UnexpectedEof.into()),
+                    Ok(Ok(_)) => {}
+                    Ok(Err(error)) => break Err(error.into()),
+                    Err(_) => break Err(ServerError::Timeout(Phase::Body)),
+                }
+            }
+            Ok(BodyPart::End) => break Ok(()),
+            Err(error) => break Err(error.into()),
+        }
+    };
+    if let (Err(error), Some(pieces)) = (&result, &mut sender) {
+        let _ = pieces.feed(Err(error.to_io_error())).await;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(
+        mut decoder: BodyDecoder,
+        bytes: &[u8],
+    ) -> Result<(Vec<u8>, Vec<u8>), ParseError> {
+        let mut buffer = bytes.to_vec();
+        let mut body = Vec::new();
+        loop {
+            match decoder.decode(&mut buffer)? {
+                BodyPart::Data(data) => body.extend(data),
+                BodyPart::Incomplete => return Err(ParseError::UnexpectedEof),
+                BodyPart::End => return Ok((body, buffer)),
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_chunked_bodies() {
+        let bytes = b"5\r\nhello\r\n7;name=value\r\n, world\r\n0\r\nExpires: never\r\n\r\nNEXT";
+        let (body, rest) = decode_all(BodyDecoder::ChunkSize, bytes).unwrap();
+        assert_eq!(body, b"hello, world");
+        assert_eq!(rest, b"NEXT");
+    }
+
+    #[test]
+    fn decodes_chunked_bodies_fed_byte_by_byte() {
+        let bytes = b"A\r\n0123456789\r\n0\r\n\r\n";
+        let mut decoder = BodyDecoder::ChunkSize;
+        let mut buffer = Vec::new();
+        let mut body = Vec::new();
+        let mut ended_after = None;
+        for (i, byte) in bytes.iter().enumerate() {
+            buffer.push(*byte);
+            loop {
+                match decoder.decode(&mut buffer).unwrap() {
+                    BodyPart::Data(data) => body.extend(data),
+                    BodyPart::Incomplete => break,
+                    BodyPart::End => {
+                        ended_after = Some(i + 1);
+                        break;
+                    }
+                }
+            }
+        }
+        assert_eq!(ended_after, Some(bytes.len()));
+        assert_eq!(body, b"0123456789");
+    }
+
+    #[test]
+    fn stops_at_the_content_length() {
+        let (body, rest) = decode_all(BodyDecoder::Length(3), b"abcdef").unwrap();
+        assert_eq!((body, rest), (b"abc".to_vec(), b"def".to_vec()));
+    }
+
+    #[test]
+    fn rejects_malformed_chunks() {
+        for bytes in [
+            &b"x\r\n"[..],
+            b"\r\n",
+            b"5\r\nhelloX\r\n0\r\n\r\n",
+            b"ffffffffffffffffff\r\n",
+        ] {
+            assert_eq!(
+                decode_all(BodyDecoder::ChunkSize, bytes),
+                Err(ParseError::InvalidChunk),
+                "{:?}",
+                bytes
+            );
+        }
+    }
+}
@@ -0,0 +1,261 @@
+//! Building HTTP/1.1 responses.
+//!
+//! Without a `Content-Length`, the only way for a client to tell where a
+//! response body ends is the server closing the connection. A `Response`
+//! fills in `Content-Length` from its body when it's written out, along
+//! with `Date` and, if the handler didn't set one, a `Content-Type`. A
+//! streamed body of unknown length is sent chunked instead (see `body`).
+
+use std::marker::Unpin;
+use std::time::SystemTime;
+
+use async_std::io::{self, Write};
+use async_std::prelude::*;
+
+use crate::body::Body;
+use crate::headers::Headers;
+use crate::request::Version;
+use crate::upgrade::OnUpgrade;
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Headers,
+    pub body: Body,
+    /// What takes the connection over once a `101 Switching Protocols`
+    /// has been sent.
+    pub upgrade: Option<OnUpgrade>,
+}
+
+impl Response {
+    /// An empty response with the given status code.
+    pub fn new(status: u16) -> Self {
+        Response {
+            status,
+            headers: Headers::new(),
+            body: Body::empty(),
+            upgrade: None,
+        }
+    }
+
+    /// A response with a plain text body.
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        Response::new(status).with_body("text/plain; charset=utf-8", body.into())
+    }
+
+    /// Set a header, replacing any previous value.
+    pub fn with_header(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn with_body(self, content_type: &str, body: impl Into<Body>) -> Self {
+        Response {
+            body: body.into(),
+            ..self.with_header("Content-Type", content_type)
+        }
+    }
+
+    /// Hand the connection over to `on_upgrade` after sending the response,
+    /// if it's a `101 Switching Protocols` to an HTTP/1.1 client.
+    pub fn with_upgrade(mut self, on_upgrade: OnUpgrade) -> Self {
+        self.upgrade = Some(on_upgrade);
+        self
+    }
+
+    /// The header fields the response goes out with to a client speaking
+    /// `version`, including those filled in for it.
+    pub fn fields(&self, version: Version) -> Headers {
+        let mut headers = self.headers.clone();
+        if !headers.contains("Date") {
+            headers.insert("Date", httpdate::fmt_http_date(SystemTime::now()));
+        }
+        if let Body::Bytes(bytes) = &self.body {
+            if !bytes.is_empty() && !headers.contains("Content-Type") {
+                headers.insert("Content-Type", guess_content_type(bytes));
+            }
+        }
+        // These never have a body, and a `Content-Length` on a 304 would
+        // describe the body of the 200 it stands for.
+        if !(self.status < 200 || self.status == 204 || self.status == 304) {
+            match self.body.len() {
+                Some(length) => headers.insert("Content-Length", length.to_string()),
+                None if version == Version::Http11 => {
+                    headers.insert("Transfer-Encoding", "chunked")
+                }
+                // HTTP/1.0 clients don't understand chunked bodies; this one
+                // ends when the connection does. HTTP/2 frames bodies itself.
+                None => {}
+            }
+        }
+        headers
+    }
+
+    /// The status line and header section, including the blank line that
+    /// ends it.
+    fn head(&self, version: Version) -> String {
+        format!(
+            "HTTP/1.1 {} {}\r\n{}\r\n",
+            self.status,
+            reason_phrase(self.status),
+            self.fields(version)
+        )
+    }
+
+    /// Serialize the response onto `stream`.
+    pub async fn write_to(self, stream: &mut (impl Write + Unpin)) -> io::Result<()> {
+        self.write_for(Version::Http11, stream).await?;
+        Ok(())
+    }
+
+    /// Serialize the response for a client speaking `version`, returning
+    /// the length of its body. For HTTP/1.0, a body of unknown length is
+    /// sent as is, and the caller has to close the connection after it.
+    pub async fn write_for(
+        self,
+        version: Version,
+        stream: &mut (impl Write + Unpin),
+    ) -> io::Result<u64> {
+        let mut head = self.head(version).into_bytes();
+        let length = match self.body {
+            // Small enough to go out in one write.
+            Body::Bytes(bytes) => {
+                head.extend_from_slice(&bytes);
+                stream.write_all(&head).await?;
+                bytes.len() as u64
+            }
+            body => {
+                stream.write_all(&head).await?;
+                body.write_to(stream, version == Version::Http11).await?
+            }
+        };
+        stream.flush().await?;
+        Ok(length)
+    }
+}
+
+/// The standard reason phrase for `status`.
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        416 => "Range Not Satisfiable",
+        426 => "Upgrade Required",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+/// A `Content-Type` for a body that the handler didn't give one.
+fn guess_content_type(body: &[u8]) -> &'static str {
+    match std::str::from_utf8(body) {
+        Ok(text) => {
+            let start = text.trim_start().as_bytes();
+            let starts_with = |prefix: &[u8]| {
+                start.len() >= prefix.len() && start[..prefix.len()].eq_ignore_ascii_case(prefix)
+            };
+            if starts_with(b"<!doctype html") || starts_with(b"<html") {
+                "text/html; charset=utf-8"
+            } else {
+                "text/plain; charset=utf-8"
+            }
+        }
+        Err(_) => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn written(response: Response) -> String {
+        let mut bytes = Vec::new();
+        async_std::task::block_on(response.write_to(&mut bytes)).unwrap();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    #[test]
+    fn fills_in_length_date_and_type() {
+        let written = written(Response::new(200).with_body("text/css", "p {}"));
+        let (head, body) = written.split_once("\r\n\r\n").unwrap();
+        let mut lines = head.split("\r\n");
+
+        assert_eq!(lines.next(), Some("HTTP/1.1 200 OK"));
+        let mut fields: Vec<_> = lines.collect();
+        fields.sort();
+        assert_eq!(fields.len(), 3);
+        assert_eq!(fields[0], "Content-Length: 4");
+        assert_eq!(fields[1], "Content-Type: text/css");
+        assert!(fields[2].starts_with("Date: "));
+        assert!(fields[2].ends_with(" GMT"));
+        assert_eq!(body, "p {}");
+    }
+
+    #[test]
+    fn guesses_missing_content_types() {
+        let html = Response {
+            body: b"<!DOCTYPE html><html></html>"[..].into(),
+            ..Response::new(200)
+        };
+        assert!(written(html).contains("Content-Type: text/html; charset=utf-8\r\n"));
+
+        let binary = Response {
+            body: vec![0xff, 0xfe].into(),
+            ..Response::new(200)
+        };
+        assert!(written(binary).contains("Content-Type: application/octet-stream\r\n"));
+    }
+
+    #[test]
+    fn empty_responses_have_zero_length() {
+        let written = written(Response::new(404));
+        assert!(written.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(written.contains("Content-Length: 0\r\n"));
+        assert!(!written.contains("Content-Type"));
+    }
+
+    #[test]
+    fn bodiless_statuses_have_no_length() {
+        for status in [101, 204, 304] {
+            assert!(!written(Response::new(status)).contains("Content-Length"));
+        }
+    }
+
+    #[test]
+    fn streams_bodies_of_unknown_length_chunked() {
+        let body = Body::from_reader(&b"hello, world"[..], None);
+        let written = written(Response::new(200).with_body("text/plain", body));
+        let (head, body) = written.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("Transfer-Encoding: chunked"));
+        assert!(!head.contains("Content-Length"));
+        assert_eq!(body, "c\r\nhello, world\r\n0\r\n\r\n");
+    }
+
+    #[test]
+    fn streams_bodies_of_known_length_as_is() {
+        let body = Body::from_reader(&b"hello, world"[..], Some(5));
+        let written = written(Response::new(200).with_body("text/plain", body));
+        let (head, body) = written.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("Content-Length: 5"));
+        assert_eq!(body, "hello");
+    }
+}
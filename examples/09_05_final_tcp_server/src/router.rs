@@ -0,0 +1,299 @@
+//! Dispatching requests to handlers by method and path.
+//!
+//! A route's pattern is a path whose segments are matched one by one:
+//!
+//! * `users` only matches `users`,
+//! * `:id` matches any one segment, which the handler can then look up with
+//!   `request.param("id")`,
+//! * `*` or `*name` (only as the last segment) matches the rest of the
+//!   path, possibly nothing at all, storing it under `name` if given.
+//!
+//! So `/files/*path` matches `/files/css/site.css` with `path` set to
+//! `css/site.css`. Routes are tried in the order they were added.
+
+use std::future::Future;
+
+use futures::future::{BoxFuture, FutureExt};
+
+use crate::error::HandlerError;
+use crate::middleware::{Middleware, Next};
+use crate::request::{Params, Request};
+use crate::response::Response;
+
+/// What a handler produces: a response, or an error to answer with a 500.
+pub type HandlerResult = Result<Response, HandlerError>;
+
+type Handler = Box<dyn Fn(Request) -> BoxFuture<'static, HandlerResult> + Send + Sync>;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Rest(Option<String>),
+}
+
+struct Route {
+    method: String,
+    pattern: Vec<Segment>,
+    handler: Handler,
+}
+
+pub struct Router {
+    routes: Vec<Route>,
+    not_found: Handler,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Router::new()
+    }
+}
+
+impl Router {
+    /// A router without routes, answering everything with a 404.
+    pub fn new() -> Self {
+        Router {
+            routes: Vec::new(),
+            not_found: boxed(|_| async { Ok(Response::text(404, "Not Found\n")) }),
+            middleware: Vec::new(),
+        }
+    }
+
+    /// Send `method` requests for paths matching `pattern` to `handler`.
+    ///
+    /// Panics if `pattern` has a wildcard anywhere but at the end.
+    pub fn route<F, Fut>(mut self, method: &str, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        let pattern = parse_pattern(pattern);
+        let wildcard = pattern
+            .iter()
+            .position(|segment| matches!(segment, Segment::Rest(_)));
+        assert!(
+            wildcard.is_none_or(|index| index == pattern.len() - 1),
+            "a wildcard must be the last segment of a route"
+        );
+        self.routes.push(Route {
+            method: method.to_string(),
+            pattern,
+            handler: boxed(handler),
+        });
+        self
+    }
+
+    pub fn get<F, Fut>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        self.route("GET", pattern, handler)
+    }
+
+    /// Answer requests that match no route with `handler` instead of a
+    /// plain 404.
+    pub fn not_found<F, Fut>(mut self, handler: F) -> Self
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        self.not_found = boxed(handler);
+        self
+    }
+
+    /// Run `middleware` around every request, inside any middleware added
+    /// before it.
+    pub fn wrap(mut self, middleware: impl Middleware) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Run `request` through the middleware to its handler.
+    pub async fn handle(&self, request: Request) -> HandlerResult {
+        Next::new(&self.middleware, self).run(request).await
+    }
+
+    /// Run the handler for `request`.
+    ///
+    /// If some route matches the path but none matches the method, the
+    /// answer is a 405 listing the methods that would have worked.
+    pub(crate) async fn dispatch(&self, mut request: Request) -> HandlerResult {
+        let mut allowed: Vec<&str> = Vec::new();
+        for route in &self.routes {
+            let params = match match_path(&route.pattern, request.path()) {
+                Some(params) => params,
+                None => continue,
+            };
+            if route.method == request.method {
+                request.params = params;
+                return (route.handler)(request).await;
+            }
+            if !allowed.contains(&route.method.as_str()) {
+                allowed.push(&route.method);
+            }
+        }
+        if allowed.is_empty() {
+            (self.not_found)(request).await
+        } else {
+            Ok(
+                Response::text(405, "Method Not Allowed\n")
+                    .with_header("Allow", allowed.join(", ")),
+            )
+        }
+    }
+}
+
+fn boxed<F, Fut>(handler: F) -> Handler
+where
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = HandlerResult> + Send + 'static,
+{
+    Box::new(move |request| handler(request).boxed())
+}
+
+/// The parts of `path` between slashes, skipping empty ones, so that
+/// `//a/b/` is the same as `/a/b`.
+pub(crate) fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    segments(pattern)
+        .map(|segment| {
+            if let Some(name) = segment.strip_prefix(':') {
+                Segment::Param(name.to_string())
+            } else if let Some(name) = segment.strip_prefix('*') {
+                Segment::Rest(Some(name.to_string()).filter(|name| !name.is_empty()))
+            } else {
+                Segment::Literal(segment.to_string())
+            }
+        })
+        .collect()
+}
+
+/// The parameters `path` binds if it matches `pattern`.
+fn match_path(pattern: &[Segment], path: &str) -> Option<Params> {
+    let mut params = Params::new();
+    let mut segments = segments(path);
+    for expected in pattern {
+        match expected {
+            Segment::Rest(name) => {
+                let rest = segments.collect::<Vec<_>>().join("/");
+                if let Some(name) = name {
+                    params.insert(name.clone(), rest);
+                }
+                return Some(params);
+            }
+            Segment::Literal(literal) => {
+                if segments.next()? != literal.as_str() {
+                    return None;
+                }
+            }
+            Segment::Param(name) => {
+                params.insert(name.clone(), segments.next()?.to_string());
+            }
+        }
+    }
+    match segments.next() {
+        Some(_) => None,
+        None => Some(params),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::RequestParser;
+
+    fn request(method: &str, target: &str) -> Request {
+        let mut parser = RequestParser::new();
+        parser.feed(format!("{} {} HTTP/1.1\r\n\r\n", method, target).as_bytes());
+        parser.parse().unwrap().unwrap()
+    }
+
+    fn matches(pattern: &str, path: &str) -> Option<Vec<(String, String)>> {
+        let params = match_path(&parse_pattern(pattern), path)?;
+        let mut params: Vec<_> = params.into_iter().collect();
+        params.sort();
+        Some(params)
+    }
+
+    fn param(name: &str, value: &str) -> (String, String) {
+        (name.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn matches_literals_params_and_wildcards() {
+        assert_eq!(matches("/", "/"), Some(vec![]));
+        assert_eq!(matches("/users", "/users/"), Some(vec![]));
+        assert_eq!(matches("/users", "/users/7"), None);
+        assert_eq!(
+            matches("/users/:id", "/users/7"),
+            Some(vec![param("id", "7")])
+        );
+        assert_eq!(matches("/users/:id", "/users"), None);
+        assert_eq!(
+            matches("/users/:id/posts/:post", "/users/7/posts/12"),
+            Some(vec![param("id", "7"), param("post", "12")])
+        );
+        assert_eq!(
+            matches("/files/*path", "/files/css/site.css"),
+            Some(vec![param("path", "css/site.css")])
+        );
+        assert_eq!(
+            matches("/files/*path", "/files"),
+            Some(vec![param("path", "")])
+        );
+        assert_eq!(matches("/*", "/anything/at/all"), Some(vec![]));
+        assert_eq!(matches("/files/*", "/other"), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "wildcard must be the last segment")]
+    fn rejects_wildcards_in_the_middle() {
+        Router::new().get("/*/edit", |_| async { Ok(Response::new(200)) });
+    }
+
+    #[async_std::test]
+    async fn dispatches_on_method_and_path() {
+        let router = Router::new()
+            .get("/users/:id", |request| async move {
+                Ok(Response::text(
+                    200,
+                    format!("user {}", request.param("id").unwrap()),
+                ))
+            })
+            .route("POST", "/users", |_| async { Ok(Response::new(201)) })
+            .not_found(|request| async move {
+                Ok(Response::text(404, format!("no {}", request.path())))
+            });
+
+        let response = router
+            .handle(request("GET", "/users/42?full=1"))
+            .await
+            .unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body.into_bytes().await.unwrap(), b"user 42");
+
+        let response = router.handle(request("POST", "/users")).await.unwrap();
+        assert_eq!(response.status, 201);
+
+        let response = router.handle(request("GET", "/teams/1")).await.unwrap();
+        assert_eq!(response.status, 404);
+        assert_eq!(response.body.into_bytes().await.unwrap(), b"no /teams/1");
+    }
+
+    #[async_std::test]
+    async fn lists_allowed_methods_on_405() {
+        let router = Router::new()
+            .get("/users/:id", |_| async { Ok(Response::new(200)) })
+            .route("PUT", "/users/:id", |_| async { Ok(Response::new(200)) })
+            .route("DELETE", "/users/*", |_| async { Ok(Response::new(200)) });
+
+        let response = router.handle(request("POST", "/users/1")).await.unwrap();
+        assert_eq!(response.status, 405);
+        assert_eq!(response.headers.get("Allow"), Some("GET, PUT, DELETE"));
+    }
+}
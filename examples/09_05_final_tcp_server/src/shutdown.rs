@@ -0,0 +1,141 @@
+//! Stopping the server cleanly.
+//!
+//! Once asked to stop, by SIGINT or SIGTERM or by calling
+//! `Shutdown::trigger`, the server stops accepting connections and closes
+//! each open one as soon as it has answered the request it's working on,
+//! or straight away if it's waiting for the next one. Connections still
+//! going when `Config::shutdown_timeout` runs out are closed regardless.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// A one-off event that any number of tasks can wait for.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    triggered: bool,
+    /// The tasks waiting, by the key of their `Wait`.
+    waiting: HashMap<u64, Waker>,
+    next_key: u64,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Shutdown::default()
+    }
+
+    /// Wake everyone waiting. Triggering it again does nothing.
+    pub fn trigger(&self) {
+        let waiting = {
+            let mut state = self.state.lock().unwrap();
+            state.triggered = true;
+            std::mem::take(&mut state.waiting)
+        };
+        for waker in waiting.into_values() {
+            waker.wake();
+        }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.state.lock().unwrap().triggered
+    }
+
+    /// Wait for `trigger` to be called, returning at once if it has been.
+    pub fn wait(&self) -> Wait {
+        Wait {
+            shutdown: self.clone(),
+            key: None,
+        }
+    }
+
+    /// Trigger on SIGINT or SIGTERM.
+    #[cfg(unix)]
+    pub fn on_signals(&self) -> std::io::Result<()> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+        use signal_hook::iterator::Signals;
+
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        let shutdown = self.clone();
+        // Waiting for a signal blocks, so it gets a thread of its own.
+        std::thread::spawn(move || {
+            if let Some(signal) = signals.forever().next() {
+                eprintln!("received signal {}, shutting down", signal);
+                shutdown.trigger();
+            }
+        });
+        Ok(())
+    }
+
+    /// Without Unix signals, there's only `trigger`.
+    #[cfg(not(unix))]
+    pub fn on_signals(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Future returned by `Shutdown::wait`.
+pub struct Wait {
+    shutdown: Shutdown,
+    key: Option<u64>,
+}
+
+impl Future for Wait {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let shutdown = self.shutdown.clone();
+        let mut state = shutdown.state.lock().unwrap();
+        if state.triggered {
+            return Poll::Ready(());
+        }
+        let key = *self.key.get_or_insert_with(|| {
+            state.next_key += 1;
+            state.next_key
+        });
+        state.waiting.insert(key, cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for Wait {
+    // Connections wait for the shutdown once per request, and shouldn't
+    // leave a waker behind each time.
+    fn drop(&mut self) {
+        if let Some(key) = self.key {
+            self.shutdown.state.lock().unwrap().waiting.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::future::timeout;
+    use async_std::task;
+    use std::time::Duration;
+
+    #[async_std::test]
+    async fn wakes_everyone_waiting() {
+        let shutdown = Shutdown::new();
+        let waiting: Vec<_> = (0..3).map(|_| task::spawn(shutdown.wait())).collect();
+        task::sleep(Duration::from_millis(50)).await;
+        assert!(!shutdown.is_triggered());
+
+        shutdown.trigger();
+        for wait in waiting {
+            timeout(Duration::from_secs(1), wait).await.unwrap();
+        }
+        assert!(shutdown.state.lock().unwrap().waiting.is_empty());
+        // Late arrivals don't wait at all.
+        timeout(Duration::from_secs(1), shutdown.wait())
+            .await
+            .unwrap();
+    }
+}
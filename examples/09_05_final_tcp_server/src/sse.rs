@@ -0,0 +1,261 @@
+//! Server-Sent Events: a response that stays open, sending events as
+//! they happen.
+//!
+//! The body is `text/event-stream`, a series of events, each a few
+//! `field: value` lines followed by a blank line:
+//!
+//! ```text
+//! event: tick
+//! id: 3
+//! data: three
+//!
+//! ```
+//!
+//! Lines starting with a colon are comments, which clients ignore. When
+//! there's nothing to send for a while we send one anyway, so that
+//! proxies don't give up on the connection as idle, and so that we find
+//! out when the client has gone away: the write fails, the response ends,
+//! and the stream of events is dropped. For a channel, that means the
+//! sending end gets an error.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_std::io::{self, Read};
+use async_std::task;
+use futures::future::BoxFuture;
+use futures::stream::{BoxStream, Stream, StreamExt};
+use futures::FutureExt;
+
+use crate::body::Body;
+use crate::response::Response;
+
+/// How long we go without sending anything before sending a comment.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// What a keep-alive looks like on the wire.
+const KEEP_ALIVE_COMMENT: &[u8] = b": keep-alive\n\n";
+
+/// One event. Only `data` is required.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Event {
+    /// What the client's `EventSource` dispatches the event as, `message`
+    /// if not given.
+    pub event: Option<String>,
+    /// May run over several lines.
+    pub data: String,
+    /// What a reconnecting client sends back as `Last-Event-ID`.
+    pub id: Option<String>,
+    /// How long the client should wait before reconnecting.
+    pub retry: Option<Duration>,
+}
+
+impl Event {
+    pub fn new(data: impl Into<String>) -> Self {
+        Event {
+            data: data.into(),
+            ..Event::default()
+        }
+    }
+
+    pub fn with_event(mut self, event: impl Into<String>) -> Self {
+        self.event = Some(event.into());
+        self
+    }
+
+    pub fn with_id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// The event as it goes on the wire. A line break in `event` or `id`
+    /// would start a new field, so they lose theirs.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = String::new();
+        let one_line = |value: &str| value.replace(['\r', '\n'], "");
+        if let Some(event) = &self.event {
+            out += &format!("event: {}\n", one_line(event));
+        }
+        if let Some(id) = &self.id {
+            out += &format!("id: {}\n", one_line(id));
+        }
+        if let Some(retry) = self.retry {
+            out += &format!("retry: {}\n", retry.as_millis());
+        }
+        for line in self.data.replace("\r\n", "\n").split(['\r', '\n']) {
+            out += &format!("data: {}\n", line);
+        }
+        out += "\n";
+        out.into_bytes()
+    }
+}
+
+/// A response that sends `events` as they come, until they run out or
+/// the client goes away.
+pub struct Sse {
+    events: BoxStream<'static, Event>,
+    keep_alive: Duration,
+}
+
+impl Sse {
+    pub fn new(events: impl Stream<Item = Event> + Send + 'static) -> Self {
+        Sse {
+            events: events.boxed(),
+            keep_alive: KEEP_ALIVE,
+        }
+    }
+
+    /// Send a comment after `interval` without an event, rather than the
+    /// default 15 seconds.
+    pub fn with_keep_alive(mut self, interval: Duration) -> Self {
+        self.keep_alive = interval;
+        self
+    }
+
+    pub fn into_response(self) -> Response {
+        let reader = EventReader {
+            events: self.events,
+            keep_alive: self.keep_alive,
+            timer: None,
+            unread: Vec::new(),
+        };
+        Response::new(200)
+            .with_header("Cache-Control", "no-cache")
+            .with_body("text/event-stream", Body::from_reader(reader, None))
+    }
+}
+
+/// The body of an `Sse` response, read one event (or keep-alive) at a
+/// time, as each comes.
+struct EventReader {
+    events: BoxStream<'static, Event>,
+    keep_alive: Duration,
+    /// Started when we run out of events to send, and dropped when the
+    /// next one comes.
+    timer: Option<BoxFuture<'static, ()>>,
+    /// What has been encoded, but not read.
+    unread: Vec<u8>,
+}
+
+impl Read for EventReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.unread.is_empty() {
+            match this.events.poll_next_unpin(cx) {
+                Poll::Ready(Some(event)) => {
+                    this.timer = None;
+                    this.unread = event.to_bytes();
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => {
+                    let keep_alive = this.keep_alive;
+                    let timer = this
+                        .timer
+                        .get_or_insert_with(|| task::sleep(keep_alive).boxed());
+                    match timer.as_mut().poll(cx) {
+                        Poll::Ready(()) => {
+                            this.timer = None;
+                            this.unread = KEEP_ALIVE_COMMENT.to_vec();
+                        }
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+            }
+        }
+        let size = this.unread.len().min(buf.len());
+        buf[..size].copy_from_slice(&this.unread[..size]);
+        this.unread.drain(..size);
+        Poll::Ready(Ok(size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Version;
+    use async_std::prelude::*;
+    use futures::channel::mpsc;
+    use futures::future;
+    use futures::sink::SinkExt;
+    use futures::stream;
+    use mock_stream::MockStream;
+
+    #[test]
+    fn encodes_events() {
+        let event = Event::new("one\ntwo\r\nthree")
+            .with_event("count")
+            .with_id("3\n");
+        assert_eq!(
+            event.to_bytes(),
+            b"event: count\nid: 3\ndata: one\ndata: two\ndata: three\n\n"
+        );
+        let event = Event {
+            retry: Some(Duration::from_secs(2)),
+            ..Event::new("")
+        };
+        assert_eq!(event.to_bytes(), b"retry: 2000\ndata: \n\n");
+    }
+
+    #[async_std::test]
+    async fn sends_events_as_they_come() {
+        let (mut sender, events) = mpsc::channel(0);
+        let response = Sse::new(events).into_response();
+        assert_eq!(
+            response.headers.get("content-type"),
+            Some("text/event-stream")
+        );
+        assert_eq!(response.headers.get("cache-control"), Some("no-cache"));
+
+        let mut stream = MockStream::new();
+        let send = async {
+            sender.send(Event::new("one")).await.unwrap();
+            sender.send(Event::new("two")).await.unwrap();
+            sender.close_channel();
+        };
+        let (written, ()) =
+            future::join(response.write_for(Version::Http11, &mut stream), send).await;
+        written.unwrap();
+        let written = String::from_utf8(stream.written().to_vec()).unwrap();
+        assert!(written.contains("\r\nTransfer-Encoding: chunked\r\n"));
+        assert!(written.ends_with("\r\n\r\nb\r\ndata: one\n\n\r\nb\r\ndata: two\n\n\r\n0\r\n\r\n"));
+    }
+
+    #[async_std::test]
+    async fn keeps_quiet_connections_alive() {
+        let response = Sse::new(stream::pending())
+            .with_keep_alive(Duration::from_millis(10))
+            .into_response();
+        let mut reader = match response.body {
+            Body::Reader { reader, .. } => reader,
+            body => panic!("{:?}", body),
+        };
+        let mut buffer = [0; 64];
+        for _ in 0..2 {
+            let size = reader.read(&mut buffer).await.unwrap();
+            assert_eq!(&buffer[..size], KEEP_ALIVE_COMMENT);
+        }
+    }
+
+    #[async_std::test]
+    async fn drops_the_events_when_the_client_goes_away() {
+        let (mut sender, events) = mpsc::channel(0);
+        let response = Sse::new(events).into_response();
+        let mut stream = MockStream::new().write_error_after(200, io::ErrorKind::ConnectionReset);
+        let send = async {
+            let mut sent = 0;
+            while sender.send(Event::new("again")).await.is_ok() {
+                sent += 1;
+            }
+            sent
+        };
+        let (written, sent) =
+            future::join(response.write_for(Version::Http11, &mut stream), send).await;
+        assert_eq!(written.unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+        assert!(sent > 0);
+    }
+}
@@ -0,0 +1,454 @@
+//! Serving the files under a directory.
+//!
+//! The path a client asks for is untrusted: `/../../etc/passwd`, or the
+//! same with the dots percent-encoded, must not get out of the root. So
+//! the path is decoded and checked segment by segment before it's joined
+//! onto the root, and the file it names is checked to still be inside the
+//! root once symbolic links have been resolved.
+//!
+//! A missing file is answered with the root's `404.html`, if it has one.
+//!
+//! Responses carry an `ETag` and `Last-Modified`, so that a client that
+//! has the file cached can ask for it conditionally and get a `304` back,
+//! and `Range` requests for a single range of bytes are answered with a
+//! `206`. Files are streamed rather than read into memory first.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_std::fs;
+use async_std::io::{self, SeekFrom};
+use async_std::prelude::*;
+
+use crate::body::Body;
+use crate::request::Request;
+use crate::response::Response;
+
+#[derive(Clone, Debug)]
+pub struct StaticFiles {
+    root: PathBuf,
+}
+
+impl StaticFiles {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        StaticFiles { root: root.into() }
+    }
+
+    /// Answer `request` with the file at `path`, relative to the root.
+    /// A directory is answered with the `index.html` inside it.
+    ///
+    /// Fails on errors other than the file not existing or not being
+    /// readable, such as the root itself missing.
+    pub async fn serve(&self, request: &Request, path: &str) -> io::Result<Response> {
+        let mut file = match resolve(&self.root, path) {
+            Some(file) => file,
+            None => return Ok(forbidden()),
+        };
+        if fs::metadata(&file)
+            .await
+            .map(|m| m.is_dir())
+            .unwrap_or(false)
+        {
+            file.push("index.html");
+        }
+        // Only now that we know the file exists can we check where its
+        // symbolic links lead.
+        let root = fs::canonicalize(&self.root).await?;
+        let canonical = match fs::canonicalize(&file).await {
+            Ok(canonical) => canonical,
+            Err(error) => return self.failed(error).await,
+        };
+        if !canonical.starts_with(&root) {
+            return Ok(forbidden());
+        }
+        let metadata = match fs::metadata(&canonical).await {
+            Ok(metadata) if metadata.is_file() => metadata,
+            Ok(_) => return Ok(self.not_found().await),
+            Err(error) => return self.failed(error).await,
+        };
+
+        // HTTP dates only have whole seconds.
+        let modified_secs = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|since_epoch| since_epoch.as_secs());
+        let modified = modified_secs.map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+        let etag = format!("\"{:x}-{:x}\"", metadata.len(), modified_secs.unwrap_or(0));
+        let mut response = Response::new(200)
+            .with_header("ETag", etag.clone())
+            .with_header("Accept-Ranges", "bytes");
+        if let Some(modified) = modified {
+            response = response.with_header("Last-Modified", httpdate::fmt_http_date(modified));
+        }
+        if is_fresh(request, &etag, modified) {
+            response.status = 304;
+            return Ok(response);
+        }
+
+        let mut file = match fs::File::open(&canonical).await {
+            Ok(file) => file,
+            Err(error) => return self.failed(error).await,
+        };
+        let content_type = content_type(Path::new(canonical.as_os_str()));
+        let length = metadata.len();
+        let response = match request
+            .headers
+            .get("range")
+            .map(|range| parse_range(range, length))
+        {
+            None | Some(RangeRequest::Ignored) => {
+                response.with_body(content_type, Body::from_reader(file, Some(length)))
+            }
+            Some(RangeRequest::Satisfiable(start, end)) => {
+                file.seek(SeekFrom::Start(start)).await?;
+                response.status = 206;
+                response
+                    .with_header(
+                        "Content-Range",
+                        format!("bytes {}-{}/{}", start, end, length),
+                    )
+                    .with_body(content_type, Body::from_reader(file, Some(end - start + 1)))
+            }
+            Some(RangeRequest::Unsatisfiable) => {
+                Response::new(416).with_header("Content-Range", format!("bytes */{}", length))
+            }
+        };
+        Ok(response)
+    }
+
+    /// Answer with a 404 or 403 if that's what `error` amounts to.
+    async fn failed(&self, error: io::Error) -> io::Result<Response> {
+        match error.kind() {
+            io::ErrorKind::NotFound => Ok(self.not_found().await),
+            io::ErrorKind::PermissionDenied => Ok(forbidden()),
+            _ => Err(error),
+        }
+    }
+
+    /// A 404 with the root's `404.html` as its body, or a plain text one if
+    /// there's no such page.
+    pub async fn not_found(&self) -> Response {
+        match fs::read(self.root.join("404.html")).await {
+            Ok(page) => Response::new(404).with_body("text/html; charset=utf-8", page),
+            Err(_) => Response::text(404, "Not Found\n"),
+        }
+    }
+}
+
+fn forbidden() -> Response {
+    Response::text(403, "Forbidden\n")
+}
+
+/// The file `path` names under `root`, or `None` if it tries to leave it.
+fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let mut file = root.to_path_buf();
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        let segment = percent_decode(segment)?;
+        if segment == "."
+            || segment == ".."
+            || segment.contains(['/', '\\', '\0'])
+            || Path::new(&segment).is_absolute()
+        {
+            return None;
+        }
+        file.push(segment);
+    }
+    Some(file)
+}
+
+fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Whether the client's cached copy, described by its conditional
+/// headers, is still up to date. `If-None-Match` takes precedence over
+/// `If-Modified-Since`.
+fn is_fresh(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    if let Some(tags) = request.headers.get("if-none-match") {
+        return tags
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag);
+    }
+    match (request.headers.get("if-modified-since"), modified) {
+        (Some(since), Some(modified)) => {
+            httpdate::parse_http_date(since).is_ok_and(|since| modified <= since)
+        }
+        _ => false,
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum RangeRequest {
+    /// The first and last byte to send.
+    Satisfiable(u64, u64),
+    Unsatisfiable,
+    /// Not a single byte range; the whole file is sent instead.
+    Ignored,
+}
+
+fn parse_range(header: &str, length: u64) -> RangeRequest {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return RangeRequest::Ignored,
+    };
+    let (first, last) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return RangeRequest::Ignored,
+    };
+    let parse = |bound: &str| bound.parse::<u64>().ok();
+    let (start, end) = match (first, last) {
+        // The last `n` bytes.
+        ("", last) => match parse(last) {
+            Some(0) => return RangeRequest::Unsatisfiable,
+            Some(n) => (length.saturating_sub(n), length.saturating_sub(1)),
+            None => return RangeRequest::Ignored,
+        },
+        (first, "") => match parse(first) {
+            Some(start) => (start, length.saturating_sub(1)),
+            None => return RangeRequest::Ignored,
+        },
+        (first, last) => match (parse(first), parse(last)) {
+            (Some(start), Some(end)) if start <= end => (start, end.min(length.saturating_sub(1))),
+            _ => return RangeRequest::Ignored,
+        },
+    };
+    if start >= length {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Satisfiable(start, end)
+    }
+}
+
+/// The `Content-Type` for a file, going by its extension.
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "mp4" => "video/mp4",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::RequestParser;
+
+    /// A fresh directory for one test, removed when dropped.
+    struct TempRoot(PathBuf);
+
+    impl TempRoot {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "final-tcp-server-{}-{}",
+                std::process::id(),
+                name
+            ));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(path.join("docs")).unwrap();
+            std::fs::write(path.join("index.html"), "<html>home</html>").unwrap();
+            std::fs::write(path.join("docs/index.html"), "<html>docs</html>").unwrap();
+            std::fs::write(path.join("logo.png"), [0x89, b'P', b'N', b'G', 0, 0xff]).unwrap();
+            TempRoot(path)
+        }
+    }
+
+    impl Drop for TempRoot {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn get(path: &str, headers: &str) -> Request {
+        let mut parser = RequestParser::new();
+        parser.feed(format!("GET {} HTTP/1.1\r\n{}\r\n", path, headers).as_bytes());
+        parser.parse().unwrap().unwrap()
+    }
+
+    async fn serve(root: &TempRoot, path: &str, headers: &str) -> Response {
+        let request = get(path, headers);
+        StaticFiles::new(&root.0)
+            .serve(&request, request.path().trim_start_matches('/'))
+            .await
+            .unwrap()
+    }
+
+    #[async_std::test]
+    async fn serves_files_and_directory_indexes() {
+        let root = TempRoot::new("serves");
+
+        let response = serve(&root, "/logo.png", "").await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.headers.get("Content-Type"), Some("image/png"));
+        assert_eq!(
+            response.body.into_bytes().await.unwrap(),
+            [0x89, b'P', b'N', b'G', 0, 0xff]
+        );
+
+        let response = serve(&root, "/docs/", "").await;
+        assert_eq!(
+            response.body.into_bytes().await.unwrap(),
+            b"<html>docs</html>"
+        );
+        assert_eq!(
+            response.headers.get("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+
+        let response = serve(&root, "/missing.css", "").await;
+        assert_eq!(response.status, 404);
+        assert_eq!(response.body.into_bytes().await.unwrap(), b"Not Found\n");
+        std::fs::write(root.0.join("404.html"), "<html>gone</html>").unwrap();
+        assert_eq!(
+            serve(&root, "/missing.css", "")
+                .await
+                .body
+                .into_bytes()
+                .await
+                .unwrap(),
+            b"<html>gone</html>"
+        );
+    }
+
+    #[async_std::test]
+    async fn streams_large_files() {
+        let root = TempRoot::new("large");
+        let contents: Vec<u8> = (0..5 * 1024 * 1024).map(|i| (i % 253) as u8).collect();
+        std::fs::write(root.0.join("large.bin"), &contents).unwrap();
+
+        let response = serve(&root, "/large.bin", "").await;
+        assert!(matches!(response.body, Body::Reader { .. }));
+        let mut written = Vec::new();
+        response.write_to(&mut written).await.unwrap();
+
+        let head_end = written.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8_lossy(&written[..head_end]);
+        assert!(head.contains("Content-Length: 5242880\r\n"));
+        assert!(head.contains("Content-Type: application/octet-stream\r\n"));
+        assert!(written[head_end..] == contents[..]);
+    }
+
+    #[async_std::test]
+    async fn refuses_to_leave_the_root() {
+        let root = TempRoot::new("traversal");
+        std::fs::write(root.0.with_extension("secret"), "secret").unwrap();
+        let secret = format!("{}.secret", root.0.file_name().unwrap().to_str().unwrap());
+
+        for path in [
+            format!("/../{}", secret),
+            format!("/docs/../../{}", secret),
+            format!("/%2e%2e/{}", secret),
+            format!("/docs/..%2f..%2f{}", secret),
+            "/%2fetc%2fpasswd".to_string(),
+        ] {
+            let response = serve(&root, &path, "").await;
+            assert_eq!(response.status, 403, "{}", path);
+        }
+        std::fs::remove_file(root.0.with_extension("secret")).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[async_std::test]
+    async fn refuses_symlinks_out_of_the_root() {
+        let root = TempRoot::new("symlink");
+        std::os::unix::fs::symlink("/etc", root.0.join("etc")).unwrap();
+        assert_eq!(serve(&root, "/etc/hostname", "").await.status, 403);
+    }
+
+    #[async_std::test]
+    async fn answers_conditional_requests() {
+        let root = TempRoot::new("conditional");
+        let response = serve(&root, "/index.html", "").await;
+        let etag = response.headers.get("ETag").unwrap().to_string();
+        let modified = response.headers.get("Last-Modified").unwrap().to_string();
+
+        let response = serve(
+            &root,
+            "/index.html",
+            &format!("If-None-Match: {}\r\n", etag),
+        )
+        .await;
+        assert_eq!(response.status, 304);
+        assert_eq!(response.body.len(), Some(0));
+        let response = serve(&root, "/index.html", "If-None-Match: \"other\"\r\n").await;
+        assert_eq!(response.status, 200);
+
+        let since = format!("If-Modified-Since: {}\r\n", modified);
+        assert_eq!(serve(&root, "/index.html", &since).await.status, 304);
+        let since = "If-Modified-Since: Thu, 01 Jan 1970 00:00:00 GMT\r\n";
+        assert_eq!(serve(&root, "/index.html", since).await.status, 200);
+    }
+
+    #[async_std::test]
+    async fn answers_range_requests() {
+        let root = TempRoot::new("range");
+        let range = |range: &'static str| format!("Range: bytes={}\r\n", range);
+
+        let response = serve(&root, "/index.html", &range("6-9")).await;
+        assert_eq!(response.status, 206);
+        assert_eq!(response.body.into_bytes().await.unwrap(), b"home");
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes 6-9/17"));
+
+        assert_eq!(
+            serve(&root, "/index.html", &range("-7"))
+                .await
+                .body
+                .into_bytes()
+                .await
+                .unwrap(),
+            b"</html>"
+        );
+        assert_eq!(
+            serve(&root, "/index.html", &range("10-"))
+                .await
+                .body
+                .into_bytes()
+                .await
+                .unwrap(),
+            b"</html>"
+        );
+        assert_eq!(
+            serve(&root, "/index.html", &range("0-0,5-6")).await.status,
+            200
+        );
+
+        let response = serve(&root, "/index.html", &range("17-")).await;
+        assert_eq!(response.status, 416);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes */17"));
+    }
+}
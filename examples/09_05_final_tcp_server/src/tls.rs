@@ -0,0 +1,254 @@
+//! HTTPS, with rustls. Only built with the `tls` feature.
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::marker::Unpin;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use async_std::io::{Read, Write};
+use futures_rustls::rustls::crypto::ring;
+use futures_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use futures_rustls::rustls::ServerConfig;
+use futures_rustls::server::TlsStream;
+use futures_rustls::TlsAcceptor;
+
+use crate::access_log::Peer;
+
+/// What we tell clients we speak during the handshake, best first.
+/// Clients that can speak HTTP/2 will, if we say we can.
+#[cfg(feature = "http2")]
+const PROTOCOLS: [&[u8]; 2] = [b"h2", b"http/1.1"];
+#[cfg(not(feature = "http2"))]
+const PROTOCOLS: [&[u8]; 1] = [b"http/1.1"];
+
+/// What we need to turn an accepted connection into an encrypted one.
+#[derive(Clone)]
+pub struct Tls {
+    acceptor: TlsAcceptor,
+}
+
+impl Tls {
+    /// Use the certificate chain in the PEM file `cert`, leaf first, and
+    /// the private key in the PEM file `key`.
+    pub fn from_pem_files(cert: &Path, key: &Path) -> io::Result<Self> {
+        let open = |path: &Path| {
+            File::open(path).map(BufReader::new).map_err(|error| {
+                io::Error::new(error.kind(), format!("{}: {}", path.display(), error))
+            })
+        };
+        let certs = rustls_pemfile::certs(&mut open(cert)?).collect::<Result<Vec<_>, _>>()?;
+        if certs.is_empty() {
+            return Err(invalid(format!("no certificates in {}", cert.display())));
+        }
+        let key = rustls_pemfile::private_key(&mut open(key)?)?
+            .ok_or_else(|| invalid(format!("no private key in {}", key.display())))?;
+        Tls::new(certs, key)
+    }
+
+    pub fn new(
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> io::Result<Self> {
+        let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(invalid)?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(invalid)?;
+        config.alpn_protocols = PROTOCOLS.iter().map(|protocol| protocol.to_vec()).collect();
+        Ok(Tls {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+
+    /// Do the handshake with the client on the other end of `stream`.
+    pub async fn accept<S: Read + Write + Unpin>(&self, stream: S) -> io::Result<TlsStream<S>> {
+        self.acceptor.accept(stream).await
+    }
+}
+
+impl<S: Peer> Peer for TlsStream<S> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().0.peer_addr()
+    }
+}
+
+fn invalid(error: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::prelude::*;
+    use futures::future;
+    use futures_rustls::rustls::pki_types::ServerName;
+    use futures_rustls::rustls::{ClientConfig, RootCertStore};
+    use futures_rustls::TlsConnector;
+    use mock_stream::duplex;
+    use std::convert::TryFrom;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use crate::{handle_connection, server};
+
+    /// A certificate for `localhost`, signed by itself, in PEM files of
+    /// its own, named after `test`.
+    struct SelfSigned {
+        cert: PathBuf,
+        key: PathBuf,
+        der: CertificateDer<'static>,
+    }
+
+    impl SelfSigned {
+        fn new(test: &str) -> Self {
+            let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+            let dir = std::env::temp_dir();
+            let name = format!("final-tcp-server-{}-{}", std::process::id(), test);
+            let cert = dir.join(format!("{}.crt", name));
+            let key = dir.join(format!("{}.key", name));
+            fs::write(&cert, certified.cert.pem()).unwrap();
+            fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+            SelfSigned {
+                cert,
+                key,
+                der: certified.cert.der().clone(),
+            }
+        }
+
+        /// A client that trusts the certificate, and nothing else, and
+        /// offers to speak `protocols`.
+        fn connector(&self, protocols: &[&[u8]]) -> TlsConnector {
+            let mut roots = RootCertStore::empty();
+            roots.add(self.der.clone()).unwrap();
+            let mut config =
+                ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                    .with_safe_default_protocol_versions()
+                    .unwrap()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth();
+            config.alpn_protocols = protocols.iter().map(|protocol| protocol.to_vec()).collect();
+            TlsConnector::from(Arc::new(config))
+        }
+    }
+
+    impl Drop for SelfSigned {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.cert);
+            let _ = fs::remove_file(&self.key);
+        }
+    }
+
+    #[async_std::test]
+    async fn serves_requests_over_tls() {
+        let certificate = SelfSigned::new("serves");
+        let tls = Tls::from_pem_files(&certificate.cert, &certificate.key).unwrap();
+        let (client, server_end) = duplex(1024);
+
+        let serve = async {
+            let stream = tls.accept(server_end).await.unwrap();
+            handle_connection(&server(), stream).await;
+        };
+        let talk = async {
+            let name = ServerName::try_from("localhost").unwrap();
+            let mut stream = certificate
+                .connector(&[])
+                .connect(name, client)
+                .await
+                .unwrap();
+            stream
+                .write_all(b"GET /hello/you HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            // Which fails if the server hangs up without saying so.
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let ((), response) = future::join(serve, talk).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nHello, you!\n"));
+    }
+
+    #[cfg(feature = "http2")]
+    #[async_std::test]
+    async fn agrees_on_http2() {
+        use bytes::Bytes;
+        use tokio_util::compat::FuturesAsyncReadCompatExt;
+
+        let certificate = SelfSigned::new("agrees");
+        let tls = Tls::from_pem_files(&certificate.cert, &certificate.key).unwrap();
+        let (client, server_end) = duplex(16 * 1024);
+        async_std::task::spawn(async move {
+            let stream = tls.accept(server_end).await.unwrap();
+            handle_connection(&server(), stream).await;
+        });
+
+        let name = ServerName::try_from("localhost").unwrap();
+        let connector = certificate.connector(&[b"h2", b"http/1.1"]);
+        let stream = connector.connect(name, client).await.unwrap();
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+        let (client, connection) = h2::client::handshake(stream.compat()).await.unwrap();
+        async_std::task::spawn(connection);
+        let request = http::Request::get("https://localhost/hello/you")
+            .body(())
+            .unwrap();
+        let (response, _) = client
+            .ready()
+            .await
+            .unwrap()
+            .send_request(request, true)
+            .unwrap();
+        let (head, mut body) = response.await.unwrap().into_parts();
+        assert_eq!(head.status, 200);
+        let data = body.data().await.unwrap().unwrap();
+        assert_eq!(data, Bytes::from("Hello, you!\n"));
+    }
+
+    #[async_std::test]
+    async fn refuses_clients_that_dont_trust_us() {
+        let certificate = SelfSigned::new("refuses");
+        let other = SelfSigned::new("refuses-other");
+        let tls = Tls::from_pem_files(&certificate.cert, &certificate.key).unwrap();
+        let (client, server_end) = duplex(1024);
+        let name = ServerName::try_from("localhost").unwrap();
+        let (accepted, connected) = future::join(
+            tls.accept(server_end),
+            other.connector(&[]).connect(name, client),
+        )
+        .await;
+        assert!(accepted.is_err());
+        assert!(connected.is_err());
+    }
+
+    #[test]
+    fn reports_bad_pem_files() {
+        let certificate = SelfSigned::new("bad");
+        let missing = Path::new("/nonexistent/server.key");
+        let error = Tls::from_pem_files(&certificate.cert, missing)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(error.to_string().contains("server.key"));
+
+        // The key where the certificate should be, and the other way round.
+        let error = Tls::from_pem_files(&certificate.key, &certificate.key)
+            .err()
+            .unwrap();
+        assert!(
+            error.to_string().starts_with("no certificates in "),
+            "{}",
+            error
+        );
+        let error = Tls::from_pem_files(&certificate.cert, &certificate.cert)
+            .err()
+            .unwrap();
+        assert!(
+            error.to_string().starts_with("no private key in "),
+            "{}",
+            error
+        );
+    }
+}
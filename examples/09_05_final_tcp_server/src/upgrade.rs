@@ -0,0 +1,198 @@
+//! Handing a connection over to a handler, after a `101 Switching
+//! Protocols` response, for it to speak some other protocol on.
+//!
+//! A connection may be borrowed, as it is in tests, so the handler can't
+//! have it outright. It gets an `Upgraded` instead, whose reads and
+//! writes are passed to and from the connection for as long as the
+//! handler keeps it.
+
+use std::fmt;
+use std::future::Future;
+use std::marker::Unpin;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_std::io::{self, Read, Write};
+use async_std::prelude::*;
+use futures::channel::mpsc;
+use futures::future::{self, BoxFuture, Either, FutureExt};
+use futures::pin_mut;
+use futures::sink::{Sink, SinkExt};
+use futures::stream::Stream;
+
+use crate::error::ServerError;
+
+/// How much we read from the connection at once.
+const READ_SIZE: usize = 8 * 1024;
+
+/// What a response takes the connection over with, once it's been sent.
+pub struct OnUpgrade(Box<dyn FnOnce(Upgraded) -> BoxFuture<'static, ()> + Send>);
+
+impl OnUpgrade {
+    pub fn new<F, Fut>(handler: F) -> Self
+    where
+        F: FnOnce(Upgraded) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        OnUpgrade(Box::new(move |upgraded| handler(upgraded).boxed()))
+    }
+}
+
+impl fmt::Debug for OnUpgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OnUpgrade")
+    }
+}
+
+/// The connection, as a handler that has taken it over sees it.
+///
+/// Reading gets whatever the client sends, and the end of the stream once
+/// it hangs up. Closing it, or dropping it, closes the connection.
+pub struct Upgraded {
+    incoming: mpsc::Receiver<Vec<u8>>,
+    /// What has arrived, but hasn't been read.
+    unread: Vec<u8>,
+    outgoing: mpsc::Sender<Vec<u8>>,
+}
+
+impl Read for Upgraded {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.unread.is_empty() {
+            match Pin::new(&mut self.incoming).poll_next(cx) {
+                Poll::Ready(Some(bytes)) => self.unread = bytes,
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let size = self.unread.len().min(buf.len());
+        buf[..size].copy_from_slice(&self.unread[..size]);
+        self.unread.drain(..size);
+        Poll::Ready(Ok(size))
+    }
+}
+
+impl Write for Upgraded {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.outgoing.poll_ready(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(_)) => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            Poll::Pending => return Poll::Pending,
+        }
+        match self.outgoing.start_send(buf.to_vec()) {
+            Ok(()) => Poll::Ready(Ok(buf.len())),
+            Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.outgoing)
+            .poll_flush(cx)
+            .map_err(|_| io::ErrorKind::BrokenPipe.into())
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.outgoing.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Let `on_upgrade` have the connection `reader` and `writer` are the two
+/// halves of, starting with the `buffered` bytes already read from it,
+/// until it's done with it or the client hangs up.
+pub async fn serve(
+    mut reader: impl Read + Unpin,
+    buffered: Vec<u8>,
+    mut writer: impl Write + Unpin,
+    on_upgrade: OnUpgrade,
+) -> Result<(), ServerError> {
+    // With no room in the channels, the handler reading slowly slows down
+    // reading from the client, and writing to the client slows down the
+    // handler.
+    let (mut to_handler, incoming) = mpsc::channel(0);
+    let (outgoing, mut from_handler) = mpsc::channel::<Vec<u8>>(0);
+    let upgraded = Upgraded {
+        incoming,
+        unread: Vec::new(),
+        outgoing,
+    };
+
+    // Taking `to_handler` along means the handler reads the end of the
+    // stream once this is done.
+    let read = async move {
+        let mut bytes = buffered;
+        let mut buffer = vec![0; READ_SIZE];
+        loop {
+            // The handler not wanting any more is fine.
+            if !bytes.is_empty() && to_handler.send(bytes).await.is_err() {
+                return Ok(());
+            }
+            let size = reader.read(&mut buffer).await?;
+            if size == 0 {
+                return Ok(());
+            }
+            bytes = buffer[..size].to_vec();
+        }
+    };
+    let write = async {
+        while let Some(bytes) = from_handler.next().await {
+            writer.write_all(&bytes).await?;
+            writer.flush().await?;
+        }
+        futures::io::AsyncWriteExt::close(&mut writer).await
+    };
+    let handler = (on_upgrade.0)(upgraded);
+    let talk = future::join(handler, write);
+    pin_mut!(read, talk);
+    // Once the client has hung up, the handler still gets to finish, and
+    // once the handler is done, so are we.
+    let (read, written) = match future::select(read, talk).await {
+        Either::Left((read, talk)) => (read, talk.await.1),
+        Either::Right((((), written), _)) => (Ok(()), written),
+    };
+    read.and(written).map_err(ServerError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock_stream::MockStream;
+
+    #[async_std::test]
+    async fn passes_bytes_both_ways() {
+        let mut stream = MockStream::new().send(b"world").send(b"!");
+        let (reader, writer) = futures::io::AsyncReadExt::split(&mut stream);
+        let on_upgrade = OnUpgrade::new(|mut upgraded: Upgraded| async move {
+            let mut read = String::new();
+            upgraded.read_to_string(&mut read).await.unwrap();
+            upgraded
+                .write_all(format!("hello, {}", read).as_bytes())
+                .await
+                .unwrap();
+        });
+        serve(reader, b"to the ".to_vec(), writer, on_upgrade)
+            .await
+            .unwrap();
+        assert_eq!(stream.written(), b"hello, to the world!");
+        assert!(stream.is_closed());
+    }
+
+    #[async_std::test]
+    async fn ends_when_the_handler_is_done() {
+        // The client never hangs up, but the handler doesn't wait for it.
+        let mut stream = MockStream::new().hang();
+        let (reader, writer) = futures::io::AsyncReadExt::split(&mut stream);
+        let on_upgrade = OnUpgrade::new(|mut upgraded: Upgraded| async move {
+            upgraded.write_all(b"bye").await.unwrap();
+        });
+        serve(reader, Vec::new(), writer, on_upgrade).await.unwrap();
+        assert_eq!(stream.written(), b"bye");
+    }
+}
@@ -0,0 +1,749 @@
+//! WebSockets (RFC 6455): the handshake that turns an HTTP/1.1 request
+//! into one, and the framing of the messages sent either way after it.
+//!
+//! Each message goes in one or more frames, with a header of two to
+//! fourteen bytes:
+//!
+//! ```text
+//! +-+-+-+-+-------+-+-------------+-------------------------------+
+//! |F|R|R|R| opcode|M| length      | longer length, if length is   |
+//! |I|S|S|S|       |A| (7 bits)    | 126 (16 bits) or 127 (64 bits)|
+//! |N|V|V|V|       |S|             |                               |
+//! | |1|2|3|       |K|             |                               |
+//! +-+-+-+-+-------+-+-------------+-------------------------------+
+//! | masking key, if MASK is set (32 bits) | payload ...           |
+//! +---------------------------------------+-----------------------+
+//! ```
+//!
+//! `FIN` marks a message's last frame. Everything a client sends is
+//! masked, XORed with a key of its choosing, and nothing a server sends
+//! is. Pings, pongs and closes are control frames, which can come in
+//! between the frames of a message, but can't be split up themselves.
+
+use std::collections::hash_map::RandomState;
+use std::convert::TryInto;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::marker::Unpin;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_std::io::{self, Read, Write};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures::ready;
+use futures::sink::Sink;
+use futures::stream::Stream;
+use sha1::{Digest, Sha1};
+
+use crate::request::{Request, Version};
+use crate::response::Response;
+use crate::upgrade::{OnUpgrade, Upgraded};
+
+/// What the client's key is combined with for the handshake.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The largest message we accept, however many frames it comes in.
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+/// The largest frame we send. Longer messages are split up.
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+/// How much we read at once.
+const READ_SIZE: usize = 8 * 1024;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+/// Status codes for closing a WebSocket with.
+pub const NORMAL_CLOSURE: u16 = 1000;
+pub const PROTOCOL_ERROR: u16 = 1002;
+pub const INVALID_DATA: u16 = 1007;
+pub const MESSAGE_TOO_BIG: u16 = 1009;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// Answered with a `Pong` for us.
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The last message either way. Receiving one sends one back, if we
+    /// haven't sent ours already.
+    Close(Option<CloseFrame>),
+}
+
+/// Why a WebSocket is being closed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+/// Which end of the connection we are, which says who masks frames.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Server,
+    Client,
+}
+
+/// Answer `request`, if it asks to open a WebSocket, by handing the
+/// connection over to `handler` once the client has had the answer.
+/// Requests that don't ask properly get a 4xx instead.
+pub fn accept<F, Fut>(request: &Request, handler: F) -> Response
+where
+    F: FnOnce(WebSocket<Upgraded>) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let headers = &request.headers;
+    // HTTP/2 has no `Upgrade`, and HTTP/1.0 no way to keep a connection.
+    let upgrading = request.version == Version::Http11
+        && headers.has_token("upgrade", "websocket")
+        && headers.has_token("connection", "upgrade");
+    if !upgrading || headers.get("sec-websocket-version") != Some("13") {
+        return Response::text(426, "Upgrade Required\n")
+            .with_header("Upgrade", "websocket")
+            .with_header("Sec-WebSocket-Version", "13");
+    }
+    let key = headers
+        .get("sec-websocket-key")
+        .filter(|key| BASE64.decode(key).is_ok_and(|key| key.len() == 16));
+    let key = match key {
+        Some(key) if request.method == "GET" => key,
+        _ => return Response::text(400, "Bad Request\n"),
+    };
+    Response::new(101)
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", accept_key(key))
+        .with_upgrade(OnUpgrade::new(move |upgraded| {
+            handler(WebSocket::new(upgraded, Role::Server))
+        }))
+}
+
+/// The `Sec-WebSocket-Accept` that shows we understood `key`.
+fn accept_key(key: &str) -> String {
+    let mut hash = Sha1::new();
+    hash.update(key.as_bytes());
+    hash.update(GUID.as_bytes());
+    BASE64.encode(hash.finalize())
+}
+
+/// A WebSocket over `S`: a stream of the messages arriving, and a sink
+/// for those to send.
+///
+/// Pings are answered, and closes echoed, as they're read. They're sent
+/// along with the next message, or when the socket is flushed or closed,
+/// so a handler should close it when it's done. Only the latest ping is
+/// answered if several arrive before we get to write: a peer that pings
+/// without reading would otherwise have the pongs pile up.
+pub struct WebSocket<S> {
+    stream: S,
+    role: Role,
+    /// What has been read, but doesn't make up a whole frame yet.
+    read_buffer: Vec<u8>,
+    /// The kind of message whose frames are still arriving, and the data
+    /// they've brought so far.
+    partial: Option<(u8, Vec<u8>)>,
+    /// Frames waiting to be written.
+    write_buffer: Vec<u8>,
+    /// What the latest ping is to be answered with, once `write_buffer`
+    /// has been written.
+    pending_pong: Option<Vec<u8>>,
+    close_sent: bool,
+    /// Nothing more is read once the other end closes, or breaks the
+    /// protocol.
+    done_reading: bool,
+}
+
+impl<S> WebSocket<S> {
+    /// A WebSocket over a connection that has already had its handshake.
+    pub fn new(stream: S, role: Role) -> Self {
+        WebSocket {
+            stream,
+            role,
+            read_buffer: Vec::new(),
+            partial: None,
+            write_buffer: Vec::new(),
+            pending_pong: None,
+            close_sent: false,
+            done_reading: false,
+        }
+    }
+
+    /// Make sense of a frame, returning the message it completes, if any.
+    fn receive(&mut self, frame: Frame) -> Result<Option<Message>, Violation> {
+        match frame.opcode {
+            PING | PONG | CLOSE if !frame.fin || frame.payload.len() > 125 => {
+                Err((PROTOCOL_ERROR, "control frame split up, or too long"))
+            }
+            PING => {
+                if !self.close_sent {
+                    self.pending_pong = Some(frame.payload.clone());
+                }
+                Ok(Some(Message::Ping(frame.payload)))
+            }
+            PONG => Ok(Some(Message::Pong(frame.payload))),
+            CLOSE => {
+                let close = parse_close(&frame.payload)?;
+                if !self.close_sent {
+                    // Echo the status code, as is customary.
+                    self.queue(true, CLOSE, &frame.payload[..frame.payload.len().min(2)]);
+                    self.close_sent = true;
+                }
+                self.done_reading = true;
+                Ok(Some(Message::Close(close)))
+            }
+            TEXT | BINARY | CONTINUATION => {
+                let data = match (&mut self.partial, frame.opcode) {
+                    (Some((_, data)), CONTINUATION) => data,
+                    (None, CONTINUATION) => {
+                        return Err((PROTOCOL_ERROR, "continuation of no message"))
+                    }
+                    (Some(_), _) => {
+                        return Err((PROTOCOL_ERROR, "new message in the middle of one"))
+                    }
+                    (partial, opcode) => &mut partial.insert((opcode, Vec::new())).1,
+                };
+                if data.len() + frame.payload.len() > MAX_MESSAGE_SIZE {
+                    return Err((MESSAGE_TOO_BIG, "message too big"));
+                }
+                data.extend_from_slice(&frame.payload);
+                if !frame.fin {
+                    return Ok(None);
+                }
+                match self.partial.take() {
+                    Some((TEXT, data)) => match String::from_utf8(data) {
+                        Ok(text) => Ok(Some(Message::Text(text))),
+                        Err(_) => Err((INVALID_DATA, "text that isn't UTF-8")),
+                    },
+                    Some((_, data)) => Ok(Some(Message::Binary(data))),
+                    None => unreachable!("a message was started above"),
+                }
+            }
+            _ => Err((PROTOCOL_ERROR, "unknown opcode")),
+        }
+    }
+
+    /// Queue `message`, in as many frames as it takes.
+    fn queue_message(&mut self, message: Message) -> io::Result<()> {
+        let (opcode, payload) = match message {
+            Message::Text(text) => (TEXT, text.into_bytes()),
+            Message::Binary(data) => (BINARY, data),
+            Message::Ping(data) => (PING, data),
+            Message::Pong(data) => (PONG, data),
+            Message::Close(close) => {
+                let payload = match close {
+                    Some(close) => {
+                        let mut payload = close.code.to_be_bytes().to_vec();
+                        payload.extend_from_slice(close.reason.as_bytes());
+                        payload
+                    }
+                    None => Vec::new(),
+                };
+                self.close_sent = true;
+                (CLOSE, payload)
+            }
+        };
+        if opcode >= CLOSE {
+            if payload.len() > 125 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "control frames carry 125 bytes at most",
+                ));
+            }
+            self.queue(true, opcode, &payload);
+            return Ok(());
+        }
+        let mut frames = payload.chunks(MAX_FRAME_SIZE).peekable();
+        let mut opcode = opcode;
+        if frames.peek().is_none() {
+            self.queue(true, opcode, &[]);
+        }
+        while let Some(frame) = frames.next() {
+            let fin = frames.peek().is_none();
+            self.queue(fin, opcode, frame);
+            opcode = CONTINUATION;
+        }
+        Ok(())
+    }
+
+    /// Add a frame to the ones waiting to be written.
+    fn queue(&mut self, fin: bool, opcode: u8, payload: &[u8]) {
+        let out = &mut self.write_buffer;
+        out.push(if fin { 0x80 } else { 0 } | opcode);
+        let masked = if self.role == Role::Client { 0x80 } else { 0 };
+        match payload.len() {
+            length if length < 126 => out.push(masked | length as u8),
+            length if length <= u16::MAX as usize => {
+                out.push(masked | 126);
+                out.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                out.push(masked | 127);
+                out.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        let start = out.len();
+        if self.role == Role::Client {
+            let key = masking_key();
+            out.extend_from_slice(&key);
+            out.extend_from_slice(payload);
+            apply_mask(&mut out[start + 4..], key);
+        } else {
+            out.extend_from_slice(payload);
+        }
+    }
+}
+
+impl<S: Write + Unpin> WebSocket<S> {
+    /// Give up on the other end for breaking the protocol, telling it why
+    /// if we still can.
+    fn fail(&mut self, (code, reason): Violation, cx: &mut Context<'_>) -> io::Error {
+        if !self.close_sent {
+            let mut payload = code.to_be_bytes().to_vec();
+            payload.extend_from_slice(reason.as_bytes());
+            self.queue(true, CLOSE, &payload);
+            self.close_sent = true;
+        }
+        self.done_reading = true;
+        self.partial = None;
+        let _ = self.poll_write_buffer(cx);
+        io::Error::new(io::ErrorKind::InvalidData, reason)
+    }
+
+    fn poll_write_buffer(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            if self.write_buffer.is_empty() {
+                match self.pending_pong.take() {
+                    // Nothing goes after a close.
+                    Some(payload) if !self.close_sent => self.queue(true, PONG, &payload),
+                    _ => return Poll::Ready(Ok(())),
+                }
+            }
+            let written = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.write_buffer))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buffer.drain(..written);
+        }
+    }
+}
+
+impl<S: Read + Write + Unpin> Stream for WebSocket<S> {
+    type Item = io::Result<Message>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            // Get any pongs and closes we owe out of the way, without
+            // waiting for them.
+            if let Poll::Ready(Err(error)) = this.poll_write_buffer(cx) {
+                this.done_reading = true;
+                return Poll::Ready(Some(Err(error)));
+            }
+            if this.done_reading {
+                return Poll::Ready(None);
+            }
+            let frame = match parse_frame(&mut this.read_buffer, this.role) {
+                Ok(frame) => frame,
+                Err(violation) => return Poll::Ready(Some(Err(this.fail(violation, cx)))),
+            };
+            if let Some(frame) = frame {
+                match this.receive(frame) {
+                    Ok(Some(message)) => {
+                        // Whatever `receive` queued gets its chance to go out.
+                        let _ = this.poll_write_buffer(cx);
+                        return Poll::Ready(Some(Ok(message)));
+                    }
+                    // The rest of the message may have arrived already.
+                    Ok(None) => continue,
+                    Err(violation) => return Poll::Ready(Some(Err(this.fail(violation, cx)))),
+                }
+            }
+            let mut buffer = [0; READ_SIZE];
+            match ready!(Pin::new(&mut this.stream).poll_read(cx, &mut buffer)) {
+                Ok(0) => {
+                    this.done_reading = true;
+                    return Poll::Ready(Some(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed without a close frame",
+                    ))));
+                }
+                Ok(size) => this.read_buffer.extend_from_slice(&buffer[..size]),
+                Err(error) => {
+                    this.done_reading = true;
+                    return Poll::Ready(Some(Err(error)));
+                }
+            }
+        }
+    }
+}
+
+impl<S: Write + Unpin> Sink<Message> for WebSocket<S> {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Let frames pile up, but not without limit.
+        if self.write_buffer.len() >= MAX_FRAME_SIZE {
+            ready!(self.poll_write_buffer(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, message: Message) -> io::Result<()> {
+        if self.close_sent {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "the WebSocket has been closed",
+            ));
+        }
+        self.queue_message(message)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_buffer(cx))?;
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    /// Send a close, unless we have already, and close the connection.
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.close_sent {
+            let close = CloseFrame {
+                code: NORMAL_CLOSURE,
+                reason: String::new(),
+            };
+            self.queue_message(Message::Close(Some(close)))?;
+        }
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.stream).poll_close(cx)
+    }
+}
+
+/// A frame, unmasked.
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// How the other end broke the protocol: the status code to close with,
+/// and what to say.
+type Violation = (u16, &'static str);
+
+/// Take a frame out of `buffer`, if a whole one has arrived.
+fn parse_frame(buffer: &mut Vec<u8>, role: Role) -> Result<Option<Frame>, Violation> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+    let (first, second) = (buffer[0], buffer[1]);
+    if first & 0x70 != 0 {
+        return Err((PROTOCOL_ERROR, "reserved bits set"));
+    }
+    let masked = second & 0x80 != 0;
+    match (role, masked) {
+        (Role::Server, false) => return Err((PROTOCOL_ERROR, "unmasked frame from a client")),
+        (Role::Client, true) => return Err((PROTOCOL_ERROR, "masked frame from a server")),
+        _ => {}
+    }
+    let (length, mut start) = match second & 0x7f {
+        126 if buffer.len() >= 4 => (u16::from_be_bytes([buffer[2], buffer[3]]) as u64, 4),
+        127 if buffer.len() >= 10 => (u64::from_be_bytes(buffer[2..10].try_into().unwrap()), 10),
+        126 | 127 => return Ok(None),
+        length => (length as u64, 2),
+    };
+    if length > MAX_MESSAGE_SIZE as u64 {
+        return Err((MESSAGE_TOO_BIG, "message too big"));
+    }
+    let key = if masked {
+        if buffer.len() < start + 4 {
+            return Ok(None);
+        }
+        start += 4;
+        Some(buffer[start - 4..start].try_into().unwrap())
+    } else {
+        None
+    };
+    let end = start + length as usize;
+    if buffer.len() < end {
+        return Ok(None);
+    }
+    let mut payload = buffer[start..end].to_vec();
+    buffer.drain(..end);
+    if let Some(key) = key {
+        apply_mask(&mut payload, key);
+    }
+    Ok(Some(Frame {
+        fin: first & 0x80 != 0,
+        opcode: first & 0x0f,
+        payload,
+    }))
+}
+
+/// The status code and reason in a close frame's payload.
+fn parse_close(payload: &[u8]) -> Result<Option<CloseFrame>, Violation> {
+    let (code, reason) = match payload {
+        [] => return Ok(None),
+        [high, low, reason @ ..] => (u16::from_be_bytes([*high, *low]), reason),
+        [_] => return Err((PROTOCOL_ERROR, "close frame with half a status code")),
+    };
+    // The rest are reserved, or not for sending.
+    if !matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999) {
+        return Err((PROTOCOL_ERROR, "invalid close status code"));
+    }
+    match String::from_utf8(reason.to_vec()) {
+        Ok(reason) => Ok(Some(CloseFrame { code, reason })),
+        Err(_) => Err((INVALID_DATA, "close reason that isn't UTF-8")),
+    }
+}
+
+fn apply_mask(data: &mut [u8], key: [u8; 4]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= key[i % 4];
+    }
+}
+
+/// A key for masking a frame with. It should be hard to guess, and each
+/// `RandomState` hashes with keys of its own.
+fn masking_key() -> [u8; 4] {
+    let hash = RandomState::new().build_hasher().finish();
+    (hash as u32).to_be_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::RequestParser;
+    use futures::future;
+    use futures::sink::SinkExt;
+    use futures::stream::StreamExt;
+    use io::ErrorKind::{InvalidData, UnexpectedEof};
+    use mock_stream::{duplex, MockStream};
+
+    /// The handshake from RFC 6455.
+    const HANDSHAKE: &str = "GET /chat HTTP/1.1\r\n\
+                             Host: server.example.com\r\n\
+                             Upgrade: websocket\r\n\
+                             Connection: Upgrade\r\n\
+                             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                             Sec-WebSocket-Version: 13\r\n\r\n";
+
+    fn request(head: &str) -> Request {
+        let mut parser = RequestParser::new();
+        parser.feed(head.as_bytes());
+        parser.parse().unwrap().unwrap()
+    }
+
+    /// A frame as a client sends it, with a payload shorter than 126 bytes.
+    fn masked(first: u8, payload: &[u8]) -> Vec<u8> {
+        let key = [1, 2, 3, 4];
+        let mut frame = vec![first, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&key);
+        frame.extend_from_slice(payload);
+        apply_mask(&mut frame[6..], key);
+        frame
+    }
+
+    /// Everything a WebSocket playing `role` makes of `input`, and what it
+    /// writes back.
+    async fn receive(role: Role, input: &[u8]) -> (Vec<Result<Message, io::ErrorKind>>, Vec<u8>) {
+        let mut stream = MockStream::new().send(input);
+        let received = WebSocket::new(&mut stream, role)
+            .map(|message| message.map_err(|error| error.kind()))
+            .collect()
+            .await;
+        (received, stream.written().to_vec())
+    }
+
+    fn text(text: &str) -> Result<Message, io::ErrorKind> {
+        Ok(Message::Text(text.to_string()))
+    }
+
+    #[test]
+    fn accepts_handshakes() {
+        let response = accept(&request(HANDSHAKE), |_| async {});
+        assert_eq!(response.status, 101);
+        assert_eq!(response.headers.get("upgrade"), Some("websocket"));
+        assert_eq!(
+            response.headers.get("sec-websocket-accept"),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+        assert!(response.upgrade.is_some());
+    }
+
+    #[test]
+    fn turns_down_other_requests() {
+        let plain = accept(&request("GET /chat HTTP/1.1\r\n\r\n"), |_| async {});
+        assert_eq!(plain.status, 426);
+        assert_eq!(plain.headers.get("upgrade"), Some("websocket"));
+        assert_eq!(plain.headers.get("sec-websocket-version"), Some("13"));
+        assert!(plain.upgrade.is_none());
+        for (from, to, status) in [
+            ("HTTP/1.1", "HTTP/1.0", 426),
+            ("Version: 13", "Version: 8", 426),
+            ("dGhlIHNhbXBsZSBub25jZQ==", "c2hvcnQ=", 400),
+            ("dGhlIHNhbXBsZSBub25jZQ==", "not base64", 400),
+            ("GET", "POST", 400),
+        ] {
+            let response = accept(&request(&HANDSHAKE.replace(from, to)), |_| async {});
+            assert_eq!(response.status, status, "{}", to);
+            assert!(response.upgrade.is_none());
+        }
+    }
+
+    #[async_std::test]
+    async fn reads_the_frames_from_the_rfc() {
+        let hello = [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+        let (received, _) = receive(Role::Client, &hello).await;
+        assert_eq!(received, [text("Hello"), Err(UnexpectedEof)]);
+
+        let masked_hello = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let (received, _) = receive(Role::Server, &masked_hello).await;
+        assert_eq!(received, [text("Hello"), Err(UnexpectedEof)]);
+
+        // In two fragments, with a ping in between.
+        let mut fragmented = vec![0x01, 0x03, 0x48, 0x65, 0x6c];
+        fragmented.extend_from_slice(&[0x89, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f]);
+        fragmented.extend_from_slice(&[0x80, 0x02, 0x6c, 0x6f]);
+        let (received, written) = receive(Role::Client, &fragmented).await;
+        let ping = Ok(Message::Ping(b"Hello".to_vec()));
+        assert_eq!(received, [ping, text("Hello"), Err(UnexpectedEof)]);
+        // The pong, masked, since we're the client.
+        assert_eq!(written[..2], [0x8a, 0x85]);
+        let mut payload = written[6..].to_vec();
+        apply_mask(&mut payload, written[2..6].try_into().unwrap());
+        assert_eq!(payload, b"Hello");
+
+        // 256 bytes, then 64KiB, of binary data.
+        let mut long = vec![0x82, 0x7e, 0x01, 0x00];
+        long.extend_from_slice(&[1; 256]);
+        long.extend_from_slice(&[0x82, 0x7f, 0, 0, 0, 0, 0, 1, 0, 0]);
+        long.extend_from_slice(&[2; 65536]);
+        let (received, _) = receive(Role::Client, &long).await;
+        assert_eq!(
+            received,
+            [
+                Ok(Message::Binary(vec![1; 256])),
+                Ok(Message::Binary(vec![2; 65536])),
+                Err(UnexpectedEof)
+            ]
+        );
+    }
+
+    #[async_std::test]
+    async fn answers_closes() {
+        let mut close = vec![0x03, 0xe8];
+        close.extend_from_slice(b"bye");
+        let (received, written) = receive(Role::Server, &masked(0x88, &close)).await;
+        let close = CloseFrame {
+            code: NORMAL_CLOSURE,
+            reason: "bye".to_string(),
+        };
+        // Nothing is read after it.
+        assert_eq!(received, [Ok(Message::Close(Some(close)))]);
+        assert_eq!(written, [0x88, 0x02, 0x03, 0xe8]);
+    }
+
+    #[async_std::test]
+    async fn gives_up_on_protocol_violations() {
+        let too_long = [0x82, 0xff, 0, 0, 0, 0, 2, 0, 0, 0, 1, 2, 3, 4];
+        for (input, code) in [
+            (vec![0x81, 0x00], PROTOCOL_ERROR),
+            (masked(0xc1, b"x"), PROTOCOL_ERROR),
+            (masked(0x83, b"x"), PROTOCOL_ERROR),
+            (masked(0x80, b"x"), PROTOCOL_ERROR),
+            (masked(0x09, b"x"), PROTOCOL_ERROR),
+            (
+                [masked(0x01, b"x"), masked(0x81, b"y")].concat(),
+                PROTOCOL_ERROR,
+            ),
+            (masked(0x88, &[0x03, 0xe7]), PROTOCOL_ERROR),
+            (masked(0x81, &[0xc3, 0x28]), INVALID_DATA),
+            (too_long.to_vec(), MESSAGE_TOO_BIG),
+        ] {
+            let (received, written) = receive(Role::Server, &input).await;
+            assert_eq!(received, [Err(InvalidData)], "{:02x?}", input);
+            assert_eq!(written[0], 0x88);
+            assert_eq!(u16::from_be_bytes([written[2], written[3]]), code);
+        }
+    }
+
+    #[async_std::test]
+    async fn answers_only_the_latest_ping_when_writes_back_up() {
+        let pings: Vec<u8> = (0..1000u16)
+            .flat_map(|n| masked(0x89, n.to_string().as_bytes()))
+            .collect();
+        let mut stream = MockStream::new().send(&pings).stall_writes_after(0);
+        let mut socket = WebSocket::new(&mut stream, Role::Server);
+        let mut received = 0;
+        while let Some(Ok(_)) = socket.next().await {
+            received += 1;
+        }
+        assert_eq!(received, 1000);
+        // The first pong, stuck, and the one for the last ping waiting.
+        assert_eq!(socket.write_buffer, [0x8a, 0x01, b'0']);
+        assert_eq!(socket.pending_pong.as_deref(), Some(&b"999"[..]));
+    }
+
+    #[async_std::test]
+    async fn splits_up_long_messages() {
+        let mut stream = MockStream::new();
+        let mut socket = WebSocket::new(&mut stream, Role::Server);
+        socket
+            .send(Message::Binary(vec![0; 100_000]))
+            .await
+            .unwrap();
+        drop(socket);
+        let written = stream.written();
+        assert_eq!(written.len(), 10 + 65536 + 4 + 34464);
+        assert_eq!(written[..10], [0x02, 0x7f, 0, 0, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(written[65546..65550], [0x80, 0x7e, 0x86, 0xa0]);
+    }
+
+    #[async_std::test]
+    async fn talks_to_itself() {
+        let (client, server) = duplex(1024);
+        let mut client = WebSocket::new(client, Role::Client);
+        let mut server = WebSocket::new(server, Role::Server);
+        let big = vec![7; 200_000];
+
+        let talk = async {
+            client.send(Message::Text("hello".into())).await.unwrap();
+            client.send(Message::Binary(big.clone())).await.unwrap();
+            client.send(Message::Ping(b"?".to_vec())).await.unwrap();
+            let pong = client.next().await.unwrap().unwrap();
+            assert_eq!(pong, Message::Pong(b"?".to_vec()));
+            client.close().await.unwrap();
+            let close = CloseFrame {
+                code: NORMAL_CLOSURE,
+                reason: String::new(),
+            };
+            let echoed = client.next().await.unwrap().unwrap();
+            assert_eq!(echoed, Message::Close(Some(close.clone())));
+            assert!(client.next().await.is_none());
+            close
+        };
+        let listen = async {
+            let mut received = Vec::new();
+            while let Some(message) = server.next().await {
+                received.push(message.unwrap());
+            }
+            server.close().await.unwrap();
+            received
+        };
+        let (close, received) = future::join(talk, listen).await;
+        assert_eq!(
+            received,
+            [
+                Message::Text("hello".into()),
+                Message::Binary(big),
+                Message::Ping(b"?".to_vec()),
+                Message::Close(Some(close)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,78 @@
+//! Giving up on clients that stop reading.
+//!
+//! A response can take as long as it likes to send, as long as it keeps
+//! moving: a large file going to a slow client is fine, a client that has
+//! stopped reading altogether isn't. So rather than a deadline for the
+//! whole response, each write gets `timeout` to make progress.
+
+use std::marker::Unpin;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_std::io::{self, Write};
+use async_std::task;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+
+/// Wraps a writer, failing with `TimedOut` any write, flush or close that
+/// stalls for longer than `timeout`.
+pub struct WriteTimeout<W> {
+    inner: W,
+    timeout: Duration,
+    /// Started when the inner writer first has nothing for us, and
+    /// dropped as soon as it makes progress.
+    timer: Option<BoxFuture<'static, ()>>,
+}
+
+impl<W: Write + Unpin> WriteTimeout<W> {
+    pub fn new(inner: W, timeout: Duration) -> Self {
+        WriteTimeout {
+            inner,
+            timeout,
+            timer: None,
+        }
+    }
+
+    fn check<T>(&mut self, cx: &mut Context<'_>, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        if poll.is_ready() {
+            self.timer = None;
+            return poll;
+        }
+        let timeout = self.timeout;
+        let timer = self
+            .timer
+            .get_or_insert_with(|| task::sleep(timeout).boxed());
+        match timer.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                self.timer = None;
+                Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "timed out writing the response",
+                )))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<W: Write + Unpin> Write for WriteTimeout<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.check(cx, poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_flush(cx);
+        self.check(cx, poll)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_close(cx);
+        self.check(cx, poll)
+    }
+}
@@ -0,0 +1,48 @@
+//! Talking to the server with the client from `http_client`.
+
+use http_client::Client;
+
+mod common;
+
+use common::Server;
+
+#[async_std::test]
+async fn answers_a_client_over_one_connection() {
+    let server = Server::start(&[]);
+    let client = Client::new();
+
+    let response = client.get(&server.url("/hello/you")).send().await.unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.text(), "Hello, you!\n");
+
+    let response = client
+        .post(&server.url("/hello"))
+        .body("there")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.text(), "Hello, there!\n");
+
+    // Sent chunked, and streamed back the same way.
+    let response = client
+        .post(&server.url("/echo"))
+        .header("Content-Type", "text/plain")
+        .body_reader(&b"echo, echo"[..])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.header("transfer-encoding"), Some("chunked"));
+    assert_eq!(response.text(), "echo, echo");
+
+    let response = client.get(&server.url("/missing")).send().await.unwrap();
+    assert_eq!(response.status, 404);
+
+    // Everything so far went over the one connection, which is still open.
+    let response = client.get(&server.url("/stats")).send().await.unwrap();
+    assert!(
+        response.text().starts_with("connections: 1\npeak: 1\n"),
+        "{}",
+        response.text()
+    );
+    assert_eq!(client.idle_connections(), 1);
+}
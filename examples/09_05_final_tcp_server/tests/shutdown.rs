@@ -0,0 +1,75 @@
+//! Shutting the server down, with SIGTERM, while clients are connected.
+#![cfg(unix)]
+
+use std::io::Read;
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod common;
+
+use common::Server;
+
+/// Read until `stream` has produced something ending in `end`.
+fn read_until(stream: &mut TcpStream, end: &str) -> String {
+    let mut read = Vec::new();
+    let mut buffer = [0; 1024];
+    while !read.ends_with(end.as_bytes()) {
+        let size = stream.read(&mut buffer).unwrap();
+        assert_ne!(size, 0, "{}", String::from_utf8_lossy(&read));
+        read.extend_from_slice(&buffer[..size]);
+    }
+    String::from_utf8(read).unwrap()
+}
+
+fn read_to_end(stream: &mut TcpStream) -> String {
+    let mut read = String::new();
+    stream.read_to_string(&mut read).unwrap();
+    read
+}
+
+/// Wait for the server to stop accepting connections.
+fn assert_refused(address: &str) {
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while TcpStream::connect(address).is_ok() {
+        assert!(Instant::now() < deadline, "still accepting connections");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn finishes_requests_in_flight() {
+    let mut server = Server::start(&["--shutdown-timeout", "10"]);
+    let mut idle = server.send("GET /hello/idle HTTP/1.1\r\n\r\n");
+    read_until(&mut idle, "Hello, idle!\n");
+    // Takes five seconds to answer.
+    let mut busy = server.send("GET /sleep HTTP/1.1\r\n\r\n");
+    thread::sleep(Duration::from_millis(200));
+
+    let terminated = Instant::now();
+    server.terminate();
+    assert_refused(&server.address);
+    // Nothing more is coming over the idle connection.
+    assert_eq!(read_to_end(&mut idle), "");
+
+    let response = read_to_end(&mut busy);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("\r\nConnection: close\r\n"));
+    server.wait();
+    assert!(terminated.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn closes_connections_after_the_timeout() {
+    let mut server = Server::start(&["--shutdown-timeout", "0.5"]);
+    let mut busy = server.send("GET /sleep HTTP/1.1\r\n\r\n");
+    thread::sleep(Duration::from_millis(200));
+
+    let terminated = Instant::now();
+    server.terminate();
+    assert_refused(&server.address);
+    // Closed before the handler got to answer.
+    assert_eq!(read_to_end(&mut busy), "");
+    server.wait();
+    assert!(terminated.elapsed() < Duration::from_secs(3));
+}
@@ -0,0 +1,61 @@
+//! Talking to the server over TLS, with a certificate made up on the spot.
+#![cfg(feature = "tls")]
+
+use std::convert::TryFrom;
+use std::fs;
+use std::sync::Arc;
+
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use futures_rustls::rustls::crypto::ring;
+use futures_rustls::rustls::pki_types::ServerName;
+use futures_rustls::rustls::{ClientConfig, RootCertStore};
+use futures_rustls::TlsConnector;
+
+mod common;
+
+use common::Server;
+
+#[async_std::test]
+async fn serves_https() {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let dir = std::env::temp_dir();
+    let cert = dir.join(format!("final-tcp-server-{}.crt", std::process::id()));
+    let key = dir.join(format!("final-tcp-server-{}.key", std::process::id()));
+    fs::write(&cert, certified.cert.pem()).unwrap();
+    fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+    let server = Server::start(&[
+        "--tls-cert",
+        cert.to_str().unwrap(),
+        "--tls-key",
+        key.to_str().unwrap(),
+    ]);
+    fs::remove_file(&cert).unwrap();
+    fs::remove_file(&key).unwrap();
+
+    let mut roots = RootCertStore::empty();
+    roots.add(certified.cert.der().clone()).unwrap();
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connector = TlsConnector::from(Arc::new(config));
+    let stream = TcpStream::connect(&server.address).await.unwrap();
+    let name = ServerName::try_from("localhost").unwrap();
+    let mut stream = connector.connect(name, stream).await.unwrap();
+    stream
+        .write_all(b"GET /hello/you HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nHello, you!\n"));
+
+    // Plain HTTP gets nowhere.
+    let mut stream = server.send("GET /hello/you HTTP/1.1\r\n\r\n");
+    let mut response = Vec::new();
+    let _ = std::io::Read::read_to_end(&mut stream, &mut response);
+    assert!(!response.starts_with(b"HTTP/1.1 200"));
+}
@@ -0,0 +1,49 @@
+//! What can go wrong while making a request.
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Connecting, sending the request or reading the response failed.
+    Io(io::Error),
+    /// The URL isn't one we can make sense of.
+    InvalidUrl(String),
+    /// The URL is for something other than plain `http`.
+    UnsupportedScheme(String),
+    /// The server sent something that isn't a valid response.
+    InvalidResponse(&'static str),
+    /// A header, named here, can't be sent as given.
+    InvalidHeader(String),
+    /// The method isn't a token, so it can't go in the request line.
+    InvalidMethod(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "{}", error),
+            Error::InvalidUrl(url) => write!(f, "invalid URL: {}", url),
+            Error::UnsupportedScheme(scheme) => write!(f, "unsupported scheme: {}", scheme),
+            Error::InvalidResponse(problem) => write!(f, "invalid response: {}", problem),
+            Error::InvalidHeader(name) => write!(f, "invalid header: {}", name),
+            Error::InvalidMethod(method) => write!(f, "invalid method: {:?}", method),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
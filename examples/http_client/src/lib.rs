@@ -0,0 +1,334 @@
+//! A small HTTP/1.1 client, to go with the servers in chapter 9.
+//!
+//! ```no_run
+//! # async_std::task::block_on(async {
+//! let client = http_client::Client::new();
+//! let response = client.get("http://127.0.0.1:7878/hello/you").send().await?;
+//! assert_eq!(response.text(), "Hello, you!\n");
+//!
+//! let response = client
+//!     .post("http://127.0.0.1:7878/echo")
+//!     .header("Content-Type", "text/plain")
+//!     .body("ping")
+//!     .send()
+//!     .await?;
+//! # Ok::<(), http_client::Error>(())
+//! # });
+//! ```
+//!
+//! Only plain `http` URLs are supported. Responses are read whole, with
+//! bodies delimited by `Content-Length`, chunked encoding, or the server
+//! closing the connection.
+//!
+//! A `Client` keeps the connections it's done with, and uses them again
+//! for later requests to the same server, rather than connecting afresh
+//! each time. Clones of a `Client` share their connections.
+
+use std::collections::HashMap;
+use std::marker::Unpin;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+
+use async_std::io::{self, BufReader, Read};
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use futures::future;
+use futures::io::AsyncBufRead;
+
+mod error;
+mod response;
+mod url;
+
+pub use error::Error;
+pub use response::Response;
+use url::Url;
+
+/// A connection, with whatever has been read from it but not used yet.
+type Connection = BufReader<TcpStream>;
+
+/// How many idle connections we keep for each server.
+const MAX_IDLE: usize = 8;
+
+#[derive(Clone, Default)]
+pub struct Client {
+    /// Idle connections, by the address they're connected to.
+    idle: Arc<Mutex<HashMap<String, Vec<Connection>>>>,
+}
+
+impl Client {
+    pub fn new() -> Self {
+        Client::default()
+    }
+
+    pub fn get(&self, url: &str) -> Request<'_> {
+        self.request("GET", url)
+    }
+
+    pub fn post(&self, url: &str) -> Request<'_> {
+        self.request("POST", url)
+    }
+
+    /// A request with any method. One that isn't a token, such as one
+    /// with a space in it, makes `send` fail with `Error::InvalidMethod`.
+    pub fn request(&self, method: &str, url: &str) -> Request<'_> {
+        Request {
+            client: self,
+            method: method.to_string(),
+            url: Url::parse(url),
+            headers: Vec::new(),
+            invalid_header: None,
+            body: Body::Bytes(Vec::new()),
+        }
+    }
+
+    /// How many connections are waiting to be used again.
+    pub fn idle_connections(&self) -> usize {
+        self.idle.lock().unwrap().values().map(Vec::len).sum()
+    }
+
+    /// An idle connection to `authority` that the server hasn't closed.
+    async fn take_idle(&self, authority: &str) -> Option<Connection> {
+        loop {
+            let mut connection = self.idle.lock().unwrap().get_mut(authority)?.pop()?;
+            if is_open(&mut connection).await {
+                return Some(connection);
+            }
+        }
+    }
+
+    fn put_idle(&self, authority: String, connection: Connection) {
+        let mut idle = self.idle.lock().unwrap();
+        let connections = idle.entry(authority).or_default();
+        if connections.len() < MAX_IDLE {
+            connections.push(connection);
+        }
+    }
+}
+
+/// Make a GET request with a client of its own.
+pub async fn get(url: &str) -> Result<Response, Error> {
+    Client::new().get(url).send().await
+}
+
+enum Body<'a> {
+    Bytes(Vec<u8>),
+    /// Sent with chunked encoding, as it's read.
+    Reader(Box<dyn Read + Send + Unpin + 'a>),
+}
+
+/// A request waiting to be sent.
+pub struct Request<'a> {
+    client: &'a Client,
+    method: String,
+    url: Result<Url, Error>,
+    headers: Vec<(String, String)>,
+    /// The name of the first header that couldn't be sent as given.
+    invalid_header: Option<String>,
+    body: Body<'a>,
+}
+
+impl<'a> Request<'a> {
+    /// Add a header. A name that isn't a token, or a value with a line
+    /// break in it, would change the rest of the request, so `send` fails
+    /// with `Error::InvalidHeader` instead.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        if is_token(name) && !value.contains(['\r', '\n', '\0']) {
+            self.headers.push((name.to_string(), value.to_string()));
+        } else if self.invalid_header.is_none() {
+            self.invalid_header = Some(name.to_string());
+        }
+        self
+    }
+
+    /// Send `body`, with a `Content-Length`.
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = Body::Bytes(body.into());
+        self
+    }
+
+    /// Send whatever `reader` produces, with chunked encoding, as it
+    /// arrives.
+    pub fn body_reader(mut self, reader: impl Read + Send + Unpin + 'a) -> Self {
+        self.body = Body::Reader(Box::new(reader));
+        self
+    }
+
+    /// Send the request, and wait for the whole response.
+    pub async fn send(mut self) -> Result<Response, Error> {
+        let url = match self.url {
+            Ok(ref url) => url.clone(),
+            Err(error) => return Err(error),
+        };
+        if !is_token(&self.method) {
+            return Err(Error::InvalidMethod(self.method));
+        }
+        if let Some(name) = self.invalid_header {
+            return Err(Error::InvalidHeader(name));
+        }
+        let head = self.head(&url);
+        let client = self.client;
+
+        // Even an idle connection that looks open may be closed by the
+        // server just as we start using it, which we only find out by
+        // trying. If so, we try again with a new one, as long as the body
+        // can be sent twice, and the server can't have acted on the
+        // request: it hung up before answering any of it, or sending the
+        // request twice is no different from sending it once.
+        let mut connection = client.take_idle(&url.authority).await;
+        if let (Some(idle), Body::Bytes(body)) = (&mut connection, &self.body) {
+            let answering = async {
+                let stream = idle.get_mut();
+                stream.write_all(head.as_bytes()).await?;
+                stream.write_all(body).await?;
+                wait_for_answer(idle).await
+            };
+            match answering.await {
+                Ok(()) => match response::read_response(idle, &self.method).await {
+                    Ok((response, reusable)) => {
+                        if reusable {
+                            client.put_idle(url.authority, connection.unwrap());
+                        }
+                        return Ok(response);
+                    }
+                    Err(Error::Io(error)) if closed(&error) && is_idempotent(&self.method) => {
+                        connection = None
+                    }
+                    Err(error) => return Err(error),
+                },
+                Err(error) if closed(&error) => connection = None,
+                Err(error) => return Err(error.into()),
+            }
+        }
+
+        let mut connection = match connection {
+            Some(connection) => connection,
+            None => {
+                let stream = TcpStream::connect(&url.authority).await?;
+                stream.set_nodelay(true)?;
+                BufReader::new(stream)
+            }
+        };
+        let (response, reusable) = match &mut self.body {
+            Body::Bytes(body) => exchange(&mut connection, &head, body, &self.method).await?,
+            Body::Reader(reader) => {
+                let stream = connection.get_mut();
+                stream.write_all(head.as_bytes()).await?;
+                send_chunked(stream, reader).await?;
+                response::read_response(&mut connection, &self.method).await?
+            }
+        };
+        if reusable {
+            client.put_idle(url.authority, connection);
+        }
+        Ok(response)
+    }
+
+    /// The request line and headers, with the blank line after them.
+    fn head(&self, url: &Url) -> String {
+        let mut head = format!("{} {} HTTP/1.1\r\n", self.method, url.target);
+        let has = |name: &str| {
+            self.headers
+                .iter()
+                .any(|(header, _)| header.eq_ignore_ascii_case(name))
+        };
+        if !has("host") {
+            head += &format!("Host: {}\r\n", url.host);
+        }
+        for (name, value) in &self.headers {
+            head += &format!("{}: {}\r\n", name, value);
+        }
+        match &self.body {
+            Body::Bytes(body) => {
+                let needs_length =
+                    !body.is_empty() || matches!(self.method.as_str(), "POST" | "PUT");
+                if needs_length && !has("content-length") {
+                    head += &format!("Content-Length: {}\r\n", body.len());
+                }
+            }
+            Body::Reader(_) => head += "Transfer-Encoding: chunked\r\n",
+        }
+        head + "\r\n"
+    }
+}
+
+/// Send a request with `body` over `connection`, and read the response.
+async fn exchange(
+    connection: &mut Connection,
+    head: &str,
+    body: &[u8],
+    method: &str,
+) -> Result<(Response, bool), Error> {
+    let stream = connection.get_mut();
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    response::read_response(connection, method).await
+}
+
+async fn send_chunked(
+    stream: &mut TcpStream,
+    reader: &mut (impl Read + Unpin + ?Sized),
+) -> io::Result<()> {
+    let mut buffer = vec![0; 8 * 1024];
+    loop {
+        let size = reader.read(&mut buffer).await?;
+        if size == 0 {
+            return stream.write_all(b"0\r\n\r\n").await;
+        }
+        stream
+            .write_all(format!("{:x}\r\n", size).as_bytes())
+            .await?;
+        stream.write_all(&buffer[..size]).await?;
+        stream.write_all(b"\r\n").await?;
+    }
+}
+
+/// Whether an idle connection is still open: the server will have sent
+/// nothing on it, unless to say it's closing it.
+async fn is_open(connection: &mut Connection) -> bool {
+    // Anything to read, even the end of the stream or an error, means it
+    // isn't: we sent no request for it to be a response to.
+    future::poll_fn(|cx| Poll::Ready(Pin::new(&mut *connection).poll_fill_buf(cx).is_pending()))
+        .await
+}
+
+/// Wait for the server to start answering on `connection`, failing with
+/// `UnexpectedEof` if it closes it instead.
+async fn wait_for_answer(connection: &mut Connection) -> io::Result<()> {
+    let buffered = future::poll_fn(|cx| {
+        Pin::new(&mut *connection)
+            .poll_fill_buf(cx)
+            .map_ok(|buffer| buffer.len())
+    })
+    .await?;
+    match buffered {
+        0 => Err(io::ErrorKind::UnexpectedEof.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Whether `s` can be a method or header name.
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+/// Whether making a request with `method` twice has the same effect as
+/// making it once.
+fn is_idempotent(method: &str) -> bool {
+    ["GET", "HEAD", "PUT", "DELETE", "OPTIONS"]
+        .iter()
+        .any(|idempotent| method.eq_ignore_ascii_case(idempotent))
+}
+
+/// Whether `error` means the server had closed the connection.
+fn closed(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+    )
+}
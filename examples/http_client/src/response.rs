@@ -0,0 +1,302 @@
+//! Reading a response off a connection.
+
+use async_std::io::{self, BufRead};
+use async_std::prelude::*;
+use std::borrow::Cow;
+use std::marker::Unpin;
+
+use crate::Error;
+
+/// The most a status line or header may take up.
+const MAX_LINE: usize = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub reason: String,
+    /// In the order they arrived, with their names as sent.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// The value of the first header called `name`, in any case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The body as text, with anything that isn't UTF-8 replaced.
+    pub fn text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.body)
+    }
+
+    /// Whether the header called `name` lists `token`, as `Connection:
+    /// close` does `close`.
+    fn has_token(&self, name: &str, token: &str) -> bool {
+        self.headers
+            .iter()
+            .filter(|(header, _)| header.eq_ignore_ascii_case(name))
+            .flat_map(|(_, value)| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    }
+}
+
+/// Read the response to a request made with `method`. Also says whether
+/// the connection can be used for another request.
+pub async fn read_response(
+    stream: &mut (impl BufRead + Unpin),
+    method: &str,
+) -> Result<(Response, bool), Error> {
+    let (version, mut response) = loop {
+        let line = read_line(stream).await?;
+        let (version, response) = parse_status_line(&line)?;
+        if !(100..200).contains(&response.status) || response.status == 101 {
+            break (version, response);
+        }
+        // `100 Continue` and the like come before the real response.
+        read_headers(stream).await?;
+    };
+    response.headers = read_headers(stream).await?;
+
+    let chunked = response
+        .header("transfer-encoding")
+        .and_then(|codings| codings.rsplit(',').next())
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+    let length = match response.header("content-length") {
+        Some(length) => Some(
+            length
+                .trim()
+                .parse::<u64>()
+                .map_err(|_| Error::InvalidResponse("bad Content-Length"))?,
+        ),
+        None => None,
+    };
+    let mut framed = true;
+    if method.eq_ignore_ascii_case("HEAD") || matches!(response.status, 101 | 204 | 304) {
+        // No body, whatever the headers say.
+    } else if chunked {
+        response.body = read_chunked(stream).await?;
+    } else if let Some(length) = length {
+        let mut body = Vec::new();
+        stream.take(length).read_to_end(&mut body).await?;
+        if (body.len() as u64) < length {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        response.body = body;
+    } else {
+        // The body ends when the server closes the connection.
+        stream.read_to_end(&mut response.body).await?;
+        framed = false;
+    }
+
+    let keep_alive = match version.as_str() {
+        "HTTP/1.1" => !response.has_token("connection", "close"),
+        _ => response.has_token("connection", "keep-alive"),
+    };
+    let reusable = framed && keep_alive && response.status != 101;
+    Ok((response, reusable))
+}
+
+fn parse_status_line(line: &str) -> Result<(String, Response), Error> {
+    let mut parts = line.splitn(3, ' ');
+    let version = parts.next().unwrap_or_default();
+    if version != "HTTP/1.1" && version != "HTTP/1.0" {
+        return Err(Error::InvalidResponse("bad status line"));
+    }
+    let status = parts
+        .next()
+        .filter(|status| status.len() == 3)
+        .and_then(|status| status.parse().ok())
+        .ok_or(Error::InvalidResponse("bad status code"))?;
+    let reason = parts.next().unwrap_or_default().to_string();
+    Ok((
+        version.to_string(),
+        Response {
+            status,
+            reason,
+            headers: Vec::new(),
+            body: Vec::new(),
+        },
+    ))
+}
+
+async fn read_headers(stream: &mut (impl BufRead + Unpin)) -> Result<Vec<(String, String)>, Error> {
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(stream).await?;
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(Error::InvalidResponse("too many headers"));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or(Error::InvalidResponse("bad header"))?;
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+}
+
+async fn read_chunked(stream: &mut (impl BufRead + Unpin)) -> Result<Vec<u8>, Error> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(stream).await?;
+        // Chunk extensions, after a `;`, mean nothing to us.
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size =
+            u64::from_str_radix(size, 16).map_err(|_| Error::InvalidResponse("bad chunk size"))?;
+        if size == 0 {
+            // Trailers, which we have no use for.
+            read_headers(stream).await?;
+            return Ok(body);
+        }
+        let start = body.len();
+        (&mut *stream).take(size).read_to_end(&mut body).await?;
+        if ((body.len() - start) as u64) < size {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        if !read_line(stream).await?.is_empty() {
+            return Err(Error::InvalidResponse("chunk longer than its size"));
+        }
+    }
+}
+
+/// Read a line ending in CRLF, or a bare LF, without the ending.
+async fn read_line(stream: &mut (impl BufRead + Unpin)) -> Result<String, Error> {
+    let mut line = Vec::new();
+    (&mut *stream)
+        .take(MAX_LINE as u64 + 2)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if line.is_empty() {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(Error::InvalidResponse("line too long, or cut short"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map_err(|_| Error::InvalidResponse("not UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::BufReader;
+
+    async fn read(input: &[u8], method: &str) -> Result<(Response, bool), Error> {
+        read_response(&mut BufReader::new(input), method).await
+    }
+
+    #[async_std::test]
+    async fn reads_bodies_by_length_or_chunked() {
+        let (response, reusable) = read(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nX-Thing: a\r\n\r\nhello",
+            "GET",
+        )
+        .await
+        .unwrap();
+        assert_eq!((response.status, response.reason.as_str()), (200, "OK"));
+        assert_eq!(response.header("x-thing"), Some("a"));
+        assert_eq!(response.text(), "hello");
+        assert!(reusable);
+
+        let (response, reusable) = read(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              3;ext=1\r\nabc\r\n2\r\nde\r\n0\r\nTrailer: x\r\n\r\n",
+            "GET",
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.text(), "abcde");
+        assert!(reusable);
+    }
+
+    #[async_std::test]
+    async fn reads_to_the_end_without_a_length() {
+        let (response, reusable) = read(b"HTTP/1.0 200 OK\r\n\r\nall of it", "GET")
+            .await
+            .unwrap();
+        assert_eq!(response.text(), "all of it");
+        assert!(!reusable);
+    }
+
+    #[async_std::test]
+    async fn knows_when_to_expect_no_body() {
+        let input = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n";
+        let (response, reusable) = read(input, "HEAD").await.unwrap();
+        assert!(response.body.is_empty());
+        assert!(reusable);
+
+        let (response, _) = read(b"HTTP/1.1 204 No Content\r\n\r\n", "GET")
+            .await
+            .unwrap();
+        assert!(response.body.is_empty());
+    }
+
+    #[async_std::test]
+    async fn skips_interim_responses() {
+        let (response, _) = read(
+            b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n",
+            "POST",
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status, 201);
+    }
+
+    #[async_std::test]
+    async fn notices_when_the_server_wants_to_close() {
+        let (_, reusable) = read(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            "GET",
+        )
+        .await
+        .unwrap();
+        assert!(!reusable);
+        let (_, reusable) = read(
+            b"HTTP/1.0 200 OK\r\nContent-Length: 0\r\nConnection: keep-alive\r\n\r\n",
+            "GET",
+        )
+        .await
+        .unwrap();
+        assert!(reusable);
+    }
+
+    #[async_std::test]
+    async fn rejects_bad_responses() {
+        for input in [
+            &b"SPDY/3 200 OK\r\n\r\n"[..],
+            b"HTTP/1.1 2000 OK\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nno colon\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nContent-Length: lots\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n1\r\nabc\r\n0\r\n\r\n",
+        ] {
+            let error = read(input, "GET").await.unwrap_err();
+            assert!(
+                matches!(error, Error::InvalidResponse(_)),
+                "{}: {}",
+                String::from_utf8_lossy(input),
+                error
+            );
+        }
+        for input in [
+            &b""[..],
+            b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort",
+            b"HTTP/1.1 200 OK\r\n",
+        ] {
+            let error = read(input, "GET").await.unwrap_err();
+            assert!(
+                matches!(&error, Error::Io(error) if error.kind() == io::ErrorKind::UnexpectedEof),
+                "{}",
+                error
+            );
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! Just enough URL parsing to make requests with.
+
+use crate::Error;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Url {
+    /// The host, and the port if one was given, for the `Host` header.
+    pub host: String,
+    /// Where to connect to: the host and the port.
+    pub authority: String,
+    /// The path and query, which go in the request line.
+    pub target: String,
+}
+
+impl Url {
+    /// Parse an `http://host[:port][/path][?query]` URL. Fragments are
+    /// dropped, as they're never sent to the server. Whitespace and
+    /// control characters aren't allowed anywhere: in the request line
+    /// or `Host` header, they'd change the rest of the request.
+    pub fn parse(url: &str) -> Result<Self, Error> {
+        if url
+            .chars()
+            .any(|c| c.is_ascii_whitespace() || c.is_ascii_control())
+        {
+            return Err(Error::InvalidUrl(url.to_string()));
+        }
+        let (scheme, rest) = url
+            .split_once("://")
+            .ok_or_else(|| Error::InvalidUrl(url.to_string()))?;
+        if !scheme.eq_ignore_ascii_case("http") {
+            return Err(Error::UnsupportedScheme(scheme.to_string()));
+        }
+        let rest = rest.split('#').next().unwrap_or_default();
+        let (host, target) = match rest.find(['/', '?']) {
+            Some(start) => (&rest[..start], &rest[start..]),
+            None => (rest, "/"),
+        };
+        let target = if target.starts_with('?') {
+            format!("/{}", target)
+        } else {
+            target.to_string()
+        };
+        // An IPv6 address has colons of its own, inside the brackets.
+        let port_start = host
+            .rfind(':')
+            .filter(|&colon| !host[colon..].contains(']'));
+        let authority = match port_start {
+            Some(colon) => {
+                let port = &host[colon + 1..];
+                if port.is_empty() || port.parse::<u16>().is_err() {
+                    return Err(Error::InvalidUrl(url.to_string()));
+                }
+                host.to_string()
+            }
+            None => format!("{}:80", host),
+        };
+        if host.is_empty() || host.starts_with(':') || host.contains('@') {
+            return Err(Error::InvalidUrl(url.to_string()));
+        }
+        Ok(Url {
+            host: host.to_string(),
+            authority,
+            target,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(url: &str) -> (String, String, String) {
+        let url = Url::parse(url).unwrap();
+        (url.host, url.authority, url.target)
+    }
+
+    #[test]
+    fn parses_http_urls() {
+        let owned = |parts: (&str, &str, &str)| {
+            (
+                parts.0.to_string(),
+                parts.1.to_string(),
+                parts.2.to_string(),
+            )
+        };
+        assert_eq!(
+            parse("http://example.com"),
+            owned(("example.com", "example.com:80", "/"))
+        );
+        assert_eq!(
+            parse("http://127.0.0.1:7878/hello/you?loud=1#top"),
+            owned(("127.0.0.1:7878", "127.0.0.1:7878", "/hello/you?loud=1"))
+        );
+        assert_eq!(
+            parse("HTTP://[::1]:8080?q"),
+            owned(("[::1]:8080", "[::1]:8080", "/?q"))
+        );
+        assert_eq!(parse("http://[::1]/").1, "[::1]:80");
+    }
+
+    #[test]
+    fn rejects_everything_else() {
+        assert!(matches!(
+            Url::parse("https://example.com"),
+            Err(Error::UnsupportedScheme(scheme)) if scheme == "https"
+        ));
+        for url in [
+            "example.com",
+            "http://",
+            "http://:80/",
+            "http://example.com:/",
+            "http://example.com:http/",
+            "http://user@example.com/",
+            "http://example.com/x HTTP/1.1\r\nX-Evil: 1\r\n\r\n",
+            "http://example.com/a b",
+            "http://example.com/\t",
+            "http://example.com\r\nX-Evil: 1/",
+            "http://example.com/\0",
+        ] {
+            assert!(
+                matches!(Url::parse(url), Err(Error::InvalidUrl(_))),
+                "{}",
+                url
+            );
+        }
+    }
+}
@@ -0,0 +1,270 @@
+//! Requests to a server of our own, on a loopback port.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use http_client::{Client, Error};
+
+/// A server answering, on each connection, until the client hangs up:
+///
+/// * `/length` with `hello`, and a `Content-Length`,
+/// * `/chunked` with `hello`, in two chunks,
+/// * `/echo` with the method, the `X-Test` header and the body it got,
+/// * `/close` with `bye`, and `Connection: close`,
+/// * `/drop` with `bye`, before hanging up without warning,
+/// * `/half` with half a response, before hanging up.
+struct Server {
+    address: String,
+    connections: Arc<AtomicUsize>,
+}
+
+impl Server {
+    fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                counter.fetch_add(1, Ordering::SeqCst);
+                thread::spawn(move || serve(stream.unwrap()));
+            }
+        });
+        Server {
+            address,
+            connections,
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.address, path)
+    }
+
+    fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+fn serve(stream: TcpStream) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).unwrap() == 0 {
+            return;
+        }
+        let mut parts = request_line.split(' ');
+        let (method, path) = (parts.next().unwrap(), parts.next().unwrap());
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(": ").unwrap();
+            headers.push((name.to_ascii_lowercase(), value.to_string()));
+        }
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(header, _)| header == name)
+                .map(|(_, value)| value.as_str())
+        };
+        let mut body = Vec::new();
+        if header("transfer-encoding") == Some("chunked") {
+            loop {
+                let mut size = String::new();
+                reader.read_line(&mut size).unwrap();
+                let size = usize::from_str_radix(size.trim_end(), 16).unwrap();
+                let mut chunk = vec![0; size + 2];
+                reader.read_exact(&mut chunk).unwrap();
+                if size == 0 {
+                    break;
+                }
+                body.extend_from_slice(&chunk[..size]);
+            }
+        } else if let Some(length) = header("content-length") {
+            body.resize(length.parse().unwrap(), 0);
+            reader.read_exact(&mut body).unwrap();
+        }
+
+        if path == "/half" {
+            write!(writer, "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhe").unwrap();
+            return;
+        }
+        let with_length = |body: &str| format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let response = match path {
+            "/length" => with_length("hello"),
+            "/chunked" => {
+                "Transfer-Encoding: chunked\r\n\r\n3\r\nhel\r\n2\r\nlo\r\n0\r\n\r\n".into()
+            }
+            "/echo" => with_length(&format!(
+                "{} {} {}",
+                method,
+                header("x-test").unwrap_or("-"),
+                String::from_utf8_lossy(&body)
+            )),
+            "/close" => format!("Connection: close\r\n{}", with_length("bye")),
+            "/drop" => with_length("bye"),
+            _ => with_length("not found"),
+        };
+        write!(writer, "HTTP/1.1 200 OK\r\n{}", response).unwrap();
+        if path == "/close" || path == "/drop" {
+            return;
+        }
+    }
+}
+
+#[async_std::test]
+async fn reads_bodies_by_length_or_chunked() {
+    let server = Server::start();
+    let client = Client::new();
+    for path in ["/length", "/chunked"] {
+        let response = client.get(&server.url(path)).send().await.unwrap();
+        assert_eq!((response.status, response.reason.as_str()), (200, "OK"));
+        assert_eq!(response.text(), "hello");
+    }
+}
+
+#[async_std::test]
+async fn sends_headers_and_bodies() {
+    let server = Server::start();
+    let client = Client::new();
+    let response = client
+        .post(&server.url("/echo"))
+        .header("X-Test", "yes")
+        .body("by length")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.text(), "POST yes by length");
+
+    let response = client
+        .request("PUT", &server.url("/echo"))
+        .body_reader(&b"chunked"[..])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.text(), "PUT - chunked");
+
+    let response = http_client::get(&server.url("/echo")).await.unwrap();
+    assert_eq!(response.text(), "GET - ");
+}
+
+#[async_std::test]
+async fn reuses_connections() {
+    let server = Server::start();
+    let client = Client::new();
+    for path in ["/length", "/chunked", "/echo"] {
+        client.get(&server.url(path)).send().await.unwrap();
+    }
+    assert_eq!(server.connections(), 1);
+    assert_eq!(client.idle_connections(), 1);
+
+    // The server says it's done with the connection.
+    client.get(&server.url("/close")).send().await.unwrap();
+    assert_eq!(client.idle_connections(), 0);
+    client.get(&server.url("/length")).send().await.unwrap();
+    assert_eq!(server.connections(), 2);
+
+    // Requests at the same time need a connection each.
+    let clone = client.clone();
+    let (first, second) = futures::join!(
+        client.get(&server.url("/length")).send(),
+        clone.get(&server.url("/chunked")).send()
+    );
+    assert_eq!(
+        (first.unwrap().text(), second.unwrap().text()),
+        ("hello".into(), "hello".into())
+    );
+    assert_eq!(server.connections(), 3);
+    assert_eq!(client.idle_connections(), 2);
+}
+
+#[async_std::test]
+async fn reconnects_when_the_server_has_hung_up() {
+    let server = Server::start();
+    let client = Client::new();
+    client.get(&server.url("/drop")).send().await.unwrap();
+    assert_eq!(client.idle_connections(), 1);
+    let response = client.get(&server.url("/length")).send().await.unwrap();
+    assert_eq!(response.text(), "hello");
+    assert_eq!(server.connections(), 2);
+}
+
+#[async_std::test]
+async fn sends_again_only_what_can_be_sent_twice() {
+    let server = Server::start();
+    let client = Client::new();
+    client.get(&server.url("/length")).send().await.unwrap();
+    // The server may have acted on it, so the POST isn't sent again.
+    let error = client
+        .post(&server.url("/half"))
+        .body("once")
+        .send()
+        .await
+        .unwrap_err();
+    assert!(matches!(error, Error::Io(_)), "{}", error);
+    assert_eq!(server.connections(), 1);
+
+    client.get(&server.url("/length")).send().await.unwrap();
+    assert_eq!(server.connections(), 2);
+    // A GET is, on a connection of its own, where it fails the same way.
+    client.get(&server.url("/half")).send().await.unwrap_err();
+    assert_eq!(server.connections(), 3);
+}
+
+#[async_std::test]
+async fn reports_errors() {
+    let client = Client::new();
+    assert!(matches!(
+        client.get("https://example.com/").send().await,
+        Err(Error::UnsupportedScheme(_))
+    ));
+    assert!(matches!(
+        client.get("example.com").send().await,
+        Err(Error::InvalidUrl(_))
+    ));
+    let injected = client
+        .get("http://127.0.0.1:1/")
+        .header("X-Test", "a\r\nX-Injected: b")
+        .send()
+        .await;
+    assert!(matches!(injected, Err(Error::InvalidHeader(name)) if name == "X-Test"));
+    let bad_name = client
+        .get("http://127.0.0.1:1/")
+        .header("X Test", "a")
+        .send()
+        .await;
+    assert!(matches!(bad_name, Err(Error::InvalidHeader(_))));
+    let smuggled = client
+        .get("http://127.0.0.1:1/x HTTP/1.1\r\nX-Injected: b\r\n\r\n")
+        .send()
+        .await;
+    assert!(matches!(smuggled, Err(Error::InvalidUrl(_))));
+    for method in ["GET / HTTP/1.1\r\n\r\nGET", "GET /x", ""] {
+        let sent = client.request(method, "http://127.0.0.1:1/").send().await;
+        assert!(
+            matches!(&sent, Err(Error::InvalidMethod(bad)) if bad == method),
+            "{:?}",
+            method
+        );
+    }
+
+    // Nothing listens on a port we've just given back.
+    let address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let error = client
+        .get(&format!("http://{}/", address))
+        .send()
+        .await
+        .unwrap_err();
+    assert!(matches!(error, Error::Io(_)), "{}", error);
+}
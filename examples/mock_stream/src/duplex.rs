@@ -0,0 +1,231 @@
+//! Two ends of an in-memory connection.
+//!
+//! A `MockStream` follows its script whatever the other side does, which
+//! is no good for a conversation: a client that waits for each response
+//! before sending its next request, say. `duplex` gives two streams
+//! connected to each other instead, each reading what the other writes,
+//! so a client and a server can run side by side on their own tasks.
+//!
+//! Each direction holds at most `capacity` bytes. Once it's full, writes
+//! wait for the other end to read some, just as they would once a
+//! socket's buffers fill up.
+
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use futures::io::{AsyncRead, AsyncWrite};
+
+/// Create two connected streams, each buffering up to `capacity` bytes
+/// on the way to the other.
+pub fn duplex(capacity: usize) -> (DuplexStream, DuplexStream) {
+    assert!(capacity > 0, "a pipe needs room for at least one byte");
+    let one = Arc::new(Mutex::new(Pipe::new(capacity)));
+    let two = Arc::new(Mutex::new(Pipe::new(capacity)));
+    (
+        DuplexStream {
+            read: one.clone(),
+            write: two.clone(),
+        },
+        DuplexStream {
+            read: two,
+            write: one,
+        },
+    )
+}
+
+/// One end of a `duplex` connection.
+#[derive(Debug)]
+pub struct DuplexStream {
+    read: Arc<Mutex<Pipe>>,
+    write: Arc<Mutex<Pipe>>,
+}
+
+/// Bytes on their way in one direction.
+#[derive(Debug)]
+struct Pipe {
+    buffer: VecDeque<u8>,
+    capacity: usize,
+    /// The writing end has closed, so once `buffer` is empty, reads find
+    /// the end of the stream.
+    closed: bool,
+    /// The reading end is gone, so there's no point writing.
+    abandoned: bool,
+    /// A reader waiting for bytes.
+    reader: Option<Waker>,
+    /// A writer waiting for room.
+    writer: Option<Waker>,
+}
+
+impl Pipe {
+    fn new(capacity: usize) -> Self {
+        Pipe {
+            buffer: VecDeque::with_capacity(capacity),
+            capacity,
+            closed: false,
+            abandoned: false,
+            reader: None,
+            writer: None,
+        }
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(reader) = self.reader.take() {
+            reader.wake();
+        }
+    }
+}
+
+impl AsyncRead for DuplexStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut pipe = self.read.lock().unwrap();
+        if pipe.buffer.is_empty() {
+            if pipe.closed || buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            pipe.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let size = pipe.buffer.len().min(buf.len());
+        for (byte, slot) in pipe.buffer.drain(..size).zip(buf.iter_mut()) {
+            *slot = byte;
+        }
+        if let Some(writer) = pipe.writer.take() {
+            writer.wake();
+        }
+        Poll::Ready(Ok(size))
+    }
+}
+
+impl AsyncWrite for DuplexStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut pipe = self.write.lock().unwrap();
+        if pipe.closed || pipe.abandoned {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let room = pipe.capacity - pipe.buffer.len();
+        if room == 0 {
+            pipe.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let size = room.min(buf.len());
+        pipe.buffer.extend(&buf[..size]);
+        if let Some(reader) = pipe.reader.take() {
+            reader.wake();
+        }
+        Poll::Ready(Ok(size))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Closes the writing half, leaving this end free to carry on reading.
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.write.lock().unwrap().close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for DuplexStream {
+    fn drop(&mut self) {
+        self.write.lock().unwrap().close();
+        let mut read = self.read.lock().unwrap();
+        read.abandoned = true;
+        if let Some(writer) = read.writer.take() {
+            writer.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::future;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn carries_bytes_both_ways() {
+        let (mut client, mut server) = duplex(64);
+        block_on(async {
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0; 4];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+
+            server.write_all(b"pong").await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"pong");
+        });
+    }
+
+    #[test]
+    fn writes_wait_for_room() {
+        let (mut client, mut server) = duplex(3);
+        let big = vec![7; 1000];
+        let ((), read) = block_on(future::join(
+            async {
+                client.write_all(&big).await.unwrap();
+                client.close().await.unwrap();
+            },
+            async {
+                let mut read = Vec::new();
+                server.read_to_end(&mut read).await.unwrap();
+                read
+            },
+        ));
+        assert_eq!(read, big);
+    }
+
+    #[test]
+    fn full_pipes_stall_writes() {
+        let (mut client, _server) = duplex(3);
+        let write = block_on(future::select(
+            client.write_all(b"hello"),
+            future::ready(()),
+        ));
+        assert!(matches!(write, future::Either::Right(_)));
+    }
+
+    #[test]
+    fn closing_ends_one_direction() {
+        let (mut client, mut server) = duplex(64);
+        block_on(async {
+            client.write_all(b"request").await.unwrap();
+            client.close().await.unwrap();
+            let mut read = String::new();
+            server.read_to_string(&mut read).await.unwrap();
+            assert_eq!(read, "request");
+
+            // The other way still works.
+            server.write_all(b"response").await.unwrap();
+            drop(server);
+            let mut read = String::new();
+            client.read_to_string(&mut read).await.unwrap();
+            assert_eq!(read, "response");
+        });
+    }
+
+    #[test]
+    fn writes_fail_once_the_other_end_is_gone() {
+        let (mut client, server) = duplex(64);
+        drop(server);
+        let error = block_on(client.write_all(b"anyone?")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+    }
+}
@@ -0,0 +1,402 @@
+//! A stand-in for a `TcpStream`, for testing code that reads and writes
+//! one.
+//!
+//! A real connection rarely hands over a whole request in one read: it
+//! arrives in pieces, with waits in between, and may end early or fail
+//! halfway. Code that only ever sees the whole thing at once, as with
+//! the `MockTcpStream` in the book, can get all of that wrong and still
+//! pass its tests. So a `MockStream` follows a script of what the client
+//! sends, one step per read:
+//!
+//! ```
+//! use futures::io::{AsyncReadExt, AsyncWriteExt};
+//! use mock_stream::MockStream;
+//!
+//! # futures::executor::block_on(async {
+//! let mut stream = MockStream::new()
+//!     .send_in_chunks(b"GET / HTTP/1.1\r\n\r\n", 4)
+//!     .pending()
+//!     .send(b"more");
+//! let mut request = Vec::new();
+//! stream.read_to_end(&mut request).await.unwrap();
+//! assert_eq!(request, b"GET / HTTP/1.1\r\n\r\nmore");
+//!
+//! stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+//! stream.assert_written(b"HTTP/1.1 204 No Content\r\n\r\n");
+//! # });
+//! ```
+//!
+//! Writes are kept, in order, for checking afterwards. Once the script
+//! runs out, reads find the end of the stream, as if the client had shut
+//! down its half of the connection, while writes carry on working until
+//! the stream itself is closed.
+//!
+//! For a client that reacts to what it reads, see `duplex`.
+
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::io::{AsyncRead, AsyncWrite};
+
+mod duplex;
+
+pub use duplex::{duplex, DuplexStream};
+
+/// What a read does.
+#[derive(Debug)]
+enum Step {
+    /// Read what's left of these bytes, or as much as fits.
+    Data(Vec<u8>),
+    /// Nothing yet, but try again.
+    Pending,
+    /// Fail with an error of this kind.
+    Error(io::ErrorKind),
+    /// Nothing, ever again. Left in place, so every later read hangs too.
+    Hang,
+}
+
+#[derive(Debug, Default)]
+pub struct MockStream {
+    script: VecDeque<Step>,
+    written: Vec<u8>,
+    /// How many bytes had been written at the last flush.
+    flushed: usize,
+    /// The most a single write takes.
+    write_chunk: Option<usize>,
+    /// Writes fail once this many bytes have been written.
+    write_limit: Option<(usize, io::ErrorKind)>,
+    /// Writes stall, for good, once this many bytes have been written.
+    write_stall: Option<usize>,
+    closed: bool,
+}
+
+impl MockStream {
+    /// A stream with nothing to read, and room for any amount of writes.
+    pub fn new() -> Self {
+        MockStream::default()
+    }
+
+    /// Have the client send `bytes`, for the next read to return. If they
+    /// don't fit in the buffer, the rest go to the reads after it.
+    pub fn send(mut self, bytes: &[u8]) -> Self {
+        self.script.push_back(Step::Data(bytes.to_vec()));
+        self
+    }
+
+    /// Have the client send `bytes` over several reads, at most `size` at
+    /// a time.
+    pub fn send_in_chunks(mut self, bytes: &[u8], size: usize) -> Self {
+        assert!(size > 0, "chunks need at least one byte");
+        for chunk in bytes.chunks(size) {
+            self.script.push_back(Step::Data(chunk.to_vec()));
+        }
+        self
+    }
+
+    /// Have the client send `bytes` a byte at a time, with a `Pending`
+    /// before each one. Anything that reads a byte and assumes the rest has arrived
+    /// will trip over this.
+    pub fn send_slowly(mut self, bytes: &[u8]) -> Self {
+        for byte in bytes {
+            self.script.push_back(Step::Pending);
+            self.script.push_back(Step::Data(vec![*byte]));
+        }
+        self
+    }
+
+    /// Have the next read return `Pending`, waking the task straight
+    /// away so that it reads again.
+    pub fn pending(mut self) -> Self {
+        self.script.push_back(Step::Pending);
+        self
+    }
+
+    /// Have the next read fail with an error of kind `kind`.
+    pub fn read_error(mut self, kind: io::ErrorKind) -> Self {
+        self.script.push_back(Step::Error(kind));
+        self
+    }
+
+    /// Have every read from here on return `Pending`, and never wake the
+    /// task: a client that's gone quiet without hanging up.
+    pub fn hang(mut self) -> Self {
+        self.script.push_back(Step::Hang);
+        self
+    }
+
+    /// Have each write take at most `size` bytes.
+    pub fn write_in_chunks(mut self, size: usize) -> Self {
+        assert!(size > 0, "chunks need at least one byte");
+        self.write_chunk = Some(size);
+        self
+    }
+
+    /// Have writes fail with an error of kind `kind` once `limit` bytes
+    /// have been written, as when the client resets the connection.
+    pub fn write_error_after(mut self, limit: usize, kind: io::ErrorKind) -> Self {
+        self.write_limit = Some((limit, kind));
+        self
+    }
+
+    /// Have writes return `Pending`, and never wake the task, once
+    /// `limit` bytes have been written: a client that has stopped reading.
+    pub fn stall_writes_after(mut self, limit: usize) -> Self {
+        self.write_stall = Some(limit);
+        self
+    }
+
+    /// Everything written so far.
+    pub fn written(&self) -> &[u8] {
+        &self.written
+    }
+
+    /// Everything written by the last flush.
+    pub fn flushed(&self) -> &[u8] {
+        &self.written[..self.flushed]
+    }
+
+    /// Everything written so far, leaving nothing.
+    pub fn take_written(&mut self) -> Vec<u8> {
+        self.flushed = 0;
+        std::mem::take(&mut self.written)
+    }
+
+    /// How many scripted bytes are left unread.
+    pub fn unread(&self) -> usize {
+        self.script
+            .iter()
+            .map(|step| match step {
+                Step::Data(bytes) => bytes.len(),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Whether the stream has been closed, which only stops writes.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Check that exactly `expected` has been written.
+    #[track_caller]
+    pub fn assert_written(&self, expected: &[u8]) {
+        assert!(
+            self.written == expected,
+            "written:\n{}\nexpected:\n{}",
+            String::from_utf8_lossy(&self.written),
+            String::from_utf8_lossy(expected),
+        );
+    }
+
+    /// Check that every scripted byte has been read.
+    #[track_caller]
+    pub fn assert_read_all(&self) {
+        assert_eq!(self.unread(), 0, "{} bytes were never read", self.unread());
+    }
+}
+
+impl AsyncRead for MockStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let step = match self.script.front_mut() {
+            Some(step) => step,
+            // The client is done sending.
+            None => return Poll::Ready(Ok(0)),
+        };
+        match step {
+            Step::Data(bytes) => {
+                let size = bytes.len().min(buf.len());
+                buf[..size].copy_from_slice(&bytes[..size]);
+                bytes.drain(..size);
+                if bytes.is_empty() {
+                    self.script.pop_front();
+                }
+                Poll::Ready(Ok(size))
+            }
+            Step::Pending => {
+                self.script.pop_front();
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            Step::Error(kind) => {
+                let kind = *kind;
+                self.script.pop_front();
+                Poll::Ready(Err(io::Error::new(kind, "scripted read error")))
+            }
+            Step::Hang => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for MockStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let mut size = buf.len();
+        if let Some(chunk) = self.write_chunk {
+            size = size.min(chunk);
+        }
+        if let Some((limit, kind)) = self.write_limit {
+            let room = limit.saturating_sub(self.written.len());
+            if room == 0 {
+                return Poll::Ready(Err(io::Error::new(kind, "scripted write error")));
+            }
+            size = size.min(room);
+        }
+        if let Some(limit) = self.write_stall {
+            let room = limit.saturating_sub(self.written.len());
+            if room == 0 {
+                return Poll::Pending;
+            }
+            size = size.min(room);
+        }
+        self.written.extend_from_slice(&buf[..size]);
+        Poll::Ready(Ok(size))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.flushed = self.written.len();
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.flushed = self.written.len();
+        self.closed = true;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::future::{self, Either};
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use futures::task::noop_waker_ref;
+
+    fn poll_read(stream: &mut MockStream, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        Pin::new(stream).poll_read(&mut cx, buf)
+    }
+
+    #[test]
+    fn reads_follow_the_script() {
+        let mut stream = MockStream::new()
+            .send(b"hello")
+            .pending()
+            .send_in_chunks(b"abcde", 2)
+            .read_error(io::ErrorKind::ConnectionReset)
+            .send(b"!");
+        let mut buf = [0; 3];
+        let mut read = |stream: &mut MockStream| match poll_read(stream, &mut buf) {
+            Poll::Ready(Ok(size)) => Some(Ok(buf[..size].to_vec())),
+            Poll::Ready(Err(error)) => Some(Err(error.kind())),
+            Poll::Pending => None,
+        };
+        assert_eq!(read(&mut stream), Some(Ok(b"hel".to_vec())));
+        assert_eq!(read(&mut stream), Some(Ok(b"lo".to_vec())));
+        assert_eq!(read(&mut stream), None);
+        assert_eq!(stream.unread(), 6);
+        assert_eq!(read(&mut stream), Some(Ok(b"ab".to_vec())));
+        assert_eq!(read(&mut stream), Some(Ok(b"cd".to_vec())));
+        assert_eq!(read(&mut stream), Some(Ok(b"e".to_vec())));
+        assert_eq!(read(&mut stream), Some(Err(io::ErrorKind::ConnectionReset)));
+        assert_eq!(read(&mut stream), Some(Ok(b"!".to_vec())));
+        stream.assert_read_all();
+        // And then the end of the stream, for good.
+        assert_eq!(read(&mut stream), Some(Ok(Vec::new())));
+        assert_eq!(read(&mut stream), Some(Ok(Vec::new())));
+    }
+
+    #[test]
+    fn pending_reads_wake_the_task() {
+        let mut stream = MockStream::new().send_slowly(b"slow");
+        let mut read = String::new();
+        block_on(stream.read_to_string(&mut read)).unwrap();
+        assert_eq!(read, "slow");
+    }
+
+    #[test]
+    fn hangs_for_good() {
+        let mut stream = MockStream::new().send(b"hi").hang();
+        let mut buf = [0; 8];
+        assert!(matches!(
+            poll_read(&mut stream, &mut buf),
+            Poll::Ready(Ok(2))
+        ));
+        assert!(poll_read(&mut stream, &mut buf).is_pending());
+        assert!(poll_read(&mut stream, &mut buf).is_pending());
+
+        let read = block_on(future::select(stream.read(&mut buf), future::ready(())));
+        assert!(matches!(read, Either::Right(_)));
+    }
+
+    #[test]
+    fn writes_append() {
+        let mut stream = MockStream::new().write_in_chunks(3);
+        block_on(async {
+            assert_eq!(stream.write(b"hello").await.unwrap(), 3);
+            stream.write_all(b"lo, world").await.unwrap();
+            assert!(stream.flushed().is_empty());
+            stream.flush().await.unwrap();
+        });
+        stream.assert_written(b"hello, world");
+        assert_eq!(stream.flushed(), b"hello, world");
+        assert_eq!(stream.take_written(), b"hello, world");
+        assert!(stream.written().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "expected:\ngoodbye")]
+    fn explains_unexpected_writes() {
+        let mut stream = MockStream::new();
+        block_on(stream.write_all(b"hello")).unwrap();
+        stream.assert_written(b"goodbye");
+    }
+
+    #[test]
+    fn fails_writes_past_the_limit() {
+        let mut stream = MockStream::new().write_error_after(4, io::ErrorKind::ConnectionReset);
+        let error = block_on(stream.write_all(b"hello")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionReset);
+        stream.assert_written(b"hell");
+    }
+
+    #[test]
+    fn stalls_writes_past_the_limit() {
+        let mut stream = MockStream::new().stall_writes_after(4);
+        let write = block_on(future::select(
+            stream.write_all(b"hello"),
+            future::ready(()),
+        ));
+        assert!(matches!(write, Either::Right(_)));
+        stream.assert_written(b"hell");
+    }
+
+    #[test]
+    fn closing_only_stops_writes() {
+        let mut stream = MockStream::new().send(b"still here");
+        block_on(async {
+            stream.write_all(b"bye").await.unwrap();
+            stream.close().await.unwrap();
+            assert!(stream.is_closed());
+            let error = stream.write_all(b"more").await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+
+            let mut read = String::new();
+            stream.read_to_string(&mut read).await.unwrap();
+            assert_eq!(read, "still here");
+        });
+        stream.assert_written(b"bye");
+    }
+}